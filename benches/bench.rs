@@ -347,11 +347,6 @@ const REQ_PROTECTED: [u8; 35] = [
 ];
 
 fn oscore(c: &mut Criterion) {
-    // Check for no_replay enabled, because otherwise unprotection will fail
-    if cfg!(not(feature = "no_replay")) {
-        panic!("Run with --features no_replay");
-    }
-
     let mut group = c.benchmark_group("oscore");
 
     group.bench_function("context_derivation", |b| {
@@ -359,6 +354,7 @@ fn oscore(c: &mut Criterion) {
             SecurityContext::new(
                 MASTER_SECRET.to_vec(),
                 MASTER_SALT.to_vec(),
+                None,
                 CLIENT_ID.to_vec(),
                 SERVER_ID.to_vec(),
             )
@@ -369,6 +365,7 @@ fn oscore(c: &mut Criterion) {
     let mut req_context = SecurityContext::new(
         MASTER_SECRET.to_vec(),
         MASTER_SALT.to_vec(),
+        None,
         CLIENT_ID.to_vec(),
         SERVER_ID.to_vec(),
     )
@@ -377,15 +374,55 @@ fn oscore(c: &mut Criterion) {
         b.iter(|| req_context.protect_request(&REQ_UNPROTECTED).unwrap())
     });
 
-    let mut req_context = SecurityContext::new(
-        MASTER_SECRET.to_vec(),
-        MASTER_SALT.to_vec(),
-        SERVER_ID.to_vec(),
-        CLIENT_ID.to_vec(),
-    )
-    .unwrap();
     group.bench_function("unprotection_request", |b| {
-        b.iter(|| req_context.unprotect_request(&REQ_PROTECTED).unwrap())
+        // Use a fresh context every time, since the replay window would
+        // reject the request otherwise
+        b.iter_batched(
+            || {
+                SecurityContext::new(
+                    MASTER_SECRET.to_vec(),
+                    MASTER_SALT.to_vec(),
+                    None,
+                    SERVER_ID.to_vec(),
+                    CLIENT_ID.to_vec(),
+                )
+                .unwrap()
+            },
+            |mut req_context| {
+                req_context.unprotect_request(&REQ_PROTECTED).unwrap()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    let mut buf = [0; 128];
+    group.bench_function("protection_request_in_place", |b| {
+        b.iter(|| {
+            req_context
+                .protect_request_into(&REQ_UNPROTECTED, &mut buf)
+                .unwrap()
+        })
+    });
+
+    group.bench_function("unprotection_request_in_place", |b| {
+        b.iter_batched(
+            || {
+                SecurityContext::new(
+                    MASTER_SECRET.to_vec(),
+                    MASTER_SALT.to_vec(),
+                    None,
+                    SERVER_ID.to_vec(),
+                    CLIENT_ID.to_vec(),
+                )
+                .unwrap()
+            },
+            |mut req_context| {
+                req_context
+                    .unprotect_request_into(&REQ_PROTECTED, &mut buf)
+                    .unwrap()
+            },
+            BatchSize::SmallInput,
+        )
     });
 
     group.finish();
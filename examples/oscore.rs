@@ -17,6 +17,7 @@ fn main() {
     let mut client_context = SecurityContext::new(
         master_secret.to_vec(),
         master_salt.to_vec(),
+        None,
         client_id.to_vec(),
         server_id.to_vec(),
     )
@@ -26,8 +27,8 @@ fn main() {
         0x44, 0x01, 0x5D, 0x1F, 0x00, 0x00, 0x39, 0x74, 0x39, 0x6C, 0x6F,
         0x63, 0x61, 0x6C, 0x68, 0x6F, 0x73, 0x74, 0x83, 0x74, 0x76, 0x31,
     ];
-    // Protect the request
-    let req_protected =
+    // Protect the request, keeping its binding to unprotect the response
+    let (req_protected, mut binding) =
         client_context.protect_request(&req_unprotected).unwrap();
 
     // Server -----------------------------------------------------------------
@@ -37,6 +38,7 @@ fn main() {
     let mut server_context = SecurityContext::new(
         master_secret.to_vec(),
         master_salt.to_vec(),
+        None,
         server_id.to_vec(),
         client_id.to_vec(),
     )
@@ -59,7 +61,8 @@ fn main() {
     // Client -----------------------------------------------------------------
 
     // Unprotect the response
-    let res_unprotected_local =
-        client_context.unprotect_response(&res_protected).unwrap();
+    let res_unprotected_local = client_context
+        .unprotect_response(&res_protected, &mut binding)
+        .unwrap();
     assert_eq!(&res_unprotected[..], &res_unprotected_local[..]);
 }
@@ -0,0 +1,474 @@
+use aes_ccm::{
+    aead::{
+        generic_array::{
+            typenum::{Unsigned, U16, U8},
+            GenericArray,
+        },
+        Aead, NewAead, Payload,
+    },
+    AesCcm,
+};
+use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use alloc::vec::Vec;
+use chacha20poly1305::ChaCha20Poly1305;
+
+use super::{ccm::Aes256Ccm, error::Error, Result};
+
+/// The AEAD algorithms that can be used to protect messages.
+///
+/// The algorithm determines the length of the keys and the nonce, as well as
+/// the size of the authentication tag. Both parties need to agree on it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AeadAlgorithm {
+    /// AES-CCM-16-64-128, the mandatory to implement algorithm.
+    #[default]
+    AesCcm16_64_128,
+    /// AES-CCM-16-128-128, which has a longer tag.
+    AesCcm16_128_128,
+    /// AES-CCM-16-64-256, which has a longer key.
+    AesCcm16_64_256,
+    /// AES-GCM with a 128 bit key.
+    A128Gcm,
+    /// AES-GCM with a 256 bit key.
+    A256Gcm,
+    /// ChaCha20/Poly1305.
+    ChaCha20Poly1305,
+}
+
+impl AeadAlgorithm {
+    /// Returns the COSE algorithm identifier.
+    pub fn id(self) -> i32 {
+        match self {
+            AeadAlgorithm::AesCcm16_64_128 => 10,
+            AeadAlgorithm::AesCcm16_128_128 => 30,
+            AeadAlgorithm::AesCcm16_64_256 => 11,
+            AeadAlgorithm::A128Gcm => 1,
+            AeadAlgorithm::A256Gcm => 3,
+            AeadAlgorithm::ChaCha20Poly1305 => 24,
+        }
+    }
+
+    /// Returns the algorithm with this COSE algorithm identifier.
+    pub fn from_id(id: i32) -> Result<AeadAlgorithm> {
+        match id {
+            10 => Ok(AeadAlgorithm::AesCcm16_64_128),
+            30 => Ok(AeadAlgorithm::AesCcm16_128_128),
+            11 => Ok(AeadAlgorithm::AesCcm16_64_256),
+            1 => Ok(AeadAlgorithm::A128Gcm),
+            3 => Ok(AeadAlgorithm::A256Gcm),
+            24 => Ok(AeadAlgorithm::ChaCha20Poly1305),
+            _ => Err(Error::UnsupportedAlgorithm),
+        }
+    }
+
+    /// Returns the length of the key in bytes.
+    pub fn key_len(self) -> usize {
+        match self {
+            AeadAlgorithm::AesCcm16_64_128
+            | AeadAlgorithm::AesCcm16_128_128
+            | AeadAlgorithm::A128Gcm => 16,
+            AeadAlgorithm::AesCcm16_64_256
+            | AeadAlgorithm::A256Gcm
+            | AeadAlgorithm::ChaCha20Poly1305 => 32,
+        }
+    }
+
+    /// Returns the length of the nonce in bytes.
+    pub fn nonce_len(self) -> usize {
+        match self {
+            AeadAlgorithm::AesCcm16_64_128
+            | AeadAlgorithm::AesCcm16_128_128
+            | AeadAlgorithm::AesCcm16_64_256 => 13,
+            AeadAlgorithm::A128Gcm
+            | AeadAlgorithm::A256Gcm
+            | AeadAlgorithm::ChaCha20Poly1305 => 12,
+        }
+    }
+
+    /// Returns the length of the authentication tag in bytes.
+    pub fn tag_len(self) -> usize {
+        match self {
+            AeadAlgorithm::AesCcm16_64_128
+            | AeadAlgorithm::AesCcm16_64_256 => 8,
+            _ => 16,
+        }
+    }
+
+    /// Returns the ciphertext, including the tag.
+    ///
+    /// The key and nonce need to have the lengths of this algorithm.
+    pub(crate) fn encrypt(
+        self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>> {
+        match self {
+            AeadAlgorithm::AesCcm16_64_128 => {
+                seal::<AesCcm<U8>>(key, nonce, aad, plaintext)
+            }
+            AeadAlgorithm::AesCcm16_128_128 => {
+                seal::<AesCcm<U16>>(key, nonce, aad, plaintext)
+            }
+            AeadAlgorithm::AesCcm16_64_256 => {
+                seal::<Aes256Ccm>(key, nonce, aad, plaintext)
+            }
+            AeadAlgorithm::A128Gcm => {
+                seal::<Aes128Gcm>(key, nonce, aad, plaintext)
+            }
+            AeadAlgorithm::A256Gcm => {
+                seal::<Aes256Gcm>(key, nonce, aad, plaintext)
+            }
+            AeadAlgorithm::ChaCha20Poly1305 => {
+                seal::<ChaCha20Poly1305>(key, nonce, aad, plaintext)
+            }
+        }
+    }
+
+    /// Returns the plaintext, if the ciphertext could be verified.
+    ///
+    /// The key and nonce need to have the lengths of this algorithm.
+    pub(crate) fn decrypt(
+        self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>> {
+        match self {
+            AeadAlgorithm::AesCcm16_64_128 => {
+                open::<AesCcm<U8>>(key, nonce, aad, ciphertext)
+            }
+            AeadAlgorithm::AesCcm16_128_128 => {
+                open::<AesCcm<U16>>(key, nonce, aad, ciphertext)
+            }
+            AeadAlgorithm::AesCcm16_64_256 => {
+                open::<Aes256Ccm>(key, nonce, aad, ciphertext)
+            }
+            AeadAlgorithm::A128Gcm => {
+                open::<Aes128Gcm>(key, nonce, aad, ciphertext)
+            }
+            AeadAlgorithm::A256Gcm => {
+                open::<Aes256Gcm>(key, nonce, aad, ciphertext)
+            }
+            AeadAlgorithm::ChaCha20Poly1305 => {
+                open::<ChaCha20Poly1305>(key, nonce, aad, ciphertext)
+            }
+        }
+    }
+
+    /// Encrypts the buffer in place, without allocating.
+    ///
+    /// The buffer contains the plaintext followed by room for the tag, which
+    /// is written there.
+    pub(crate) fn encrypt_in_place(
+        self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<()> {
+        match self {
+            AeadAlgorithm::AesCcm16_64_128 => {
+                seal_in_place::<AesCcm<U8>>(key, nonce, aad, buffer)
+            }
+            AeadAlgorithm::AesCcm16_128_128 => {
+                seal_in_place::<AesCcm<U16>>(key, nonce, aad, buffer)
+            }
+            AeadAlgorithm::AesCcm16_64_256 => {
+                seal_in_place::<Aes256Ccm>(key, nonce, aad, buffer)
+            }
+            AeadAlgorithm::A128Gcm => {
+                seal_in_place::<Aes128Gcm>(key, nonce, aad, buffer)
+            }
+            AeadAlgorithm::A256Gcm => {
+                seal_in_place::<Aes256Gcm>(key, nonce, aad, buffer)
+            }
+            AeadAlgorithm::ChaCha20Poly1305 => {
+                seal_in_place::<ChaCha20Poly1305>(key, nonce, aad, buffer)
+            }
+        }
+    }
+
+    /// Decrypts the buffer containing the ciphertext and tag in place,
+    /// without allocating, and returns the length of the plaintext at its
+    /// start.
+    pub(crate) fn decrypt_in_place(
+        self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<usize> {
+        match self {
+            AeadAlgorithm::AesCcm16_64_128 => {
+                open_in_place::<AesCcm<U8>>(key, nonce, aad, buffer)
+            }
+            AeadAlgorithm::AesCcm16_128_128 => {
+                open_in_place::<AesCcm<U16>>(key, nonce, aad, buffer)
+            }
+            AeadAlgorithm::AesCcm16_64_256 => {
+                open_in_place::<Aes256Ccm>(key, nonce, aad, buffer)
+            }
+            AeadAlgorithm::A128Gcm => {
+                open_in_place::<Aes128Gcm>(key, nonce, aad, buffer)
+            }
+            AeadAlgorithm::A256Gcm => {
+                open_in_place::<Aes256Gcm>(key, nonce, aad, buffer)
+            }
+            AeadAlgorithm::ChaCha20Poly1305 => {
+                open_in_place::<ChaCha20Poly1305>(key, nonce, aad, buffer)
+            }
+        }
+    }
+}
+
+/// The HKDF algorithms that can be used to derive the keys and Common IV.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum HkdfAlgorithm {
+    /// HKDF SHA-256, the mandatory to implement algorithm.
+    #[default]
+    Sha256,
+    /// HKDF SHA-512.
+    Sha512,
+}
+
+impl HkdfAlgorithm {
+    /// Returns the COSE algorithm identifier.
+    pub fn id(self) -> i32 {
+        match self {
+            HkdfAlgorithm::Sha256 => -10,
+            HkdfAlgorithm::Sha512 => -11,
+        }
+    }
+
+    /// Returns the algorithm with this COSE algorithm identifier.
+    pub fn from_id(id: i32) -> Result<HkdfAlgorithm> {
+        match id {
+            -10 => Ok(HkdfAlgorithm::Sha256),
+            -11 => Ok(HkdfAlgorithm::Sha512),
+            _ => Err(Error::UnsupportedAlgorithm),
+        }
+    }
+}
+
+/// The algorithms used by a security context.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Algorithms {
+    /// The AEAD algorithm, AES-CCM-16-64-128 by default.
+    pub aead: AeadAlgorithm,
+    /// The HKDF algorithm, HKDF SHA-256 by default.
+    pub hkdf: HkdfAlgorithm,
+}
+
+/// Encrypts with a specific AEAD.
+fn seal<A: NewAead + Aead>(
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>> {
+    if key.len() != A::KeySize::to_usize()
+        || nonce.len() != A::NonceSize::to_usize()
+    {
+        return Err(Error::Aead);
+    }
+    let aead = A::new(GenericArray::clone_from_slice(key));
+
+    Ok(aead.encrypt(
+        GenericArray::from_slice(nonce),
+        Payload {
+            aad,
+            msg: plaintext,
+        },
+    )?)
+}
+
+/// Decrypts with a specific AEAD.
+fn open<A: NewAead + Aead>(
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>> {
+    if key.len() != A::KeySize::to_usize()
+        || nonce.len() != A::NonceSize::to_usize()
+    {
+        return Err(Error::Aead);
+    }
+    let aead = A::new(GenericArray::clone_from_slice(key));
+
+    Ok(aead.decrypt(
+        GenericArray::from_slice(nonce),
+        Payload {
+            aad,
+            msg: ciphertext,
+        },
+    )?)
+}
+
+/// Encrypts in place with a specific AEAD.
+fn seal_in_place<A: NewAead + Aead>(
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    buffer: &mut [u8],
+) -> Result<()> {
+    if key.len() != A::KeySize::to_usize()
+        || nonce.len() != A::NonceSize::to_usize()
+        || buffer.len() < A::TagSize::to_usize()
+    {
+        return Err(Error::Aead);
+    }
+    let aead = A::new(GenericArray::clone_from_slice(key));
+    let (plaintext, tag) =
+        buffer.split_at_mut(buffer.len() - A::TagSize::to_usize());
+
+    tag.copy_from_slice(&aead.encrypt_in_place_detached(
+        GenericArray::from_slice(nonce),
+        aad,
+        plaintext,
+    )?);
+
+    Ok(())
+}
+
+/// Decrypts in place with a specific AEAD.
+fn open_in_place<A: NewAead + Aead>(
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    buffer: &mut [u8],
+) -> Result<usize> {
+    if key.len() != A::KeySize::to_usize()
+        || nonce.len() != A::NonceSize::to_usize()
+        || buffer.len() < A::TagSize::to_usize()
+    {
+        return Err(Error::Aead);
+    }
+    let aead = A::new(GenericArray::clone_from_slice(key));
+    let (ciphertext, tag) =
+        buffer.split_at_mut(buffer.len() - A::TagSize::to_usize());
+
+    aead.decrypt_in_place_detached(
+        GenericArray::from_slice(nonce),
+        aad,
+        ciphertext,
+        GenericArray::from_slice(tag),
+    )?;
+
+    Ok(ciphertext.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [AeadAlgorithm; 6] = [
+        AeadAlgorithm::AesCcm16_64_128,
+        AeadAlgorithm::AesCcm16_128_128,
+        AeadAlgorithm::AesCcm16_64_256,
+        AeadAlgorithm::A128Gcm,
+        AeadAlgorithm::A256Gcm,
+        AeadAlgorithm::ChaCha20Poly1305,
+    ];
+
+    #[test]
+    fn identifiers() {
+        for &alg in ALL.iter() {
+            assert_eq!(alg, AeadAlgorithm::from_id(alg.id()).unwrap());
+        }
+        assert_eq!(
+            Error::UnsupportedAlgorithm,
+            AeadAlgorithm::from_id(12).unwrap_err()
+        );
+
+        for &alg in [HkdfAlgorithm::Sha256, HkdfAlgorithm::Sha512].iter() {
+            assert_eq!(alg, HkdfAlgorithm::from_id(alg.id()).unwrap());
+        }
+        assert_eq!(
+            Error::UnsupportedAlgorithm,
+            HkdfAlgorithm::from_id(5).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn aes_ccm_256() {
+        // Cross-checked with another implementation
+        let alg = AeadAlgorithm::AesCcm16_64_256;
+        let key: Vec<u8> = (0..32).collect();
+        let nonce: Vec<u8> = (0x10..0x1D).collect();
+        let aad = [0xAA; 20];
+        let plaintext: Vec<u8> = (0..40).collect();
+        let ciphertext = [
+            0x1D, 0xB3, 0x4E, 0xEB, 0xD3, 0xAA, 0x70, 0x7F, 0x6F, 0x29, 0x9D,
+            0x97, 0xA2, 0x3B, 0x7E, 0x47, 0x52, 0x0D, 0xA6, 0x3F, 0x38, 0xC4,
+            0xE0, 0xFF, 0xC7, 0xDE, 0x41, 0x23, 0xCB, 0x12, 0x58, 0x1E, 0x5E,
+            0xA8, 0x19, 0xA4, 0x2A, 0xDF, 0x55, 0x8D, 0x98, 0xAF, 0xE7, 0x43,
+            0x5E, 0x10, 0xEB, 0x42,
+        ];
+        assert_eq!(
+            &ciphertext[..],
+            &alg.encrypt(&key, &nonce, &aad, &plaintext).unwrap()[..]
+        );
+        assert_eq!(
+            plaintext,
+            alg.decrypt(&key, &nonce, &aad, &ciphertext).unwrap()
+        );
+        // Only the tag for an empty plaintext without AAD
+        assert_eq!(
+            &[0x81, 0x44, 0x2D, 0xB8, 0x4C, 0x7D, 0x50, 0x3A][..],
+            &alg.encrypt(&key, &nonce, &[], &[]).unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn roundtrip() {
+        let aad = [0xAA; 9];
+        let plaintext = [0x01, 0x02, 0x03];
+        for &alg in ALL.iter() {
+            let key = vec![0x42; alg.key_len()];
+            let nonce = vec![0x24; alg.nonce_len()];
+            let ciphertext =
+                alg.encrypt(&key, &nonce, &aad, &plaintext).unwrap();
+            assert_eq!(plaintext.len() + alg.tag_len(), ciphertext.len());
+            assert_eq!(
+                &plaintext[..],
+                &alg.decrypt(&key, &nonce, &aad, &ciphertext).unwrap()[..]
+            );
+
+            // Tampering is detected
+            let mut tampered = ciphertext.clone();
+            tampered[0] ^= 1;
+            assert_eq!(
+                Error::Aead,
+                alg.decrypt(&key, &nonce, &aad, &tampered).unwrap_err()
+            );
+            // In place, the result is the same
+            let mut buffer = plaintext.to_vec();
+            buffer.resize(plaintext.len() + alg.tag_len(), 0);
+            alg.encrypt_in_place(&key, &nonce, &aad, &mut buffer)
+                .unwrap();
+            assert_eq!(ciphertext, buffer);
+            assert_eq!(
+                plaintext.len(),
+                alg.decrypt_in_place(&key, &nonce, &aad, &mut buffer)
+                    .unwrap()
+            );
+            assert_eq!(&plaintext[..], &buffer[..plaintext.len()]);
+            assert_eq!(
+                Error::Aead,
+                alg.decrypt_in_place(&key, &nonce, &aad, &mut tampered)
+                    .unwrap_err()
+            );
+
+            // And so are wrong key lengths
+            assert_eq!(
+                Error::Aead,
+                alg.encrypt(&key[1..], &nonce, &aad, &plaintext)
+                    .unwrap_err()
+            );
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use coap_lite::Packet;
+
+use super::{
+    error::Error,
+    inplace,
+    option::MAX_PIV_LEN,
+    transport::{self, Transport},
+    util, Result,
+};
+
+/// The longest `kid`, since IDs leave 6 bytes of the nonce to the rest.
+const MAX_KID_LEN: usize = inplace::MAX_NONCE_LEN - 6;
+/// The longest token (RFC 7252 Section 3).
+const MAX_TOKEN_LEN: usize = 8;
+
+/// Binds a response to the request it answers.
+///
+/// Responses are protected with the `kid` and `piv` of their request in the
+/// AAD, so a client has to know which request a response belongs to before
+/// it can unprotect it. `protect_request` returns one of these for every
+/// request, which is then passed to `unprotect_response`. This way several
+/// requests can be in flight at the same time.
+///
+/// The token of the request is kept as well, to refuse responses that
+/// belong to a different request early. Since all of these are short, they
+/// are stored inline, so bindings don't need the heap.
+///
+/// A client only accepts a single response per request, which is what
+/// protects responses against replay (RFC 8613 Section 7.4). Once one has
+/// been verified, the binding refuses any others. The notifications of an
+/// observation are unprotected with an `Observation` instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestBinding {
+    request_kid: Inline<MAX_KID_LEN>,
+    request_piv: Inline<MAX_PIV_LEN>,
+    token: Inline<MAX_TOKEN_LEN>,
+    /// Whether a response has been received yet.
+    answered: bool,
+}
+
+impl RequestBinding {
+    /// Creates the `RequestBinding` for a protected request.
+    ///
+    /// This is only needed for requests that weren't protected with
+    /// `protect_request`, which returns the binding already.
+    ///
+    /// # Arguments
+    /// * `request` - The OSCORE request.
+    /// * `transport` - The transport the request is framed for.
+    pub fn new(
+        request: &[u8],
+        transport: Transport,
+    ) -> Result<RequestBinding> {
+        Self::from_packet(&transport::decode(request, transport)?)
+    }
+
+    /// Creates the `RequestBinding` for a parsed protected request.
+    pub(crate) fn from_packet(request: &Packet) -> Result<RequestBinding> {
+        // Extract the kid and piv from its OSCORE option
+        let (request_kid, request_piv, _) =
+            util::extract_kid_piv_context(request)?;
+
+        Self::from_parts(
+            &request_kid.ok_or(Error::NoKidPiv)?,
+            &request_piv.ok_or(Error::NoKidPiv)?,
+            request.get_token(),
+        )
+    }
+
+    /// Creates the `RequestBinding` from its parts.
+    pub(crate) fn from_parts(
+        request_kid: &[u8],
+        request_piv: &[u8],
+        token: &[u8],
+    ) -> Result<RequestBinding> {
+        Ok(RequestBinding {
+            request_kid: Inline::new(request_kid).ok_or(Error::InvalidId)?,
+            request_piv: Inline::new(request_piv).ok_or(Error::NoKidPiv)?,
+            token: Inline::new(token).ok_or(Error::TokenMismatch)?,
+            answered: false,
+        })
+    }
+
+    /// Returns the `kid` of the request.
+    pub(crate) fn get_request_kid(&self) -> &[u8] {
+        self.request_kid.as_slice()
+    }
+
+    /// Returns the `piv` of the request.
+    pub(crate) fn get_request_piv(&self) -> &[u8] {
+        self.request_piv.as_slice()
+    }
+
+    /// Throws an error if a response with this token can't belong to the
+    /// request.
+    pub(crate) fn check_token(&self, token: &[u8]) -> Result<()> {
+        if token == self.token.as_slice() {
+            Ok(())
+        } else {
+            Err(Error::TokenMismatch)
+        }
+    }
+
+    /// Throws an error if the request has been answered already.
+    pub(crate) fn check_answered(&self) -> Result<()> {
+        if self.answered {
+            Err(Error::ReplayDetected)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Remembers that a response to the request has been verified.
+    pub(crate) fn set_answered(&mut self) {
+        self.answered = true;
+    }
+}
+
+/// Up to `N` bytes, stored inline.
+#[derive(Debug, Clone, PartialEq)]
+struct Inline<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Inline<N> {
+    /// Copies the bytes, if there are at most `N` of them.
+    fn new(bytes: &[u8]) -> Option<Inline<N>> {
+        let mut inline = Inline {
+            bytes: [0; N],
+            len: bytes.len(),
+        };
+        inline.bytes.get_mut(..bytes.len())?.copy_from_slice(bytes);
+
+        Some(inline)
+    }
+
+    /// Returns the bytes.
+    fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_vectors::*;
+    use super::*;
+
+    #[test]
+    fn creation() {
+        let binding =
+            RequestBinding::new(&REQ_PROTECTED, Transport::Udp).unwrap();
+        assert_eq!(&CLIENT_ID, binding.get_request_kid());
+        assert_eq!(&REQ_PIV, binding.get_request_piv());
+        assert!(binding.check_token(&REQ_PROTECTED[4..8]).is_ok());
+        assert_eq!(Err(Error::TokenMismatch), binding.check_token(&[0x39]));
+
+        // Unprotected requests don't work
+        assert_eq!(
+            Error::NoOscoreOption,
+            RequestBinding::new(&REQ_UNPROTECTED, Transport::Udp).unwrap_err()
+        );
+        // Neither do responses
+        assert_eq!(
+            Error::NoKidPiv,
+            RequestBinding::new(&RES_PROTECTED, Transport::Udp).unwrap_err()
+        );
+        // IDs longer than any nonce allows
+        assert_eq!(
+            Error::InvalidId,
+            RequestBinding::from_parts(&[0; 8], &REQ_PIV, &[]).unwrap_err()
+        );
+    }
+}
@@ -0,0 +1,478 @@
+use alloc::vec::Vec;
+use coap_lite::{CoapOption, MessageClass, Packet};
+use core::mem;
+
+use super::{
+    error::Error,
+    transport::{self, Transport},
+    Result,
+};
+
+/// The largest size exponent, since 7 is reserved for BERT.
+pub const MAX_SZX: u8 = 6;
+
+/// The value of a Block1 or Block2 option.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockValue {
+    /// The number of the block.
+    pub num: u32,
+    /// Whether more blocks follow this one.
+    pub more: bool,
+    /// The size exponent, the block size is `2^(szx + 4)`.
+    szx: u8,
+}
+
+impl BlockValue {
+    /// Creates a `BlockValue`, refusing size exponents above `MAX_SZX`.
+    ///
+    /// # Arguments
+    /// * `num` - The number of the block.
+    /// * `more` - Whether more blocks follow this one.
+    /// * `szx` - The size exponent, from 0 (16 bytes) to 6 (1024 bytes).
+    pub fn new(num: u32, more: bool, szx: u8) -> Result<BlockValue> {
+        if szx > MAX_SZX {
+            return Err(Error::InvalidBlock);
+        }
+
+        Ok(BlockValue { num, more, szx })
+    }
+
+    /// Returns the size exponent.
+    pub fn szx(&self) -> u8 {
+        self.szx
+    }
+
+    /// Returns the size of a block in bytes.
+    pub fn size(&self) -> usize {
+        1 << (self.szx + 4)
+    }
+
+    /// Returns the offset of the block's first byte in the whole payload.
+    pub fn offset(&self) -> usize {
+        self.num as usize * self.size()
+    }
+
+    /// Returns the byte representation of the option value.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let value = self.num << 4 | (self.more as u32) << 3 | self.szx as u32;
+        encode_uint(value)
+    }
+
+    /// Parses the option value.
+    pub fn from_bytes(bytes: &[u8]) -> Result<BlockValue> {
+        // It's an unsigned integer of at most three bytes
+        if bytes.len() > 3 {
+            return Err(Error::InvalidBlock);
+        }
+        let value = bytes.iter().fold(0, |acc, &b| acc << 8 | b as u32);
+        // An SZX of 7 is reserved for BERT, which we don't support
+        BlockValue::new(value >> 4, value & 0b1000 != 0, (value & 0b111) as u8)
+    }
+}
+
+/// Splits an OSCORE message into blocks using outer block-wise transfer.
+///
+/// This is meant for messages that are too large to pass through proxies in
+/// one piece, as described in RFC 8613 Section 4.1.3.4.2. Requests get outer
+/// Block1 and Size1 options, responses Block2 and Size2. Each block is a
+/// complete CoAP message, but the message ID is the same for all of them, so
+/// the transport layer has to take care of setting it.
+///
+/// # Arguments
+/// * `oscore_msg` - The OSCORE message to split.
+/// * `szx` - The size exponent for the blocks, from 0 (16 bytes) to 6 (1024
+///   bytes).
+/// * `transport` - The transport the message is framed for.
+pub fn fragment(
+    oscore_msg: &[u8],
+    szx: u8,
+    transport: Transport,
+) -> Result<Vec<Vec<u8>>> {
+    if szx > MAX_SZX {
+        return Err(Error::InvalidBlock);
+    }
+    // Parse the CoAP message, keeping the code coap_lite may not know
+    let code = transport::code(oscore_msg, transport)?;
+    let mut original = transport::decode(oscore_msg, transport)?;
+    let (block_option, size_option) = outer_options(&original);
+    // Take out the payload, which is going to be split up
+    let payload = mem::take(&mut original.payload);
+    let block_size = 1 << (szx + 4);
+
+    let mut blocks = vec![];
+    let mut chunks = payload.chunks(block_size).peekable();
+    let mut num = 0;
+    while let Some(chunk) = chunks.next() {
+        let mut block = original.clone();
+        let value = BlockValue::new(num, chunks.peek().is_some(), szx)?;
+        block.add_option(block_option, value.to_bytes());
+        // Indicate the total size in the first block
+        if num == 0 {
+            block.add_option(size_option, encode_uint(payload.len() as u32));
+        }
+        block.payload = chunk.to_vec();
+        blocks.push(transport::encode_with_code(&block, code, transport)?);
+        num += 1;
+    }
+
+    Ok(blocks)
+}
+
+/// Reassembles an OSCORE message from the blocks of an outer block-wise
+/// transfer.
+///
+/// To bound the memory used, messages larger than the maximum unfragmented
+/// size are refused, as recommended by RFC 8613 Section 4.1.3.4.2.
+#[derive(Debug, Clone)]
+pub struct Reassembler {
+    max_unfragmented_size: usize,
+    /// The transport the blocks are framed for.
+    transport: Transport,
+    /// The message being reassembled and its code, if any.
+    message: Option<(Packet, u8)>,
+    /// The number of the block we expect next.
+    next_num: u32,
+}
+
+impl Reassembler {
+    /// Creates a new `Reassembler`.
+    ///
+    /// # Arguments
+    /// * `max_unfragmented_size` - The maximum size in bytes of the payload
+    ///   of a reassembled message.
+    /// * `transport` - The transport the blocks are framed for.
+    pub fn new(
+        max_unfragmented_size: usize,
+        transport: Transport,
+    ) -> Reassembler {
+        Reassembler {
+            max_unfragmented_size,
+            transport,
+            message: None,
+            next_num: 0,
+        }
+    }
+
+    /// Adds a block, returning the complete OSCORE message once the last
+    /// block has been received.
+    ///
+    /// A message without an outer block option is returned as is. Blocks
+    /// have to be added in order, a new transfer can be started at any time
+    /// by adding a block with number 0. On error, the transfer in progress
+    /// is aborted.
+    ///
+    /// # Arguments
+    /// * `block` - The CoAP message carrying the block.
+    pub fn add_block(&mut self, block: &[u8]) -> Result<Option<Vec<u8>>> {
+        let result = self.process(block);
+        if result.is_err() {
+            self.reset();
+        }
+
+        result
+    }
+
+    /// Does the actual work for `add_block`.
+    fn process(&mut self, block: &[u8]) -> Result<Option<Vec<u8>>> {
+        // Parse the CoAP message, keeping the code coap_lite may not know
+        let code = transport::code(block, self.transport)?;
+        let mut block = transport::decode(block, self.transport)?;
+        let (block_option, size_option) = outer_options(&block);
+
+        let value = match block.get_option(block_option) {
+            Some(value_list) => BlockValue::from_bytes(
+                value_list.front().ok_or(Error::InvalidBlock)?,
+            )?,
+            // This is not a fragmented message
+            None => {
+                if block.payload.len() > self.max_unfragmented_size {
+                    return Err(Error::MessageTooLarge);
+                }
+                return Ok(Some(transport::encode_with_code(
+                    &block,
+                    code,
+                    self.transport,
+                )?));
+            }
+        };
+        // Refuse a transfer that announces a size we can't handle
+        if let Some(size) = block.get_option(size_option) {
+            let size = size.front().map_or(0, |s| {
+                s.iter().fold(0, |acc, &b| acc << 8 | b as usize)
+            });
+            if size > self.max_unfragmented_size {
+                return Err(Error::MessageTooLarge);
+            }
+        }
+
+        // The first block starts a new transfer
+        if value.num == 0 {
+            self.reset();
+        }
+        let payload_len =
+            self.message.as_ref().map_or(0, |(m, _)| m.payload.len());
+        // Make sure this block is the one continuing the payload
+        if value.num != self.next_num || value.offset() != payload_len {
+            return Err(Error::InvalidBlock);
+        }
+        // All blocks except the last have to be complete
+        if value.more && block.payload.len() != value.size() {
+            return Err(Error::InvalidBlock);
+        }
+        if payload_len + block.payload.len() > self.max_unfragmented_size {
+            return Err(Error::MessageTooLarge);
+        }
+
+        // Add the payload of this block to the message
+        match &mut self.message {
+            Some((message, _)) => message.payload.extend(&block.payload),
+            None => {
+                // Remove the block-wise options, they're only used for this
+                // transfer
+                block.clear_option(block_option);
+                block.clear_option(size_option);
+                self.message = Some((block, code));
+            }
+        }
+        self.next_num += 1;
+
+        if value.more {
+            return Ok(None);
+        }
+        // We got the last block, so we're done
+        let (message, code) =
+            self.message.take().ok_or(Error::InvalidBlock)?;
+        self.reset();
+
+        Ok(Some(transport::encode_with_code(
+            &message,
+            code,
+            self.transport,
+        )?))
+    }
+
+    /// Aborts the transfer in progress.
+    fn reset(&mut self) {
+        self.message = None;
+        self.next_num = 0;
+    }
+}
+
+/// Returns the block and size options used for this kind of message.
+fn outer_options(message: &Packet) -> (CoapOption, CoapOption) {
+    match message.header.code {
+        MessageClass::Response(_) => (CoapOption::Block2, CoapOption::Size2),
+        _ => (CoapOption::Block1, CoapOption::Size1),
+    }
+}
+
+/// Returns the shortest representation of an unsigned integer option value.
+fn encode_uint(value: u32) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let first_nonzero = bytes.iter().position(|&x| x != 0);
+    match first_nonzero {
+        Some(n) => bytes[n..].to_vec(),
+        // Zero is represented by the empty value
+        None => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use coap_lite::RequestType;
+
+    use super::super::{context::SecurityContext, test_vectors::*};
+    use super::*;
+
+    #[test]
+    fn block_value() {
+        let value = BlockValue::new(0, false, 0).unwrap();
+        assert_eq!(&[0u8; 0][..], &value.to_bytes()[..]);
+        assert_eq!(value, BlockValue::from_bytes(&[]).unwrap());
+
+        let value = BlockValue::new(1, true, 6).unwrap();
+        assert_eq!(&[0x1E][..], &value.to_bytes()[..]);
+        assert_eq!(value, BlockValue::from_bytes(&[0x1E]).unwrap());
+        assert_eq!(1024, value.size());
+        assert_eq!(1024, value.offset());
+
+        let value = BlockValue::new(4096, false, 2).unwrap();
+        assert_eq!(&[0x01, 0x00, 0x02][..], &value.to_bytes()[..]);
+        assert_eq!(
+            value,
+            BlockValue::from_bytes(&[0x01, 0x00, 0x02]).unwrap()
+        );
+
+        assert_eq!(
+            Error::InvalidBlock,
+            BlockValue::from_bytes(&[0x0F]).unwrap_err()
+        );
+        assert_eq!(
+            Error::InvalidBlock,
+            BlockValue::new(0, false, 7).unwrap_err()
+        );
+        assert_eq!(
+            Error::InvalidBlock,
+            BlockValue::new(0, false, u8::MAX).unwrap_err()
+        );
+        assert_eq!(
+            Error::InvalidBlock,
+            BlockValue::from_bytes(&[0x01, 0x00, 0x00, 0x00]).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn fragmentation() {
+        // The request's payload of 13 bytes fits into a single block
+        let blocks = fragment(&REQ_PROTECTED, 0, Transport::Udp).unwrap();
+        assert_eq!(1, blocks.len());
+        // The response's 22 bytes need a full and a partial one
+        let blocks = fragment(&RES_PIV_PROTECTED, 0, Transport::Udp).unwrap();
+        assert_eq!(2, blocks.len());
+
+        let first = Packet::from_bytes(&blocks[0]).unwrap();
+        assert_eq!(16, first.payload.len());
+        assert_eq!(
+            &[0x08][..],
+            &first
+                .get_option(CoapOption::Block2)
+                .unwrap()
+                .front()
+                .unwrap()[..]
+        );
+        assert_eq!(
+            &[22][..],
+            &first
+                .get_option(CoapOption::Size2)
+                .unwrap()
+                .front()
+                .unwrap()[..]
+        );
+        let second = Packet::from_bytes(&blocks[1]).unwrap();
+        assert_eq!(6, second.payload.len());
+        assert_eq!(
+            &[0x10][..],
+            &second
+                .get_option(CoapOption::Block2)
+                .unwrap()
+                .front()
+                .unwrap()[..]
+        );
+        assert!(second.get_option(CoapOption::Size2).is_none());
+
+        assert_eq!(
+            Error::InvalidBlock,
+            fragment(&REQ_PROTECTED, 7, Transport::Udp).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn reassembly() {
+        let mut reassembler = Reassembler::new(1024, Transport::Udp);
+        let blocks = fragment(&RES_PIV_PROTECTED, 0, Transport::Udp).unwrap();
+        assert_eq!(None, reassembler.add_block(&blocks[0]).unwrap());
+        assert_eq!(
+            &RES_PIV_PROTECTED[..],
+            &reassembler.add_block(&blocks[1]).unwrap().unwrap()[..]
+        );
+
+        // Unfragmented messages pass through
+        assert_eq!(
+            &REQ_PROTECTED[..],
+            &reassembler.add_block(&REQ_PROTECTED).unwrap().unwrap()[..]
+        );
+
+        // Missing blocks are detected
+        assert_eq!(
+            Error::InvalidBlock,
+            reassembler.add_block(&blocks[1]).unwrap_err()
+        );
+        // And duplicated ones too
+        assert_eq!(None, reassembler.add_block(&blocks[0]).unwrap());
+        assert_eq!(None, reassembler.add_block(&blocks[0]).unwrap());
+        assert!(reassembler.add_block(&blocks[1]).unwrap().is_some());
+
+        // Blocks framed for TCP
+        let response = Packet::from_bytes(&RES_PIV_PROTECTED).unwrap();
+        let tcp = transport::encode(&response, Transport::Tcp).unwrap();
+        let blocks = fragment(&tcp, 0, Transport::Tcp).unwrap();
+        let mut reassembler = Reassembler::new(1024, Transport::Tcp);
+        assert_eq!(None, reassembler.add_block(&blocks[0]).unwrap());
+        assert_eq!(tcp, reassembler.add_block(&blocks[1]).unwrap().unwrap());
+    }
+
+    #[test]
+    fn observe_registration() {
+        let mut client_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+        let mut server_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+        let mut request = Packet::new();
+        request.header.code = MessageClass::Request(RequestType::Get);
+        request.add_option(CoapOption::Observe, vec![]);
+        request.add_option(CoapOption::UriPath, b"temperature".to_vec());
+        request.payload = vec![0x42; 40];
+        let (protected, _) = client_context
+            .protect_request(&request.to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(transport::FETCH, protected[1]);
+
+        // Every block keeps the FETCH
+        let blocks = fragment(&protected, 0, Transport::Udp).unwrap();
+        assert!(blocks.len() > 1);
+        for block in blocks.iter() {
+            assert_eq!(transport::FETCH, block[1]);
+        }
+
+        // And so does the reassembled registration
+        let mut reassembler = Reassembler::new(1024, Transport::Udp);
+        let (last, rest) = blocks.split_last().unwrap();
+        for block in rest {
+            assert_eq!(None, reassembler.add_block(block).unwrap());
+        }
+        let reassembled = reassembler.add_block(last).unwrap().unwrap();
+        assert_eq!(protected, reassembled);
+        assert!(server_context.unprotect_request(&reassembled).is_ok());
+    }
+
+    #[test]
+    fn max_unfragmented_size() {
+        let blocks = fragment(&RES_PIV_PROTECTED, 0, Transport::Udp).unwrap();
+
+        // The announced size is too large
+        let mut reassembler = Reassembler::new(20, Transport::Udp);
+        assert_eq!(
+            Error::MessageTooLarge,
+            reassembler.add_block(&blocks[0]).unwrap_err()
+        );
+
+        // Without a size announcement, it's only detected at the end
+        let mut first = Packet::from_bytes(&blocks[0]).unwrap();
+        first.clear_option(CoapOption::Size2);
+        let first = first.to_bytes().unwrap();
+        assert_eq!(None, reassembler.add_block(&first).unwrap());
+        assert_eq!(
+            Error::MessageTooLarge,
+            reassembler.add_block(&blocks[1]).unwrap_err()
+        );
+
+        // Unfragmented messages are limited as well
+        let mut reassembler = Reassembler::new(8, Transport::Udp);
+        assert_eq!(
+            Error::MessageTooLarge,
+            reassembler.add_block(&REQ_PROTECTED).unwrap_err()
+        );
+    }
+}
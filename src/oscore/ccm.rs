@@ -0,0 +1,71 @@
+//! AES-CCM with 256 bit keys, which `aes_ccm` doesn't provide.
+//!
+//! This wraps the implementation of the `ccm` crate with the parameters of
+//! the COSE AES-CCM-16 variants (RFC 8152 Section 10.2), a 13 byte nonce and
+//! a 2 byte length field, in the `aead` traits the other algorithms use.
+
+use aes::Aes256;
+use aes_ccm::aead::{
+    generic_array::{
+        typenum::{U0, U13, U32, U8},
+        GenericArray,
+    },
+    Aead, Error, NewAead,
+};
+use ccm::{aead::generic_array, consts, AeadInPlace, Ccm, KeyInit};
+
+/// AES-CCM-16-64-256, AES-CCM-16 with a 256 bit key and an 8 byte tag.
+pub struct Aes256Ccm {
+    cipher: Ccm<Aes256, consts::U8, consts::U13>,
+}
+
+impl NewAead for Aes256Ccm {
+    type KeySize = U32;
+
+    fn new(key: GenericArray<u8, U32>) -> Self {
+        Aes256Ccm {
+            cipher: Ccm::new(generic_array::GenericArray::from_slice(&key)),
+        }
+    }
+}
+
+impl Aead for Aes256Ccm {
+    type NonceSize = U13;
+    type TagSize = U8;
+    type CiphertextOverhead = U0;
+
+    fn encrypt_in_place_detached(
+        &self,
+        nonce: &GenericArray<u8, U13>,
+        associated_data: &[u8],
+        payload: &mut [u8],
+    ) -> Result<GenericArray<u8, U8>, Error> {
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(
+                generic_array::GenericArray::from_slice(nonce),
+                associated_data,
+                payload,
+            )
+            .map_err(|_| Error)?;
+
+        Ok(GenericArray::clone_from_slice(&tag))
+    }
+
+    fn decrypt_in_place_detached(
+        &self,
+        nonce: &GenericArray<u8, U13>,
+        associated_data: &[u8],
+        payload: &mut [u8],
+        tag: &GenericArray<u8, U8>,
+    ) -> Result<(), Error> {
+        self.cipher
+            .decrypt_in_place_detached(
+                generic_array::GenericArray::from_slice(nonce),
+                associated_data,
+                payload,
+                generic_array::GenericArray::from_slice(tag),
+            )
+            .map_err(|_| Error)
+    }
+}
@@ -0,0 +1,186 @@
+use alloc::vec::Vec;
+use coap_lite::CoapOption;
+
+use super::{echo, error::Error, Result};
+
+/// How an option is protected (RFC 8613 Section 4.1).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OptionClass {
+    /// Encrypted and integrity protected, only in the inner message.
+    Inner,
+    /// Unprotected, only in the outer message for proxies.
+    Outer,
+    /// Protected in the inner message, with an outer copy for proxies.
+    Both,
+}
+
+/// The option number of Hop-Limit (RFC 8768).
+const HOP_LIMIT: usize = 16;
+/// The option number of Q-Block1 (RFC 9177).
+const Q_BLOCK1: usize = 19;
+/// The option number of the EDHOC option (RFC 9668).
+const EDHOC: usize = 21;
+/// The option number of Q-Block2 (RFC 9177).
+const Q_BLOCK2: usize = 31;
+/// The option number of Request-Tag (RFC 9175).
+const REQUEST_TAG: usize = 292;
+
+/// The classes of the options defined so far.
+///
+/// Options that aren't listed here are class E, as required for unknown
+/// options. The OSCORE option itself is added and removed separately.
+static KNOWN_CLASSES: [(usize, OptionClass); 25] = [
+    (1, OptionClass::Inner),  // If-Match
+    (3, OptionClass::Outer),  // Uri-Host
+    (4, OptionClass::Inner),  // ETag
+    (5, OptionClass::Inner),  // If-None-Match
+    (6, OptionClass::Both),   // Observe
+    (7, OptionClass::Outer),  // Uri-Port
+    (8, OptionClass::Inner),  // Location-Path
+    (11, OptionClass::Inner), // Uri-Path
+    (12, OptionClass::Inner), // Content-Format
+    (14, OptionClass::Inner), // Max-Age
+    (15, OptionClass::Inner), // Uri-Query
+    (HOP_LIMIT, OptionClass::Outer),
+    (17, OptionClass::Inner), // Accept
+    (Q_BLOCK1, OptionClass::Inner),
+    (20, OptionClass::Inner), // Location-Query
+    (EDHOC, OptionClass::Outer),
+    (23, OptionClass::Inner), // Block2
+    (27, OptionClass::Inner), // Block1
+    (28, OptionClass::Inner), // Size2
+    (Q_BLOCK2, OptionClass::Inner),
+    (35, OptionClass::Outer), // Proxy-Uri
+    (39, OptionClass::Outer), // Proxy-Scheme
+    (60, OptionClass::Inner), // Size1
+    (echo::ECHO, OptionClass::Inner),
+    (REQUEST_TAG, OptionClass::Inner),
+];
+
+/// The classes deciding which options of a message are protected.
+///
+/// Besides the options defined so far, applications can register the
+/// classes of their own options. No-Response is class E, but can be
+/// configured to have an outer copy as well.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OptionClasses {
+    registered: Vec<(usize, OptionClass)>,
+    outer_no_response: bool,
+}
+
+impl OptionClasses {
+    /// Creates the `OptionClasses` with only the known options.
+    pub fn new() -> OptionClasses {
+        OptionClasses::default()
+    }
+
+    /// Registers the class of an option that isn't defined in a standard,
+    /// replacing a previously registered one.
+    ///
+    /// # Arguments
+    /// * `number` - The option number.
+    /// * `class` - The class of the option.
+    pub fn register(
+        &mut self,
+        number: usize,
+        class: OptionClass,
+    ) -> Result<()> {
+        if number == usize::from(CoapOption::NoResponse)
+            || number == usize::from(CoapOption::Oscore)
+            || KNOWN_CLASSES.iter().any(|&(known, _)| known == number)
+        {
+            return Err(Error::UnsupportedOption(CoapOption::from(number)));
+        }
+
+        match self.registered.iter_mut().find(|(n, _)| *n == number) {
+            Some(registered) => registered.1 = class,
+            None => self.registered.push((number, class)),
+        }
+
+        Ok(())
+    }
+
+    /// Returns the class of an option.
+    ///
+    /// # Arguments
+    /// * `number` - The option number.
+    pub fn get(&self, number: usize) -> OptionClass {
+        if number == usize::from(CoapOption::NoResponse) {
+            return match self.outer_no_response {
+                true => OptionClass::Both,
+                false => OptionClass::Inner,
+            };
+        }
+
+        KNOWN_CLASSES
+            .iter()
+            .chain(self.registered.iter())
+            .find(|&&(n, _)| n == number)
+            .map_or(OptionClass::Inner, |&(_, class)| class)
+    }
+
+    /// Returns whether the option is part of the inner message.
+    pub(crate) fn is_inner(&self, number: usize) -> bool {
+        self.get(number) != OptionClass::Outer
+    }
+
+    /// Returns whether the option is part of the outer message.
+    pub(crate) fn is_outer(&self, number: usize) -> bool {
+        self.get(number) != OptionClass::Inner
+    }
+
+    /// Returns whether No-Response has an outer copy.
+    pub(crate) fn get_outer_no_response(&self) -> bool {
+        self.outer_no_response
+    }
+
+    /// Sets whether No-Response has an outer copy.
+    pub(crate) fn set_outer_no_response(&mut self, outer: bool) {
+        self.outer_no_response = outer;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classes() {
+        let mut classes = OptionClasses::new();
+        assert_eq!(
+            OptionClass::Outer,
+            classes.get(CoapOption::ProxyScheme.into())
+        );
+        assert_eq!(OptionClass::Both, classes.get(CoapOption::Observe.into()));
+        assert_eq!(OptionClass::Inner, classes.get(echo::ECHO));
+        assert_eq!(OptionClass::Outer, classes.get(HOP_LIMIT));
+        // Unknown options are protected
+        assert_eq!(OptionClass::Inner, classes.get(65000));
+
+        assert_eq!(
+            OptionClass::Inner,
+            classes.get(CoapOption::NoResponse.into())
+        );
+        classes.set_outer_no_response(true);
+        assert_eq!(
+            OptionClass::Both,
+            classes.get(CoapOption::NoResponse.into())
+        );
+
+        classes.register(65000, OptionClass::Outer).unwrap();
+        assert_eq!(OptionClass::Outer, classes.get(65000));
+        assert!(classes.is_outer(65000) && !classes.is_inner(65000));
+        classes.register(65000, OptionClass::Both).unwrap();
+        assert!(classes.is_outer(65000) && classes.is_inner(65000));
+
+        // The known options can't be changed
+        assert_eq!(
+            Err(Error::UnsupportedOption(CoapOption::UriPath)),
+            classes.register(CoapOption::UriPath.into(), OptionClass::Outer)
+        );
+        assert_eq!(
+            Err(Error::UnsupportedOption(CoapOption::NoResponse)),
+            classes.register(CoapOption::NoResponse.into(), OptionClass::Both)
+        );
+    }
+}
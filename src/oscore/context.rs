@@ -1,37 +1,58 @@
-use aes_ccm::{
-    aead::{generic_array::typenum::U8, Aead, NewAead, Payload},
-    AesCcm,
-};
-use alloc::{collections::LinkedList, vec::Vec};
+use alloc::{boxed::Box, collections::LinkedList, vec::Vec};
 use coap_lite::{CoapOption, MessageClass, Packet, RequestType, ResponseType};
-use core::convert::TryFrom;
+use serde::de::IgnoredAny;
+use serde_bytes::{ByteBuf, Bytes};
+
+use crate::cbor;
 
 use super::{
+    algorithm::{AeadAlgorithm, Algorithms, HkdfAlgorithm},
+    binding::RequestBinding,
+    classes::{OptionClass, OptionClasses},
+    echo::{self, WindowState},
     error::Error,
+    inplace,
+    observe::Observation,
+    option::OscoreOptionRef,
+    replay::ReplayWindow,
+    storage::SequenceNumberStorage,
+    transport::{self, Transport},
     util::{self, ProxyUri},
     Result,
 };
 
 /// The common context part of the security context.
 struct CommonContext {
-    // Master secret and salt are unused, hence not part of this
-    common_iv: [u8; util::NONCE_LEN],
+    // The master secret is kept for key updates, the salt is unused
+    master_secret: Vec<u8>,
+    algorithms: Algorithms,
+    id_context: Option<Vec<u8>>,
+    common_iv: Vec<u8>,
 }
 
 /// The sender context part of the security context.
 struct SenderContext {
     sender_id: Vec<u8>,
-    sender_key: [u8; util::KEY_LEN],
+    sender_key: Vec<u8>,
     sender_sequence_number: u64,
+    // Where the sender sequence number is persisted, if anywhere
+    storage: Option<Box<dyn SequenceNumberStorage>>,
+    // The number of messages between commits to the storage (K)
+    commit_interval: u64,
+    // The value last committed to the storage
+    stored_sequence_number: u64,
+    // The sequence number at which to warn about exhaustion
+    warning_threshold: u64,
+    // The callback for the warning, until it has been called
+    warning_callback: Option<Box<dyn FnMut(u64) + Send>>,
 }
 
 /// The recipient context part of the security context.
 struct RecipientContext {
     recipient_id: Vec<u8>,
-    recipient_key: [u8; util::KEY_LEN],
-    // We're assuming a reliable transport and therefore only store the last
-    // received partial IV for simplicity
-    replay_window: Option<u64>,
+    recipient_key: Vec<u8>,
+    replay_window: ReplayWindow,
+    window_state: WindowState,
 }
 
 /// The security context.
@@ -39,70 +60,174 @@ pub struct SecurityContext {
     common_context: CommonContext,
     sender_context: SenderContext,
     recipient_context: RecipientContext,
+    /// The classes deciding which options are protected.
+    option_classes: OptionClasses,
+    /// Whether responses get an outer Max-Age of 0 against caching.
+    outer_max_age: bool,
+    /// Whether unprotected requests get a single, complete Proxy-Uri.
+    recompose_proxy_uri: bool,
+    /// The transport the messages are framed for.
+    transport: Transport,
 }
 
-/// The known class U options that have to remain public.
-static CLASS_U: [CoapOption; 4] = [
-    CoapOption::UriHost,
-    CoapOption::UriPort,
-    CoapOption::ProxyUri,
-    CoapOption::ProxyScheme,
-];
-/// The optional options that we don't support.
-static UNSUPPORTED: [CoapOption; 6] = [
-    CoapOption::Observe,
-    CoapOption::Block2,
-    CoapOption::Block1,
-    CoapOption::Size2,
-    CoapOption::Size1,
-    CoapOption::NoResponse,
-];
+/// The largest sender sequence number, since the partial IV is limited to
+/// 5 bytes (RFC 8613 Section 3.1).
+pub const MAX_SEQUENCE_NUMBER: u64 = (1 << 40) - 1;
+
+/// The version of the exported context state.
+const STATE_VERSION: u8 = 1;
+
+/// The exported context state, in the order it's encoded.
+///
+/// (id_context, common_iv, sender_id, sender_key, sender_sequence_number,
+/// recipient_id, recipient_key, window_size, window_highest,
+/// window_bitmap_high, window_bitmap_low, window_valid, outer_no_response,
+/// alg_aead, alg_hkdf, master_secret)
+type State = (
+    Option<ByteBuf>,
+    ByteBuf,
+    ByteBuf,
+    ByteBuf,
+    u64,
+    ByteBuf,
+    ByteBuf,
+    usize,
+    Option<u64>,
+    u64,
+    u64,
+    bool,
+    bool,
+    i32,
+    i32,
+    ByteBuf,
+);
 
 impl SecurityContext {
-    /// Creates a new `SecurityContext`.
+    /// Creates a new `SecurityContext` using the default algorithms.
+    ///
+    /// # Arguments
+    /// * `master_secret` - The master secret.
+    /// * `master_salt` - The master salt.
+    /// * `id_context` - The optional ID Context, which is needed when
+    ///   multiple contexts share the same recipient ID. It can be at most 255
+    ///   bytes long.
+    /// * `sender_id` - The own ID, at most 7 bytes long for AES-CCM and 6
+    ///   bytes for the other algorithms.
+    /// * `recipient_id` - The peer's ID, with the same limit.
     pub fn new(
         master_secret: Vec<u8>,
         master_salt: Vec<u8>,
+        id_context: Option<Vec<u8>>,
+        sender_id: Vec<u8>,
+        recipient_id: Vec<u8>,
+    ) -> Result<SecurityContext> {
+        SecurityContext::with_algorithms(
+            master_secret,
+            master_salt,
+            id_context,
+            sender_id,
+            recipient_id,
+            Algorithms::default(),
+        )
+    }
+
+    /// Creates a new `SecurityContext` using the given algorithms.
+    ///
+    /// # Arguments
+    /// * `master_secret` - The master secret.
+    /// * `master_salt` - The master salt.
+    /// * `id_context` - The optional ID Context, which is needed when
+    ///   multiple contexts share the same recipient ID. It can be at most 255
+    ///   bytes long.
+    /// * `sender_id` - The own ID, at most 7 bytes long for AES-CCM and 6
+    ///   bytes for the other algorithms.
+    /// * `recipient_id` - The peer's ID, with the same limit.
+    /// * `algorithms` - The algorithms, which have to match the peer's.
+    pub fn with_algorithms(
+        master_secret: Vec<u8>,
+        master_salt: Vec<u8>,
+        id_context: Option<Vec<u8>>,
         sender_id: Vec<u8>,
         recipient_id: Vec<u8>,
+        algorithms: Algorithms,
     ) -> Result<SecurityContext> {
-        // Derive the keys and IV
-        let sender_key_vec = util::hkdf(
+        // The length of the kid context has to fit into a single byte in the
+        // OSCORE option
+        if let Some(id_context) = &id_context {
+            if id_context.len() > 255 {
+                return Err(Error::InvalidIdContext);
+            }
+        }
+        // Longer IDs would make different IDs share nonces
+        util::check_id(&sender_id, algorithms.aead)?;
+        util::check_id(&recipient_id, algorithms.aead)?;
+
+        // Derive the keys and IV with the lengths the AEAD needs
+        let alg_aead = algorithms.aead.id();
+        let key_len = algorithms.aead.key_len();
+        let nonce_len = algorithms.aead.nonce_len();
+        let sender_key = util::hkdf(
+            algorithms.hkdf,
             &master_secret,
             &master_salt,
-            &util::build_info(&sender_id, "Key", 16)?,
-            16,
+            &util::build_info(
+                &sender_id,
+                id_context.as_deref(),
+                alg_aead,
+                "Key",
+                key_len,
+            )?,
+            key_len,
         )?;
-        let recipient_key_vec = util::hkdf(
+        let recipient_key = util::hkdf(
+            algorithms.hkdf,
             &master_secret,
             &master_salt,
-            &util::build_info(&recipient_id, "Key", 16)?,
-            16,
+            &util::build_info(
+                &recipient_id,
+                id_context.as_deref(),
+                alg_aead,
+                "Key",
+                key_len,
+            )?,
+            key_len,
         )?;
-        let common_iv_vec = util::hkdf(
+        let common_iv = util::hkdf(
+            algorithms.hkdf,
             &master_secret,
             &master_salt,
-            &util::build_info(&[], "IV", 13)?,
-            13,
+            &util::build_info(
+                &[],
+                id_context.as_deref(),
+                alg_aead,
+                "IV",
+                nonce_len,
+            )?,
+            nonce_len,
         )?;
-        let mut sender_key = [0; util::KEY_LEN];
-        sender_key.copy_from_slice(&sender_key_vec);
-        let mut recipient_key = [0; util::KEY_LEN];
-        recipient_key.copy_from_slice(&recipient_key_vec);
-        let mut common_iv = [0; util::NONCE_LEN];
-        common_iv.copy_from_slice(&common_iv_vec);
 
         // Build the subcontexts
-        let common_context = CommonContext { common_iv };
+        let common_context = CommonContext {
+            master_secret,
+            algorithms,
+            id_context,
+            common_iv,
+        };
         let sender_context = SenderContext {
             sender_id,
             sender_key,
             sender_sequence_number: 0,
+            storage: None,
+            commit_interval: 0,
+            stored_sequence_number: 0,
+            warning_threshold: 0,
+            warning_callback: None,
         };
         let recipient_context = RecipientContext {
             recipient_id,
             recipient_key,
-            replay_window: None,
+            replay_window: ReplayWindow::default(),
+            window_state: WindowState::Valid,
         };
 
         // Combine them to the final thing
@@ -110,19 +235,190 @@ impl SecurityContext {
             common_context,
             sender_context,
             recipient_context,
+            option_classes: OptionClasses::new(),
+            outer_max_age: false,
+            recompose_proxy_uri: false,
+            transport: Transport::Udp,
+        })
+    }
+
+    /// Returns the CBOR encoded state of the context, which can be turned
+    /// back into a context with `restore`.
+    ///
+    /// This contains the master secret and the derived keys, so it has to be
+    /// stored securely. The sequence number storage isn't part of it.
+    /// Since restoring a state that's older than the last message sent would
+    /// reuse nonces, the context shouldn't be used after exporting it,
+    /// unless it's exported again afterwards or the sequence number is
+    /// persisted with `set_sequence_number_storage` as well.
+    pub fn export(&self) -> Result<Vec<u8>> {
+        let (window_size, window_highest, window_bitmap) =
+            self.recipient_context.replay_window.state();
+        let state = (
+            self.common_context.id_context.as_deref().map(Bytes::new),
+            Bytes::new(&self.common_context.common_iv),
+            Bytes::new(&self.sender_context.sender_id),
+            Bytes::new(&self.sender_context.sender_key),
+            self.sender_context.sender_sequence_number,
+            Bytes::new(&self.recipient_context.recipient_id),
+            Bytes::new(&self.recipient_context.recipient_key),
+            window_size,
+            window_highest,
+            (window_bitmap >> 64) as u64,
+            window_bitmap as u64,
+            // A challenge is meaningless after a restore, so we only keep
+            // whether the window can be trusted
+            self.recipient_context.window_state == WindowState::Valid,
+            self.option_classes.get_outer_no_response(),
+            self.aead().id(),
+            self.common_context.algorithms.hkdf.id(),
+            Bytes::new(&self.common_context.master_secret),
+        );
+
+        Ok(cbor::encode((STATE_VERSION, state))?)
+    }
+
+    /// Creates a `SecurityContext` from the state returned by `export`.
+    ///
+    /// # Arguments
+    /// * `state` - The CBOR encoded state of the context.
+    pub fn restore(state: &[u8]) -> Result<SecurityContext> {
+        // Make sure we understand this version before decoding the rest
+        let (version, _) = cbor::decode::<(u8, IgnoredAny)>(state)?;
+        if version != STATE_VERSION {
+            return Err(Error::InvalidState);
+        }
+        let (
+            _,
+            (
+                id_context,
+                common_iv,
+                sender_id,
+                sender_key,
+                sender_sequence_number,
+                recipient_id,
+                recipient_key,
+                window_size,
+                window_highest,
+                window_bitmap_high,
+                window_bitmap_low,
+                window_valid,
+                outer_no_response,
+                alg_aead,
+                alg_hkdf,
+                master_secret,
+            ),
+        ) = cbor::decode::<(u8, State)>(state)?;
+        let mut option_classes = OptionClasses::new();
+        option_classes.set_outer_no_response(outer_no_response);
+
+        if let Some(id_context) = &id_context {
+            if id_context.len() > 255 {
+                return Err(Error::InvalidIdContext);
+            }
+        }
+        let aead = AeadAlgorithm::from_id(alg_aead)?;
+        let hkdf = HkdfAlgorithm::from_id(alg_hkdf)?;
+        util::check_id(&sender_id, aead)?;
+        util::check_id(&recipient_id, aead)?;
+        if common_iv.len() != aead.nonce_len()
+            || sender_key.len() != aead.key_len()
+            || recipient_key.len() != aead.key_len()
+            // An exhausted context is fine, anything beyond isn't
+            || sender_sequence_number > MAX_SEQUENCE_NUMBER + 1
+        {
+            return Err(Error::InvalidState);
+        }
+        let replay_window = ReplayWindow::from_state(
+            window_size,
+            window_highest,
+            (window_bitmap_high as u128) << 64 | window_bitmap_low as u128,
+        )?;
+
+        Ok(SecurityContext {
+            common_context: CommonContext {
+                master_secret: master_secret.into_vec(),
+                algorithms: Algorithms { aead, hkdf },
+                id_context: id_context.map(ByteBuf::into_vec),
+                common_iv: common_iv.into_vec(),
+            },
+            sender_context: SenderContext {
+                sender_id: sender_id.into_vec(),
+                sender_key: sender_key.into_vec(),
+                sender_sequence_number,
+                storage: None,
+                commit_interval: 0,
+                stored_sequence_number: 0,
+                warning_threshold: 0,
+                warning_callback: None,
+            },
+            recipient_context: RecipientContext {
+                recipient_id: recipient_id.into_vec(),
+                recipient_key: recipient_key.into_vec(),
+                replay_window,
+                window_state: if window_valid {
+                    WindowState::Valid
+                } else {
+                    WindowState::Unknown
+                },
+            },
+            option_classes,
+            outer_max_age: false,
+            recompose_proxy_uri: false,
+            transport: Transport::Udp,
         })
     }
 
-    /// Returns an OSCORE message based on the original CoAP request.
+    /// Returns an OSCORE message based on the original CoAP request,
+    /// together with the `RequestBinding` to unprotect its response with.
+    ///
+    /// # Arguments
+    /// * `coap_msg` - The original CoAP request to protect.
+    pub fn protect_request(
+        &mut self,
+        coap_msg: &[u8],
+    ) -> Result<(Vec<u8>, RequestBinding)> {
+        let (request, binding) =
+            self.protect_request_packet(self.decode(coap_msg)?)?;
+
+        Ok((transport::encode_oscore(&request, self.transport)?, binding))
+    }
+
+    /// Returns an OSCORE message based on the original CoAP request, for
+    /// callers that already have it parsed, together with the
+    /// `RequestBinding` to unprotect its response with.
+    ///
+    /// Since coap_lite has no FETCH, a request that keeps an outer Observe
+    /// has POST here, and has to be sent with `transport::FETCH` instead.
     ///
     /// # Arguments
     /// * `coap_msg` - The original CoAP request to protect.
-    pub fn protect_request(&mut self, coap_msg: &[u8]) -> Result<Vec<u8>> {
+    pub fn protect_request_packet(
+        &mut self,
+        coap_msg: Packet,
+    ) -> Result<(Packet, RequestBinding)> {
+        let request = self.protect_request_kudos(coap_msg, None)?;
+        let binding = RequestBinding::from_packet(&request)?;
+
+        Ok((request, binding))
+    }
+
+    /// Returns an OSCORE message based on the original CoAP request, with
+    /// the values of a key update in the OSCORE option if present.
+    pub(crate) fn protect_request_kudos(
+        &mut self,
+        coap_msg: Packet,
+        kudos: Option<(u8, &[u8])>,
+    ) -> Result<Packet> {
         // Store piv for this execution
         let piv = self.get_piv();
 
         // Compute the AAD
-        let aad = util::build_aad(&self.sender_context.sender_id, &piv)?;
+        let aad = util::build_aad(
+            self.aead().id(),
+            &self.sender_context.sender_id,
+            &piv,
+        )?;
 
         // Build nonce from own sender context
         let nonce = util::compute_nonce(
@@ -130,12 +426,15 @@ impl SecurityContext {
             &self.sender_context.sender_id,
             &self.common_context.common_iv,
         );
-        // Encode the kid and piv in the OSCORE option
-        let option = util::build_oscore_option(
+        // Refuse an exhausted sequence number before its piv is encoded
+        self.increment_sequence_number()?;
+        // Encode the kid, piv and kid context in the OSCORE option
+        let option = util::build_oscore_option_kudos(
             Some(&self.sender_context.sender_id),
             Some(&piv),
-        );
-        self.sender_context.sender_sequence_number += 1;
+            self.common_context.id_context.as_deref(),
+            kudos,
+        )?;
 
         // Use these values to protect the message
         self.protect_message(coap_msg, &aad, nonce, option)
@@ -148,20 +447,62 @@ impl SecurityContext {
     /// * `request` - The OSCORE request to which to respond. Necessary to
     ///   extract `kid` and `piv` values.
     /// * `reuse_piv` - Whether the request's `piv` should be reused. Otherwise
-    ///   the own `sender_sequence_number` will be used.
+    ///   the own `sender_sequence_number` will be used. This is ignored for
+    ///   Observe notifications, which always get their own `piv`.
     pub fn protect_response(
         &mut self,
         coap_msg: &[u8],
         request: &[u8],
         reuse_piv: bool,
     ) -> Result<Vec<u8>> {
+        let response = self.protect_response_packet(
+            self.decode(coap_msg)?,
+            &self.decode(request)?,
+            reuse_piv,
+        )?;
+
+        self.encode(&response)
+    }
+
+    /// Returns an OSCORE message based on the original CoAP response, for
+    /// callers that already have it parsed.
+    ///
+    /// # Arguments
+    /// * `coap_msg` - The original CoAP response to protect.
+    /// * `request` - The OSCORE request to which to respond.
+    /// * `reuse_piv` - Whether the request's `piv` should be reused, as in
+    ///   `protect_response`.
+    pub fn protect_response_packet(
+        &mut self,
+        coap_msg: Packet,
+        request: &Packet,
+        reuse_piv: bool,
+    ) -> Result<Packet> {
+        self.protect_response_kudos(coap_msg, request, reuse_piv, None)
+    }
+
+    /// Returns an OSCORE message based on the original CoAP response, with
+    /// the values of a key update in the OSCORE option if present.
+    ///
+    /// A response carrying them can't reuse the request's `piv`, since the
+    /// option couldn't be empty anyway.
+    pub(crate) fn protect_response_kudos(
+        &mut self,
+        coap_msg: Packet,
+        request: &Packet,
+        reuse_piv: bool,
+        kudos: Option<(u8, &[u8])>,
+    ) -> Result<Packet> {
         // Store piv for this execution
         let piv = self.get_piv();
-
-        // Parse the request to which we respond
-        let request = Packet::from_bytes(request)?;
-        // Extract the kid and piv from its OSCORE option
-        let (request_kid, request_piv) = util::extract_kid_piv(&request)?;
+        // Notifications need a fresh piv, so the client can order them
+        let reuse_piv = reuse_piv
+            && kudos.is_none()
+            && coap_msg.get_option(CoapOption::Observe).is_none();
+
+        // Extract the kid and piv from the request's OSCORE option
+        let (request_kid, request_piv, _) =
+            util::extract_kid_piv_context(request)?;
         // This is a request, so they need to be present
         let (request_kid, request_piv) = (
             request_kid.ok_or(Error::NoKidPiv)?,
@@ -169,7 +510,8 @@ impl SecurityContext {
         );
 
         // Compute the AAD
-        let aad = util::build_aad(&request_kid, &request_piv)?;
+        let aad =
+            util::build_aad(self.aead().id(), &request_kid, &request_piv)?;
 
         // Decide on the nonce and option value
         let (nonce, option) = if reuse_piv {
@@ -181,22 +523,26 @@ impl SecurityContext {
                     &self.recipient_context.recipient_id,
                     &self.common_context.common_iv,
                 ),
-                util::build_oscore_option(None, None),
+                util::build_oscore_option(None, None, None)?,
             )
         } else {
             // We're not reusing the request's piv:
-            // Build nonce from own sender context, transmit piv but no kid
-            let result = (
+            // Build nonce from own sender context, transmit piv but no kid.
+            // Since we use our sender context, increment the sequence number
+            self.increment_sequence_number()?;
+            (
                 util::compute_nonce(
                     &piv,
                     &self.sender_context.sender_id,
                     &self.common_context.common_iv,
                 ),
-                util::build_oscore_option(None, Some(&piv)),
-            );
-            // Since we used our sender context, increment the sequence number
-            self.sender_context.sender_sequence_number += 1;
-            result
+                util::build_oscore_option_kudos(
+                    None,
+                    Some(&piv),
+                    None,
+                    kudos,
+                )?,
+            )
         };
 
         // Use these values to protect the message
@@ -212,103 +558,28 @@ impl SecurityContext {
     /// * `option` - The value of the OSCORE option.
     fn protect_message(
         &self,
-        coap_msg: &[u8],
+        coap_msg: Packet,
         aad: &[u8],
-        nonce: [u8; util::NONCE_LEN],
+        nonce: Vec<u8>,
         option: Vec<u8>,
-    ) -> Result<Vec<u8>> {
-        // Parse the CoAP message
-        let mut original = Packet::from_bytes(coap_msg)?;
-        // Initialize a new CoAP message to store the protected parts
-        let mut inner = Packet::new();
-
-        // Move the code into the inner message
-        inner.header.code = original.header.code;
-        // Replace the outer code
-        original.header.code = match original.header.code {
-            // All responses get Changed
-            MessageClass::Response(_) => {
-                MessageClass::Response(ResponseType::Changed)
-            }
-            // All requests (and unknown + reserved) get POST
-            _ => MessageClass::Request(RequestType::Post),
-        };
-
-        // Proxy-Uri handling if it's present
-        if let Some(proxy_uri) = original.get_option(CoapOption::ProxyUri) {
-            // Obtain and parse it
-            let proxy_uri = proxy_uri.front().ok_or(Error::InvalidProxyUri)?;
-            let proxy_uri = ProxyUri::try_from(proxy_uri.as_slice())?;
-
-            // If there's a Uri-Path or Uri-Query, add them to the options and
-            // they will be protected in the next stage
-            if let Some(path_list) = proxy_uri.get_path_list() {
-                original.set_option(CoapOption::UriPath, path_list);
-            }
-            if let Some(query_list) = proxy_uri.get_query_list() {
-                original.set_option(CoapOption::UriQuery, query_list);
-            }
-
-            // Compose the remaining parts into the Proxy-Uri, which will
-            // remain public
-            let mut uri_list = LinkedList::new();
-            uri_list.push_back(proxy_uri.compose_proxy_uri());
-            original.set_option(CoapOption::ProxyUri, uri_list);
-        }
-
-        // Store which options we remove from the outer message in this
-        let mut moved_options = vec![];
-        // Go over options, moving class E ones into the inner message
-        for (number, value_list) in original.options() {
-            let option = CoapOption::from(*number);
-
-            // Abort on unimplemented optional features
-            if UNSUPPORTED.contains(&option) {
-                return Err(Error::UnsupportedOption(option));
-            }
-            // Skip class U options
-            if CLASS_U.contains(&option) {
-                continue;
-            }
-
-            // At this point the option is class E or undefined, so protect it
-            // Add it to the inner message
-            inner.set_option(option, value_list.clone());
-            // Remember it's been moved
-            moved_options.push(option);
-        }
-        // Remove the moved options from the original
-        for option in moved_options {
-            original.clear_option(option);
-        }
-
-        // Move the payload out of the original into the new one
-        inner.payload = original.payload;
-        // Convert the inner message to its byte representation
-        let mut inner_bytes = inner.to_bytes()?;
-        // Remove the message ID and the token (if it exists)
-        let tkl = inner.header.get_token_length();
-        inner_bytes.drain(2..4 + tkl as usize);
-        // Remove the first header byte
-        inner_bytes.remove(0);
-
-        // Encrypt the payload
-        let ccm: AesCcm<U8> =
-            AesCcm::new(self.sender_context.sender_key.into());
-        let ciphertext_buf = ccm.encrypt(
-            &nonce.into(),
-            Payload {
-                aad,
-                msg: &inner_bytes,
-            },
+    ) -> Result<Packet> {
+        let is_response =
+            matches!(coap_msg.header.code, MessageClass::Response(_));
+        let mut oscore_msg = encrypt_message(
+            coap_msg,
+            self.aead(),
+            &self.sender_context.sender_key,
+            aad,
+            &nonce,
+            option,
+            &self.option_classes,
         )?;
-        // Set the ciphertext as the new payload
-        original.payload = ciphertext_buf;
-
-        // Add the OSCORE option
-        original.add_option(CoapOption::Oscore, option);
+        // The inner Max-Age is protected, this one is for intermediaries
+        if is_response && self.outer_max_age {
+            oscore_msg.add_option(CoapOption::MaxAge, vec![]);
+        }
 
-        Ok(original.to_bytes()?)
+        Ok(oscore_msg)
     }
 
     /// Returns the original CoAP request protected in the OSCORE message.
@@ -316,21 +587,48 @@ impl SecurityContext {
     /// # Arguments
     /// * `oscore_msg` - The OSCORE message protecting the CoAP request.
     pub fn unprotect_request(&mut self, oscore_msg: &[u8]) -> Result<Vec<u8>> {
-        // Parse the CoAP message
-        let original = Packet::from_bytes(oscore_msg)?;
-        // Extract the kid and piv from the OSCORE option
-        let (request_kid, request_piv) = util::extract_kid_piv(&original)?;
+        let request =
+            self.unprotect_request_packet(self.decode(oscore_msg)?)?;
+
+        self.encode(&request)
+    }
+
+    /// Returns the original CoAP request protected in the OSCORE message,
+    /// for callers that already have it parsed.
+    ///
+    /// # Arguments
+    /// * `original` - The OSCORE message protecting the CoAP request.
+    pub fn unprotect_request_packet(
+        &mut self,
+        original: Packet,
+    ) -> Result<Packet> {
+        // Extract the kid, piv and kid context from the OSCORE option
+        let (request_kid, request_piv, request_kid_context) =
+            util::extract_kid_piv_context(&original)?;
         // This is a request, so they need to be present
         let (request_kid, request_piv) = (
             request_kid.ok_or(Error::NoKidPiv)?,
             request_piv.ok_or(Error::NoKidPiv)?,
         );
+        // If there's a kid context, it has to be the one of this context
+        if request_kid_context.is_some()
+            && request_kid_context != self.common_context.id_context
+        {
+            return Err(Error::IdContextMismatch);
+        }
 
-        // Verify that the partial IV has not been received before
-        self.check_and_remember(&request_piv)?;
+        // Verify that the partial IV has not been received before, unless
+        // we can't tell yet
+        let sequence_number = util::piv_to_u64(&request_piv);
+        if self.recipient_context.window_state == WindowState::Valid {
+            self.recipient_context
+                .replay_window
+                .check(sequence_number)?;
+        }
 
         // Compute the AAD
-        let aad = util::build_aad(&request_kid, &request_piv)?;
+        let aad =
+            util::build_aad(self.aead().id(), &request_kid, &request_piv)?;
 
         // Compute the nonce
         let nonce = util::compute_nonce(
@@ -339,250 +637,2467 @@ impl SecurityContext {
             &self.common_context.common_iv,
         );
 
-        // Use these values to protect the message
-        self.unprotect_message(original, &aad, nonce)
+        // Use these values to unprotect the message
+        let mut unprotected = self.unprotect_message(original, &aad, nonce)?;
+        if self.recompose_proxy_uri {
+            util::recompose_proxy_uri(&mut unprotected)?;
+        }
+
+        // Only remember the partial IV once the message has been verified
+        match &self.recipient_context.window_state {
+            WindowState::Valid => {
+                self.recipient_context.replay_window.update(sequence_number);
+            }
+            // If the request answers our challenge, it's fresh and we can
+            // reinitialize the replay window from it
+            WindowState::Challenged(challenge)
+                if echo::get_echo(&unprotected) == Some(challenge) =>
+            {
+                self.recipient_context
+                    .replay_window
+                    .initialize(sequence_number);
+                self.recipient_context.window_state = WindowState::Valid;
+            }
+            _ => return Err(Error::EchoRequired),
+        }
+
+        Ok(unprotected)
+    }
+
+    /// Returns the OSCORE response challenging the client to prove the
+    /// freshness of its request.
+    ///
+    /// This is the protected 4.01 (Unauthorized) response with an Echo option
+    /// to send when `unprotect_request` returns `Error::EchoRequired`. Once
+    /// the client repeats its request with the Echo value, the replay window
+    /// is reinitialized.
+    ///
+    /// # Arguments
+    /// * `request` - The OSCORE request to which to respond.
+    /// * `echo` - The Echo value, which has to be unpredictable, so it
+    ///   should come from a good source of randomness. It needs to be between
+    ///   1 and `MAX_ECHO_LEN` bytes long.
+    /// * `message_id` - The fresh Message ID of a non-confirmable response.
+    ///   The piggybacked response to a confirmable request has the
+    ///   request's.
+    pub fn protect_echo_challenge(
+        &mut self,
+        request: &[u8],
+        echo: &[u8],
+        message_id: u16,
+    ) -> Result<Vec<u8>> {
+        let request = self.decode(request)?;
+        let challenge =
+            echo::build_challenge_packet(&request, echo, message_id)?;
+        // We can't reuse the request's piv, since we don't know whether it's
+        // been used before
+        let response =
+            self.protect_response_packet(challenge, &request, false)?;
+        // Remember the challenge if we're waiting for one
+        if self.recipient_context.window_state != WindowState::Valid {
+            self.recipient_context.window_state =
+                WindowState::Challenged(echo.to_vec());
+        }
+
+        self.encode(&response)
     }
 
     /// Returns the original CoAP response protected in the OSCORE message.
     ///
+    /// Only a single response is accepted per request, any further ones
+    /// fail with `Error::ReplayDetected`.
+    ///
     /// # Arguments
     /// * `oscore_msg` - The OSCORE message protecting the CoAP response.
+    /// * `request` - The binding of the request the response belongs to, as
+    ///   returned by `protect_request`.
     pub fn unprotect_response(
         &mut self,
         oscore_msg: &[u8],
+        request: &mut RequestBinding,
     ) -> Result<Vec<u8>> {
-        // Parse the CoAP message
-        let original = Packet::from_bytes(oscore_msg)?;
+        let response =
+            self.unprotect_response_packet(self.decode(oscore_msg)?, request)?;
+
+        self.encode(&response)
+    }
+
+    /// Returns the original CoAP response protected in the OSCORE message,
+    /// for callers that already have it parsed.
+    ///
+    /// # Arguments
+    /// * `original` - The OSCORE message protecting the CoAP response.
+    /// * `request` - The binding of the request the response belongs to.
+    pub fn unprotect_response_packet(
+        &mut self,
+        original: Packet,
+        request: &mut RequestBinding,
+    ) -> Result<Packet> {
+        request.check_token(original.get_token())?;
+        request.check_answered()?;
+        let request_kid = request.get_request_kid();
+        let request_piv = request.get_request_piv();
+
         // Attempt to extract the piv from the OSCORE option
-        let (_, request_piv) = util::extract_kid_piv(&original)?;
+        let (_, response_piv, _) = util::extract_kid_piv_context(&original)?;
         // If we don't reuse the request's piv, extract it from the response
-        let (kid, piv) = match request_piv {
+        let (kid, piv) = match &response_piv {
             // Using the sender's kid & piv
-            Some(piv) => (&self.recipient_context.recipient_id, piv),
-            // Using our kid & piv
-            None => (&self.sender_context.sender_id, self.get_last_piv()),
+            Some(piv) => (&self.recipient_context.recipient_id[..], &piv[..]),
+            // Using the request's kid & piv
+            None => (request_kid, request_piv),
         };
 
         // Compute the AAD
-        let aad = util::build_aad(
-            &self.sender_context.sender_id,
-            &self.get_last_piv(),
-        )?;
+        let aad = util::build_aad(self.aead().id(), request_kid, request_piv)?;
 
         // Compute the nonce
         let nonce =
-            util::compute_nonce(&piv, &kid, &self.common_context.common_iv);
+            util::compute_nonce(piv, kid, &self.common_context.common_iv);
 
         // Use these values to protect the message
-        self.unprotect_message(original, &aad, nonce)
+        let response = self.unprotect_message(original, &aad, nonce)?;
+        // Only remember the response once it has been verified
+        request.set_answered();
+
+        Ok(response)
     }
 
-    /// Returns the original CoAP message protected in the OSCORE message.
+    /// Returns the original CoAP notification protected in the OSCORE
+    /// message.
+    ///
+    /// Since the notifications of an observation are bound to the request
+    /// that registered it, they can't be unprotected with
+    /// `unprotect_response`. This also verifies that each notification is
+    /// newer than the ones previously received for the observation.
+    ///
     /// # Arguments
-    /// * `oscore_msg` - The OSCORE message protecting the CoAP message.
-    /// * `aad` - The AAD for the AEAD.
-    /// * `nonce` - The AEAD nonce to use.
-    fn unprotect_message(
+    /// * `observation` - The observation the notification belongs to.
+    /// * `oscore_msg` - The OSCORE message protecting the CoAP notification.
+    pub fn unprotect_notification(
         &mut self,
-        mut original: Packet,
-        aad: &[u8],
-        nonce: [u8; util::NONCE_LEN],
+        observation: &mut Observation,
+        oscore_msg: &[u8],
     ) -> Result<Vec<u8>> {
-        // Store which options we remove from the outer message in this
-        let mut to_discard = vec![];
-        // Go over options, remembering class E ones to discard
-        for (number, _) in original.options() {
-            let option = CoapOption::from(*number);
-
-            // Abort on unimplemented optional features
-            if UNSUPPORTED.contains(&option) {
-                return Err(Error::UnsupportedOption(option));
-            }
-            // Skip class U options
-            if CLASS_U.contains(&option) {
-                continue;
-            }
+        let notification = self.unprotect_notification_packet(
+            observation,
+            self.decode(oscore_msg)?,
+        )?;
 
-            // At this point the option is class E or undefined, so discard it
-            to_discard.push(option);
-        }
-        // Discard class E options
-        for option in to_discard {
-            original.clear_option(option);
-        }
+        self.encode(&notification)
+    }
 
-        // Decrypt the payload
-        let ccm: AesCcm<U8> =
-            AesCcm::new(self.recipient_context.recipient_key.into());
-        let plaintext_buf = ccm.decrypt(
-            &nonce.into(),
-            Payload {
-                aad,
-                msg: &original.payload,
-            },
+    /// Returns the original CoAP notification protected in the OSCORE
+    /// message, for callers that already have it parsed.
+    ///
+    /// # Arguments
+    /// * `observation` - The observation the notification belongs to.
+    /// * `original` - The OSCORE message protecting the CoAP notification.
+    pub fn unprotect_notification_packet(
+        &mut self,
+        observation: &mut Observation,
+        original: Packet,
+    ) -> Result<Packet> {
+        // Attempt to extract the piv from the OSCORE option
+        let (_, piv, _) = util::extract_kid_piv_context(&original)?;
+
+        // Verify that the notification is fresh
+        observation.check(piv.as_deref())?;
+
+        // Compute the AAD from the registration request
+        let aad = util::build_aad(
+            self.aead().id(),
+            observation.get_request_kid(),
+            observation.get_request_piv(),
         )?;
 
-        // Build a CoAP message from the bytes of the plaintext, which contain
-        // the code, class E options and the payload
-        // [ver_t_tkl, code, message_id, message_id]
-        let mut inner = vec![0x40, plaintext_buf[0], 0x00, 0x00];
-        inner.extend(&plaintext_buf[1..]);
-        // Parse the CoAP message
-        let inner = Packet::from_bytes(&inner)?;
-        // Set the code from the inner message
-        original.header.code = inner.header.code;
-        // Set the options from the inner message
-        for (number, value_list) in inner.options() {
-            original.set_option((*number).into(), value_list.clone());
-        }
-        // Set the payload from the inner message
-        original.payload = inner.payload;
-
-        Ok(original.to_bytes()?)
-    }
-
-    /// Throws an error if the `piv` has been received before and adds it to
-    /// the replay window.
-    fn check_and_remember(&mut self, piv: &[u8]) -> Result<()> {
-        let piv_64 = util::piv_to_u64(&piv);
-        if let Some(previous) = self.recipient_context.replay_window {
-            if previous == piv_64 {
-                #[cfg(not(feature = "no_replay"))]
-                return Err(Error::ReplayDetected);
-            }
-        }
-        // Remember it
-        self.recipient_context.replay_window = Some(piv_64);
+        // Compute the nonce
+        let nonce = match &piv {
+            // Using the sender's kid & piv
+            Some(piv) => util::compute_nonce(
+                piv,
+                &self.recipient_context.recipient_id,
+                &self.common_context.common_iv,
+            ),
+            // Using the request's kid & piv
+            None => util::compute_nonce(
+                observation.get_request_piv(),
+                observation.get_request_kid(),
+                &self.common_context.common_iv,
+            ),
+        };
 
-        Ok(())
-    }
+        // Use these values to unprotect the message
+        let unprotected = self.unprotect_message(original, &aad, nonce)?;
+        // Only remember the notification once it has been verified
+        observation.update(piv.as_deref());
 
-    /// Returns the byte representation of the partial IV.
-    fn get_piv(&self) -> Vec<u8> {
-        util::format_piv(self.sender_context.sender_sequence_number)
+        Ok(unprotected)
     }
 
-    /// Returns the byte representation of the partial IV.
-    fn get_last_piv(&self) -> Vec<u8> {
-        util::format_piv(self.sender_context.sender_sequence_number - 1)
-    }
+    /// Protects the CoAP request into the buffer, returning the length of the
+    /// OSCORE message and the `RequestBinding` to unprotect its response
+    /// with.
+    ///
+    /// This is the same as `protect_request`, for devices where the heap is
+    /// scarce, so nothing is allocated. Since it can't decompose a Proxy-Uri
+    /// option or compose one from Proxy-Scheme, messages with either are
+    /// refused.
+    ///
+    /// # Arguments
+    /// * `coap_msg` - The original CoAP request to protect.
+    /// * `buf` - The buffer for the OSCORE message.
+    pub fn protect_request_into(
+        &mut self,
+        coap_msg: &[u8],
+        buf: &mut [u8],
+    ) -> Result<(usize, RequestBinding)> {
+        self.check_udp()?;
+        let mut piv = [0; 8];
+        let piv = inplace::format_piv(
+            self.sender_context.sender_sequence_number,
+            &mut piv,
+        );
+        let mut aad = [0; inplace::MAX_AAD_LEN];
+        let aad_len = inplace::build_aad(
+            self.aead().id(),
+            &self.sender_context.sender_id,
+            piv,
+            &mut aad,
+        )?;
+        let mut nonce = [0; inplace::MAX_NONCE_LEN];
+        let nonce = &mut nonce[..self.common_context.common_iv.len()];
+        util::write_nonce(
+            piv,
+            &self.sender_context.sender_id,
+            &self.common_context.common_iv,
+            nonce,
+        );
+        self.increment_sequence_number()?;
+
+        let len = self.protect_message_into(
+            coap_msg,
+            &aad[..aad_len],
+            nonce,
+            OscoreOptionRef {
+                kid: Some(&self.sender_context.sender_id),
+                piv: Some(piv),
+                kid_context: self.common_context.id_context.as_deref(),
+                ..Default::default()
+            },
+            buf,
+        )?;
+        let binding = RequestBinding::from_parts(
+            &self.sender_context.sender_id,
+            piv,
+            &inplace::Message::parse(&buf[..len])?.header[4..],
+        )?;
 
-    #[cfg(test)]
-    pub fn set_sender_sequence_number(&mut self, n: u64) {
-        self.sender_context.sender_sequence_number = n;
+        Ok((len, binding))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::super::test_vectors::*;
-    use super::*;
+    /// Protects the CoAP response into the buffer without allocating,
+    /// returning the length of the OSCORE message.
+    ///
+    /// This is the same as `protect_response`, with the restrictions of
+    /// `protect_request_into`.
+    ///
+    /// # Arguments
+    /// * `coap_msg` - The original CoAP response to protect.
+    /// * `request` - The OSCORE request to which to respond.
+    /// * `reuse_piv` - Whether the request's `piv` should be reused.
+    /// * `buf` - The buffer for the OSCORE message.
+    pub fn protect_response_into(
+        &mut self,
+        coap_msg: &[u8],
+        request: &[u8],
+        reuse_piv: bool,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        self.check_udp()?;
+        let mut piv = [0; 8];
+        let piv = inplace::format_piv(
+            self.sender_context.sender_sequence_number,
+            &mut piv,
+        );
+        // Notifications need a fresh piv, so the client can order them
+        let reuse_piv = reuse_piv
+            && inplace::Message::parse(coap_msg)?
+                .option(usize::from(CoapOption::Observe))?
+                .is_none();
+
+        // Extract the kid and piv from the request's OSCORE option
+        let request = inplace::Message::parse(request)?;
+        let request_option = OscoreOptionRef::decode(
+            request
+                .option(inplace::OSCORE)?
+                .ok_or(Error::NoOscoreOption)?,
+        )?;
+        let (request_kid, request_piv) = (
+            request_option.kid.ok_or(Error::NoKidPiv)?,
+            request_option.piv.ok_or(Error::NoKidPiv)?,
+        );
 
-    #[test]
-    fn context_derivation() {
-        let security_context = SecurityContext::new(
-            MASTER_SECRET.to_vec(),
-            MASTER_SALT.to_vec(),
-            CLIENT_ID.to_vec(),
-            SERVER_ID.to_vec(),
-        )
-        .unwrap();
+        let mut aad = [0; inplace::MAX_AAD_LEN];
+        let aad_len = inplace::build_aad(
+            self.aead().id(),
+            request_kid,
+            request_piv,
+            &mut aad,
+        )?;
+        let mut nonce = [0; inplace::MAX_NONCE_LEN];
+        let nonce = &mut nonce[..self.common_context.common_iv.len()];
+        let option = if reuse_piv {
+            util::write_nonce(
+                request_piv,
+                &self.recipient_context.recipient_id,
+                &self.common_context.common_iv,
+                nonce,
+            );
+            OscoreOptionRef::default()
+        } else {
+            util::write_nonce(
+                piv,
+                &self.sender_context.sender_id,
+                &self.common_context.common_iv,
+                nonce,
+            );
+            self.increment_sequence_number()?;
+            OscoreOptionRef {
+                piv: Some(piv),
+                ..Default::default()
+            }
+        };
 
-        assert_eq!(&COMMON_IV, &security_context.common_context.common_iv[..]);
+        self.protect_message_into(
+            coap_msg,
+            &aad[..aad_len],
+            nonce,
+            option,
+            buf,
+        )
+    }
 
-        assert_eq!(&CLIENT_ID, &security_context.sender_context.sender_id[..]);
-        assert_eq!(
-            &CLIENT_KEY,
-            &security_context.sender_context.sender_key[..]
+    /// Unprotects the OSCORE request into the buffer without allocating,
+    /// returning the length of the CoAP request.
+    ///
+    /// This is the same as `unprotect_request`. Since the ciphertext is
+    /// decrypted in the buffer, it needs room for it after the class U
+    /// options of the message.
+    ///
+    /// # Arguments
+    /// * `oscore_msg` - The OSCORE message protecting the CoAP request.
+    /// * `buf` - The buffer for the CoAP request.
+    pub fn unprotect_request_into(
+        &mut self,
+        oscore_msg: &[u8],
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        self.check_udp()?;
+        let original = inplace::Message::parse(oscore_msg)?;
+        // Recomposing the Proxy-Uri needs to allocate
+        if self.recompose_proxy_uri
+            && (original.option(CoapOption::ProxyUri.into())?.is_some()
+                || original.option(CoapOption::ProxyScheme.into())?.is_some())
+        {
+            return Err(Error::UnsupportedOption(CoapOption::ProxyUri));
+        }
+        let request_option = OscoreOptionRef::decode(
+            original
+                .option(inplace::OSCORE)?
+                .ok_or(Error::NoOscoreOption)?,
+        )?;
+        let (request_kid, request_piv) = (
+            request_option.kid.ok_or(Error::NoKidPiv)?,
+            request_option.piv.ok_or(Error::NoKidPiv)?,
         );
-        assert_eq!(0, security_context.sender_context.sender_sequence_number);
+        if request_option.kid_context.is_some()
+            && request_option.kid_context
+                != self.common_context.id_context.as_deref()
+        {
+            return Err(Error::IdContextMismatch);
+        }
 
-        assert_eq!(
-            &SERVER_ID,
-            &security_context.recipient_context.recipient_id[..]
-        );
-        assert_eq!(
-            &SERVER_KEY,
-            &security_context.recipient_context.recipient_key[..]
+        let sequence_number = util::piv_to_u64(request_piv);
+        if self.recipient_context.window_state == WindowState::Valid {
+            self.recipient_context
+                .replay_window
+                .check(sequence_number)?;
+        }
+
+        let mut aad = [0; inplace::MAX_AAD_LEN];
+        let aad_len = inplace::build_aad(
+            self.aead().id(),
+            request_kid,
+            request_piv,
+            &mut aad,
+        )?;
+        let mut nonce = [0; inplace::MAX_NONCE_LEN];
+        let nonce = &mut nonce[..self.common_context.common_iv.len()];
+        util::write_nonce(
+            request_piv,
+            &self.recipient_context.recipient_id,
+            &self.common_context.common_iv,
+            nonce,
         );
-        assert_eq!(None, security_context.recipient_context.replay_window);
-    }
+
+        let len = self.unprotect_message_into(
+            oscore_msg,
+            &aad[..aad_len],
+            nonce,
+            buf,
+        )?;
+
+        // Only remember the partial IV once the message has been verified
+        match &self.recipient_context.window_state {
+            WindowState::Valid => {
+                self.recipient_context.replay_window.update(sequence_number);
+            }
+            WindowState::Challenged(challenge)
+                if inplace::Message::parse(&buf[..len])?
+                    .option(echo::ECHO)?
+                    == Some(&challenge[..]) =>
+            {
+                self.recipient_context
+                    .replay_window
+                    .initialize(sequence_number);
+                self.recipient_context.window_state = WindowState::Valid;
+            }
+            _ => return Err(Error::EchoRequired),
+        }
+
+        Ok(len)
+    }
+
+    /// Unprotects the OSCORE response into the buffer without allocating,
+    /// returning the length of the CoAP response.
+    ///
+    /// This is the same as `unprotect_response`, with the requirements on
+    /// the buffer of `unprotect_request_into`.
+    ///
+    /// # Arguments
+    /// * `oscore_msg` - The OSCORE message protecting the CoAP response.
+    /// * `request` - The binding of the request the response belongs to.
+    /// * `buf` - The buffer for the CoAP response.
+    pub fn unprotect_response_into(
+        &mut self,
+        oscore_msg: &[u8],
+        request: &mut RequestBinding,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        self.check_udp()?;
+        let original = inplace::Message::parse(oscore_msg)?;
+        request.check_token(&original.header[4..])?;
+        request.check_answered()?;
+        let request_kid = request.get_request_kid();
+        let request_piv = request.get_request_piv();
+
+        let response_option = OscoreOptionRef::decode(
+            original
+                .option(inplace::OSCORE)?
+                .ok_or(Error::NoOscoreOption)?,
+        )?;
+        let (kid, piv) = match response_option.piv {
+            Some(piv) => (&self.recipient_context.recipient_id[..], piv),
+            None => (request_kid, request_piv),
+        };
+
+        let mut aad = [0; inplace::MAX_AAD_LEN];
+        let aad_len = inplace::build_aad(
+            self.aead().id(),
+            request_kid,
+            request_piv,
+            &mut aad,
+        )?;
+        let mut nonce = [0; inplace::MAX_NONCE_LEN];
+        let nonce = &mut nonce[..self.common_context.common_iv.len()];
+        util::write_nonce(piv, kid, &self.common_context.common_iv, nonce);
+
+        let len = self.unprotect_message_into(
+            oscore_msg,
+            &aad[..aad_len],
+            nonce,
+            buf,
+        )?;
+        request.set_answered();
+
+        Ok(len)
+    }
+
+    /// Writes the protected OSCORE message for the given parameters into the
+    /// buffer, returning its length.
+    ///
+    /// # Arguments
+    /// * `coap_msg` - The original CoAP message to protect.
+    /// * `aad` - The AAD for the AEAD.
+    /// * `nonce` - The AEAD nonce to use.
+    /// * `option` - The values of the OSCORE option.
+    /// * `buf` - The buffer for the OSCORE message.
+    fn protect_message_into(
+        &self,
+        coap_msg: &[u8],
+        aad: &[u8],
+        nonce: &[u8],
+        option: OscoreOptionRef,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let original = inplace::Message::parse(coap_msg)?;
+        let code = original.header[1];
+        let mut writer = inplace::Writer::new(buf, 0);
+
+        // The header with the outer code, followed by the token
+        writer.push_byte(original.header[0])?;
+        let observe = usize::from(CoapOption::Observe);
+        let outer_code = match MessageClass::from(code) {
+            // All responses get Changed
+            MessageClass::Response(_) => {
+                MessageClass::Response(ResponseType::Changed)
+            }
+            // All requests (and unknown + reserved) get POST, or FETCH if
+            // Observe stays outside
+            _ => MessageClass::Request(RequestType::Post),
+        };
+        let is_fetch = !matches!(outer_code, MessageClass::Response(_))
+            && original
+                .options()
+                .any(|outer| matches!(outer, Ok((n, _)) if n == observe));
+        writer.push_byte(match is_fetch {
+            true => transport::FETCH,
+            false => outer_code.into(),
+        })?;
+        writer.push(&original.header[2..])?;
+
+        // The outer options, with the OSCORE option and the outer Max-Age
+        // in their places
+        let max_age = usize::from(CoapOption::MaxAge);
+        let mut option_written = false;
+        let mut max_age_written = !(self.outer_max_age
+            && matches!(outer_code, MessageClass::Response(_)));
+        for outer in original.options() {
+            let (number, value) = outer?;
+            let coap_option = CoapOption::from(number);
+            if coap_option == CoapOption::ProxyUri
+                || coap_option == CoapOption::ProxyScheme
+            {
+                return Err(Error::UnsupportedOption(coap_option));
+            }
+            if !option_written && number > inplace::OSCORE {
+                inplace::write_oscore_option(&mut writer, &option)?;
+                option_written = true;
+            }
+            if !max_age_written && number > max_age {
+                writer.option(max_age, &[])?;
+                max_age_written = true;
+            }
+            if self.option_classes.is_outer(number) {
+                writer.option(number, value)?;
+            }
+        }
+        if !option_written {
+            inplace::write_oscore_option(&mut writer, &option)?;
+        }
+        if !max_age_written {
+            writer.option(max_age, &[])?;
+        }
+        writer.push_byte(0xFF)?;
+
+        // The plaintext, consisting of the code, class E options and payload
+        let plaintext_start = writer.position();
+        writer.push_byte(code)?;
+        writer.restart_options();
+        for inner in original.options() {
+            let (number, value) = inner?;
+            if self.option_classes.is_inner(number) {
+                writer.option(number, value)?;
+            }
+        }
+        if !original.payload.is_empty() {
+            writer.push_byte(0xFF)?;
+            writer.push(original.payload)?;
+        }
+        // Leave room for the tag
+        writer.reserve(self.aead().tag_len())?;
+        let end = writer.position();
+
+        self.aead().encrypt_in_place(
+            &self.sender_context.sender_key,
+            nonce,
+            aad,
+            &mut buf[plaintext_start..end],
+        )?;
+
+        Ok(end)
+    }
+
+    /// Writes the original CoAP message protected in the OSCORE message into
+    /// the buffer, returning its length.
+    ///
+    /// The ciphertext is decrypted at the end of the buffer, from where the
+    /// plaintext is moved forward into place.
+    ///
+    /// # Arguments
+    /// * `oscore_msg` - The OSCORE message protecting the CoAP message.
+    /// * `aad` - The AAD for the AEAD.
+    /// * `nonce` - The AEAD nonce to use.
+    /// * `buf` - The buffer for the CoAP message.
+    fn unprotect_message_into(
+        &self,
+        oscore_msg: &[u8],
+        aad: &[u8],
+        nonce: &[u8],
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let original = inplace::Message::parse(oscore_msg)?;
+        // The plaintext has at least the code
+        if original.payload.len() <= self.aead().tag_len() {
+            return Err(Error::Aead);
+        }
+
+        // Everything that's written before the inner options is at most the
+        // header and the class U options, so the plaintext won't be
+        // overwritten before it's been moved if it comes after that
+        let mut prefix_len = original.header.len();
+        for outer in original.options() {
+            let (number, value) = outer?;
+            if !self.option_classes.is_inner(number) {
+                prefix_len += inplace::option_header_len(number, value.len())
+                    + value.len();
+            }
+        }
+        if buf.len() < prefix_len + original.payload.len() {
+            return Err(Error::BufferTooSmall);
+        }
+        let ciphertext_start = buf.len() - original.payload.len();
+        buf[ciphertext_start..].copy_from_slice(original.payload);
+        let plaintext_end = ciphertext_start
+            + self.aead().decrypt_in_place(
+                &self.recipient_context.recipient_key,
+                nonce,
+                aad,
+                &mut buf[ciphertext_start..],
+            )?;
+
+        // The header with the inner code, followed by the token
+        buf[..original.header.len()].copy_from_slice(original.header);
+        buf[1] = buf[ciphertext_start];
+        let mut writer = inplace::Writer::new(buf, original.header.len());
+
+        // Merge the class U options with the inner ones, which replace outer
+        // options with the same number
+        let mut outer_options =
+            original.options().filter(|outer| match outer {
+                Ok((number, _)) => !self.option_classes.is_inner(*number),
+                Err(_) => true,
+            });
+        let mut outer = outer_options.next().transpose()?;
+        let mut position = ciphertext_start + 1;
+        let mut number = 0;
+        loop {
+            let inner = inplace::read_option(
+                &writer.buffer()[..plaintext_end],
+                position,
+                number,
+            )?;
+            match (outer, inner) {
+                (Some((o, value)), Some((i, _, _))) if o < i => {
+                    writer.option(o, value)?;
+                    outer = outer_options.next().transpose()?;
+                }
+                (Some((o, value)), None) => {
+                    writer.option(o, value)?;
+                    outer = outer_options.next().transpose()?;
+                }
+                (Some((o, _)), Some((i, _, _))) if o == i => {
+                    outer = outer_options.next().transpose()?;
+                }
+                (_, Some((i, start, end))) => {
+                    writer.option_within(i, start, end)?;
+                    number = i;
+                    position = end;
+                }
+                (None, None) => break,
+            }
+        }
+
+        // Move the payload, if there is one
+        if position + 1 < plaintext_end {
+            writer.push_byte(0xFF)?;
+            writer.copy_within(position + 1, plaintext_end)?;
+        }
+
+        Ok(writer.position())
+    }
+
+    /// Returns the original CoAP message protected in the OSCORE message.
+    /// # Arguments
+    /// * `original` - The OSCORE message protecting the CoAP message.
+    /// * `aad` - The AAD for the AEAD.
+    /// * `nonce` - The AEAD nonce to use.
+    fn unprotect_message(
+        &mut self,
+        original: Packet,
+        aad: &[u8],
+        nonce: Vec<u8>,
+    ) -> Result<Packet> {
+        decrypt_message(
+            original,
+            self.aead(),
+            &self.recipient_context.recipient_key,
+            aad,
+            &nonce,
+            &self.option_classes,
+        )
+    }
+
+    /// Returns the context resulting from a key update with these values.
+    ///
+    /// The new master secret is expanded from the current one, bound to `x`
+    /// and the nonce, which becomes the new master salt. Everything else
+    /// stays the same, except that the sequence number and replay window
+    /// start over.
+    ///
+    /// # Arguments
+    /// * `x` - The `x` values of the key update, concatenated.
+    /// * `nonce` - The nonces of the key update, concatenated.
+    pub(crate) fn update(
+        &self,
+        x: &[u8],
+        nonce: &[u8],
+    ) -> Result<SecurityContext> {
+        let algorithms = self.common_context.algorithms;
+        // KUDOS-Expand-Label, the new master secret has the key length
+        let l = algorithms.aead.key_len();
+        let master_secret = util::hkdf_expand(
+            algorithms.hkdf,
+            &self.common_context.master_secret,
+            &util::build_update_info(x, nonce, l)?,
+            l,
+        )?;
+        let mut context = SecurityContext::with_algorithms(
+            master_secret,
+            nonce.to_vec(),
+            self.common_context.id_context.clone(),
+            self.sender_context.sender_id.clone(),
+            self.recipient_context.recipient_id.clone(),
+            algorithms,
+        )?;
+        // Keep the configuration that isn't part of the key material
+        context.set_replay_window_size(
+            self.recipient_context.replay_window.state().0,
+        )?;
+        context.option_classes = self.option_classes.clone();
+        context.outer_max_age = self.outer_max_age;
+        context.recompose_proxy_uri = self.recompose_proxy_uri;
+        context.transport = self.transport;
+
+        Ok(context)
+    }
+
+    /// Returns the intermediate context of a key update, which continues the
+    /// sender sequence number and replay window of this one.
+    ///
+    /// This lets the server detect a replay of the request carrying the
+    /// update with its replay window.
+    ///
+    /// # Arguments
+    /// * `x` - The `x` value of the key update.
+    /// * `nonce` - The nonce of the key update.
+    pub(crate) fn update_intermediate(
+        &self,
+        x: &[u8],
+        nonce: &[u8],
+    ) -> Result<SecurityContext> {
+        let mut intermediate = self.update(x, nonce)?;
+        intermediate.sender_context.sender_sequence_number =
+            self.sender_context.sender_sequence_number;
+        intermediate.recipient_context.replay_window =
+            self.recipient_context.replay_window.clone();
+        intermediate.recipient_context.window_state =
+            self.recipient_context.window_state.clone();
+
+        Ok(intermediate)
+    }
+
+    /// Uses up the current sender sequence number, after the intermediate
+    /// context of a key update has taken it.
+    pub(crate) fn skip_sequence_number(&mut self) -> Result<()> {
+        self.increment_sequence_number()
+    }
+
+    /// Takes over the replay window of the intermediate context, after it
+    /// has unprotected the request carrying a key update.
+    ///
+    /// # Arguments
+    /// * `intermediate` - The intermediate context of the key update.
+    pub(crate) fn adopt_replay_window(
+        &mut self,
+        intermediate: &SecurityContext,
+    ) {
+        self.recipient_context.replay_window =
+            intermediate.recipient_context.replay_window.clone();
+        self.recipient_context.window_state =
+            intermediate.recipient_context.window_state.clone();
+    }
+
+    /// Returns the AEAD algorithm used by this context.
+    fn aead(&self) -> AeadAlgorithm {
+        self.common_context.algorithms.aead
+    }
+
+    /// Returns the CoAP message framed for the transport as a `Packet`.
+    fn decode(&self, msg: &[u8]) -> Result<Packet> {
+        transport::decode(msg, self.transport)
+    }
+
+    /// Returns the `Packet` framed for the transport.
+    fn encode(&self, packet: &Packet) -> Result<Vec<u8>> {
+        transport::encode(packet, self.transport)
+    }
+
+    /// Throws an error if the messages aren't framed for UDP, which the
+    /// in-place methods rely on.
+    fn check_udp(&self) -> Result<()> {
+        if self.transport == Transport::Udp {
+            Ok(())
+        } else {
+            Err(Error::UnsupportedTransport)
+        }
+    }
+
+    /// Returns the ID Context, if any.
+    pub fn get_id_context(&self) -> Option<&[u8]> {
+        self.common_context.id_context.as_deref()
+    }
+
+    /// Returns the recipient ID.
+    pub fn get_recipient_id(&self) -> &[u8] {
+        &self.recipient_context.recipient_id
+    }
+
+    /// Returns the transport the messages passed as bytes are framed for.
+    pub fn get_transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// Sets the transport the messages passed as bytes are framed for.
+    ///
+    /// Over the reliable transports of RFC 8323, the header consists of the
+    /// length and token length instead of the type and message ID.
+    /// Signaling messages are only exchanged between the endpoints of a
+    /// connection, so they're refused. Since the transport belongs to the
+    /// connection, it's not part of the exported state. It's UDP by default.
+    /// The in-place methods only support UDP.
+    pub fn set_transport(&mut self, transport: Transport) {
+        self.transport = transport;
+    }
+
+    /// Sets whether No-Response is also added as an outer option.
+    ///
+    /// No-Response is always protected, so the server knows which responses
+    /// the client is interested in. Additionally exposing it to proxies lets
+    /// them know not to expect a response, at the cost of revealing this.
+    /// It's off by default.
+    pub fn set_outer_no_response(&mut self, outer: bool) {
+        self.option_classes.set_outer_no_response(outer);
+    }
+
+    /// Sets whether protected responses get an outer Max-Age of 0.
+    ///
+    /// The inner Max-Age is protected, so intermediaries would otherwise
+    /// cache responses for the default of 60 seconds, although they can't
+    /// be served to anyone but the client (RFC 8613 Section 4.1.3.1). The
+    /// outer value is discarded when unprotecting. It's off by default and
+    /// not part of the exported state.
+    pub fn set_outer_max_age(&mut self, outer: bool) {
+        self.outer_max_age = outer;
+    }
+
+    /// Sets whether unprotected requests for a forward proxy get a single,
+    /// complete Proxy-Uri.
+    ///
+    /// The path and query of a Proxy-Uri are protected separately as
+    /// Uri-Path and Uri-Query, while a client may also have used
+    /// Proxy-Scheme with Uri-Host and Uri-Port. With this, a proxy gets the
+    /// same Proxy-Uri either way (RFC 7252 Section 6.5). It's off by default
+    /// and not supported by `unprotect_request_into`.
+    pub fn set_recompose_proxy_uri(&mut self, recompose: bool) {
+        self.recompose_proxy_uri = recompose;
+    }
+
+    /// Registers the class of an option that isn't defined in a standard.
+    ///
+    /// Unknown options are protected, so this is needed for options that
+    /// proxies have to see. Like the transport, the registered classes
+    /// aren't part of the exported state.
+    ///
+    /// # Arguments
+    /// * `number` - The option number.
+    /// * `class` - The class of the option.
+    pub fn register_option(
+        &mut self,
+        number: usize,
+        class: OptionClass,
+    ) -> Result<()> {
+        self.option_classes.register(number, class)
+    }
+
+    /// Marks the replay window as unknown.
+    ///
+    /// This has to be used for a context whose replay window state may have
+    /// been lost, for example after a reboot. Until a client has answered an
+    /// Echo challenge, its requests are refused with `Error::EchoRequired`
+    /// (RFC 8613 Appendix B.1.2).
+    pub fn invalidate_replay_window(&mut self) {
+        self.recipient_context.window_state = WindowState::Unknown;
+    }
+
+    /// Replaces the replay window with an empty one of the given size.
+    ///
+    /// The size is the number of partial IVs below the highest one received
+    /// that are still accepted, which allows for messages arriving out of
+    /// order. It has to be between 1 and `MAX_WINDOW_SIZE`, the default is
+    /// `DEFAULT_WINDOW_SIZE`.
+    pub fn set_replay_window_size(&mut self, size: usize) -> Result<()> {
+        self.recipient_context.replay_window = ReplayWindow::new(size)?;
+
+        Ok(())
+    }
+
+    /// Makes the sender sequence number persistent.
+    ///
+    /// If the storage contains a value, the context was used before, so the
+    /// sender sequence number is restored by skipping ahead by
+    /// `commit_interval` from there, since the messages after the last
+    /// commit could have been sent. From then on, the sender sequence number
+    /// is committed to the storage every `commit_interval` messages (RFC 8613
+    /// Appendix B.1.1). A larger interval means fewer writes to the storage,
+    /// but more sequence numbers skipped when restoring.
+    ///
+    /// # Arguments
+    /// * `storage` - The persistent storage for the sender sequence number.
+    /// * `commit_interval` - The number of messages between commits (K).
+    pub fn set_sequence_number_storage(
+        &mut self,
+        mut storage: Box<dyn SequenceNumberStorage>,
+        commit_interval: u64,
+    ) -> Result<()> {
+        if commit_interval == 0 {
+            return Err(Error::InvalidCommitInterval);
+        }
+
+        // Skip ahead from the last commit, if there was one
+        if let Some(stored) = storage.load()? {
+            let restored = stored
+                .checked_add(commit_interval)
+                .ok_or(Error::InvalidCommitInterval)?;
+            if restored > self.sender_context.sender_sequence_number {
+                self.sender_context.sender_sequence_number = restored;
+            }
+        }
+        // Commit the number we start from right away, so a restore skips
+        // everything we might use before the next commit
+        let sequence_number = self.sender_context.sender_sequence_number;
+        storage.store(sequence_number)?;
+
+        self.sender_context.stored_sequence_number = sequence_number;
+        self.sender_context.commit_interval = commit_interval;
+        self.sender_context.storage = Some(storage);
+
+        Ok(())
+    }
+
+    /// Registers a callback for when the sender sequence number reaches the
+    /// threshold.
+    ///
+    /// Once `MAX_SEQUENCE_NUMBER` has been used, the context can't protect
+    /// any more messages and fails with `Error::SequenceNumberExhausted`.
+    /// This gives the application a chance to establish a new context
+    /// before that. The callback is called once, with the sequence number
+    /// that reached the threshold.
+    ///
+    /// # Arguments
+    /// * `threshold` - The sequence number at which to call the callback.
+    /// * `callback` - The callback to call.
+    pub fn set_exhaustion_warning(
+        &mut self,
+        threshold: u64,
+        callback: Box<dyn FnMut(u64) + Send>,
+    ) {
+        self.sender_context.warning_threshold = threshold;
+        self.sender_context.warning_callback = Some(callback);
+    }
+
+    /// Increments the sender sequence number after it has been used,
+    /// committing it to the storage if it's due.
+    ///
+    /// This has to be called before the message is returned, so it isn't
+    /// sent if the sequence number couldn't be used.
+    fn increment_sequence_number(&mut self) -> Result<()> {
+        let sequence_number = self.sender_context.sender_sequence_number;
+        // Beyond this, the partial IV wouldn't fit into the nonce anymore
+        if sequence_number > MAX_SEQUENCE_NUMBER {
+            return Err(Error::SequenceNumberExhausted);
+        }
+        if let Some(storage) = &mut self.sender_context.storage {
+            // After K messages since the last commit, a restore wouldn't
+            // skip this one anymore
+            if sequence_number - self.sender_context.stored_sequence_number
+                >= self.sender_context.commit_interval
+            {
+                storage.store(sequence_number)?;
+                self.sender_context.stored_sequence_number = sequence_number;
+            }
+        }
+        self.sender_context.sender_sequence_number += 1;
+
+        // Warn about the upcoming exhaustion only once
+        if sequence_number >= self.sender_context.warning_threshold {
+            if let Some(mut callback) =
+                self.sender_context.warning_callback.take()
+            {
+                callback(sequence_number);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the byte representation of the partial IV.
+    fn get_piv(&self) -> Vec<u8> {
+        util::format_piv(self.sender_context.sender_sequence_number)
+    }
+
+    #[cfg(test)]
+    pub fn set_sender_sequence_number(&mut self, n: u64) {
+        self.sender_context.sender_sequence_number = n;
+    }
+}
+
+/// Returns the `kid` and `kid context` of an OSCORE request.
+///
+/// This allows a server to pick the security context to use for an incoming
+/// request, by comparing them to the recipient ID and ID Context of the
+/// contexts it has.
+///
+/// # Arguments
+/// * `oscore_msg` - The OSCORE message protecting the CoAP request.
+/// * `transport` - The transport the message is framed for.
+pub fn extract_request_ids(
+    oscore_msg: &[u8],
+    transport: Transport,
+) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
+    // Parse the CoAP message
+    let original = transport::decode(oscore_msg, transport)?;
+    // Extract the kid and kid context from the OSCORE option
+    let (kid, _, kid_context) = util::extract_kid_piv_context(&original)?;
+
+    Ok((kid.ok_or(Error::NoKidPiv)?, kid_context))
+}
+
+/// Returns the OSCORE message protecting the CoAP message, before it's
+/// serialized.
+///
+/// # Arguments
+/// * `original` - The original CoAP message to protect.
+/// * `alg_aead` - The AEAD algorithm.
+/// * `key` - The key to encrypt with.
+/// * `aad` - The AAD for the AEAD.
+/// * `nonce` - The AEAD nonce to use.
+/// * `option` - The value of the OSCORE option.
+/// * `classes` - The classes deciding which options are protected.
+pub(super) fn encrypt_message(
+    mut original: Packet,
+    alg_aead: AeadAlgorithm,
+    key: &[u8],
+    aad: &[u8],
+    nonce: &[u8],
+    option: Vec<u8>,
+    classes: &OptionClasses,
+) -> Result<Packet> {
+    // Initialize a new CoAP message to store the protected parts
+    let mut inner = Packet::new();
+
+    // Move the code into the inner message
+    inner.header.code = original.header.code;
+    // Replace the outer code
+    original.header.code = match original.header.code {
+        // All responses get Changed
+        MessageClass::Response(_) => {
+            MessageClass::Response(ResponseType::Changed)
+        }
+        // All requests (and unknown + reserved) get POST, which becomes
+        // FETCH when encoded if Observe stays outside
+        _ => MessageClass::Request(RequestType::Post),
+    };
+
+    // Proxy-Uri handling if it's present, in either form
+    if let Some(proxy_uri) = ProxyUri::from_options(&original)? {
+        // If there's a Uri-Path or Uri-Query, add them to the options and
+        // they will be protected in the next stage
+        if let Some(path_list) = proxy_uri.get_path_list() {
+            original.set_option(CoapOption::UriPath, path_list);
+        }
+        if let Some(query_list) = proxy_uri.get_query_list() {
+            original.set_option(CoapOption::UriQuery, query_list);
+        }
+
+        // Compose the remaining parts into the Proxy-Uri, which will
+        // remain public. The Proxy-Scheme form is folded into it, so
+        // intermediaries see the same options for both forms.
+        original.clear_option(CoapOption::ProxyScheme);
+        original.clear_option(CoapOption::UriHost);
+        original.clear_option(CoapOption::UriPort);
+        let mut uri_list = LinkedList::new();
+        uri_list.push_back(proxy_uri.compose_proxy_uri());
+        original.set_option(CoapOption::ProxyUri, uri_list);
+    }
+
+    // Store which options we remove from the outer message in this
+    let mut moved_options = vec![];
+    // Go over options, moving class E ones into the inner message
+    for (number, value_list) in original.options() {
+        let option = CoapOption::from(*number);
+
+        // Skip class U options
+        if !classes.is_inner(*number) {
+            continue;
+        }
+
+        // At this point the option is class E or undefined, so protect it
+        // Add it to the inner message
+        inner.set_option(option, value_list.clone());
+        // Options like Observe are also needed by intermediaries, so they
+        // stay outside as well
+        if classes.is_outer(*number) {
+            continue;
+        }
+        // Remember it's been moved
+        moved_options.push(option);
+    }
+    // Remove the moved options from the original
+    for option in moved_options {
+        original.clear_option(option);
+    }
+
+    // Move the payload out of the original into the new one
+    inner.payload = original.payload;
+    // Convert the inner message to its byte representation
+    let mut inner_bytes = inner.to_bytes()?;
+    // Remove the message ID and the token (if it exists)
+    let tkl = inner.header.get_token_length();
+    inner_bytes.drain(2..4 + tkl as usize);
+    // Remove the first header byte
+    inner_bytes.remove(0);
+
+    // Encrypt the payload
+    let ciphertext_buf = alg_aead.encrypt(key, nonce, aad, &inner_bytes)?;
+    // Set the ciphertext as the new payload
+    original.payload = ciphertext_buf;
+
+    // Add the OSCORE option
+    original.add_option(CoapOption::Oscore, option);
+
+    Ok(original)
+}
+
+/// Returns the original CoAP message protected in the OSCORE message.
+///
+/// # Arguments
+/// * `original` - The OSCORE message protecting the CoAP message.
+/// * `alg_aead` - The AEAD algorithm.
+/// * `key` - The key to decrypt with.
+/// * `aad` - The AAD for the AEAD.
+/// * `nonce` - The AEAD nonce to use.
+/// * `classes` - The classes deciding which options are protected.
+pub(super) fn decrypt_message(
+    mut original: Packet,
+    alg_aead: AeadAlgorithm,
+    key: &[u8],
+    aad: &[u8],
+    nonce: &[u8],
+    classes: &OptionClasses,
+) -> Result<Packet> {
+    // Store which options we remove from the outer message in this
+    let mut to_discard = vec![];
+    // Go over options, remembering class E ones to discard
+    for (number, _) in original.options() {
+        let option = CoapOption::from(*number);
+
+        // Skip class U options
+        if !classes.is_inner(*number) {
+            continue;
+        }
+
+        // At this point the option is class E or undefined, so discard it
+        to_discard.push(option);
+    }
+    // Discard class E options
+    for option in to_discard {
+        original.clear_option(option);
+    }
+
+    // Decrypt the payload
+    let plaintext_buf =
+        alg_aead.decrypt(key, nonce, aad, &original.payload)?;
+
+    // Build a CoAP message from the bytes of the plaintext, which contain
+    // the code, class E options and the payload
+    // [ver_t_tkl, code, message_id, message_id]
+    let mut inner = vec![0x40, plaintext_buf[0], 0x00, 0x00];
+    inner.extend(&plaintext_buf[1..]);
+    // Parse the CoAP message
+    let inner = Packet::from_bytes(&inner)?;
+    // Set the code from the inner message
+    original.header.code = inner.header.code;
+    // Set the options from the inner message
+    for (number, value_list) in inner.options() {
+        original.set_option((*number).into(), value_list.clone());
+    }
+    // Set the payload from the inner message
+    original.payload = inner.payload;
+
+    Ok(original)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{block, is_response_suppressed, test_vectors::*};
+    use super::*;
+    use alloc::sync::Arc;
+    use std::sync::Mutex;
+
+    #[test]
+    fn context_derivation() {
+        let security_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+
+        assert_eq!(&COMMON_IV, &security_context.common_context.common_iv[..]);
+
+        assert_eq!(&CLIENT_ID, &security_context.sender_context.sender_id[..]);
+        assert_eq!(
+            &CLIENT_KEY,
+            &security_context.sender_context.sender_key[..]
+        );
+        assert_eq!(0, security_context.sender_context.sender_sequence_number);
+
+        assert_eq!(
+            &SERVER_ID,
+            &security_context.recipient_context.recipient_id[..]
+        );
+        assert_eq!(
+            &SERVER_KEY,
+            &security_context.recipient_context.recipient_key[..]
+        );
+        assert_eq!(
+            ReplayWindow::default(),
+            security_context.recipient_context.replay_window
+        );
+    }
+
+    #[test]
+    fn context_derivation_id_context() {
+        let security_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            Some(ID_CONTEXT.to_vec()),
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+
+        assert_eq!(Some(&ID_CONTEXT[..]), security_context.get_id_context());
+        assert_eq!(
+            &COMMON_IV_CTX,
+            &security_context.common_context.common_iv[..]
+        );
+        assert_eq!(
+            &CLIENT_KEY_CTX,
+            &security_context.sender_context.sender_key[..]
+        );
+        assert_eq!(
+            &SERVER_KEY_CTX,
+            &security_context.recipient_context.recipient_key[..]
+        );
+
+        assert_eq!(
+            Error::InvalidIdContext,
+            SecurityContext::new(
+                MASTER_SECRET.to_vec(),
+                MASTER_SALT.to_vec(),
+                Some(vec![0; 256]),
+                CLIENT_ID.to_vec(),
+                SERVER_ID.to_vec(),
+            )
+            .err()
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn context_derivation_sha512() {
+        let security_context = SecurityContext::with_algorithms(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+            Algorithms {
+                hkdf: HkdfAlgorithm::Sha512,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            &COMMON_IV_SHA512,
+            &security_context.common_context.common_iv[..]
+        );
+        assert_eq!(
+            &CLIENT_KEY_SHA512,
+            &security_context.sender_context.sender_key[..]
+        );
+        assert_eq!(
+            &SERVER_KEY_SHA512,
+            &security_context.recipient_context.recipient_key[..]
+        );
+    }
+
+    #[test]
+    fn algorithms() {
+        let all = [
+            AeadAlgorithm::AesCcm16_64_128,
+            AeadAlgorithm::AesCcm16_128_128,
+            AeadAlgorithm::AesCcm16_64_256,
+            AeadAlgorithm::A128Gcm,
+            AeadAlgorithm::A256Gcm,
+            AeadAlgorithm::ChaCha20Poly1305,
+        ];
+        for &aead in all.iter() {
+            let algorithms = Algorithms {
+                aead,
+                ..Default::default()
+            };
+            let mut client_context = SecurityContext::with_algorithms(
+                MASTER_SECRET.to_vec(),
+                MASTER_SALT.to_vec(),
+                None,
+                CLIENT_ID.to_vec(),
+                SERVER_ID.to_vec(),
+                algorithms,
+            )
+            .unwrap();
+            let mut server_context = SecurityContext::with_algorithms(
+                MASTER_SECRET.to_vec(),
+                MASTER_SALT.to_vec(),
+                None,
+                SERVER_ID.to_vec(),
+                CLIENT_ID.to_vec(),
+                algorithms,
+            )
+            .unwrap();
+            // The keys and IV have the lengths the AEAD needs
+            assert_eq!(
+                aead.key_len(),
+                client_context.sender_context.sender_key.len()
+            );
+            assert_eq!(
+                aead.nonce_len(),
+                client_context.common_context.common_iv.len()
+            );
+
+            let (req, mut binding) =
+                client_context.protect_request(&REQ_UNPROTECTED).unwrap();
+            // The ciphertext includes the tag
+            assert_eq!(REQ_PROTECTED.len() - 8 + aead.tag_len(), req.len());
+            assert_eq!(
+                &REQ_UNPROTECTED[..],
+                &server_context.unprotect_request(&req).unwrap()[..]
+            );
+            let res = server_context
+                .protect_response(&RES_UNPROTECTED, &req, true)
+                .unwrap();
+            assert_eq!(
+                &RES_UNPROTECTED[..],
+                &client_context
+                    .unprotect_response(&res, &mut binding)
+                    .unwrap()[..]
+            );
+        }
+
+        // Both sides need to use the same algorithm
+        let mut client_context = SecurityContext::with_algorithms(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+            Algorithms {
+                aead: AeadAlgorithm::AesCcm16_128_128,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut server_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+        let (req, _) =
+            client_context.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert_eq!(
+            Error::Aead,
+            server_context.unprotect_request(&req).unwrap_err()
+        );
+
+        // The IDs have to fit into the nonce, which leaves 7 bytes with
+        // AES-CCM and 6 with the other algorithms
+        let new_context = |sender_id: &[u8], recipient_id: &[u8], aead| {
+            SecurityContext::with_algorithms(
+                MASTER_SECRET.to_vec(),
+                MASTER_SALT.to_vec(),
+                None,
+                sender_id.to_vec(),
+                recipient_id.to_vec(),
+                Algorithms {
+                    aead,
+                    ..Default::default()
+                },
+            )
+        };
+        assert!(
+            new_context(&[0; 7], &[1; 7], AeadAlgorithm::default()).is_ok()
+        );
+        assert_eq!(
+            Some(Error::InvalidId),
+            new_context(&[0; 8], &[1], AeadAlgorithm::default()).err()
+        );
+        assert_eq!(
+            Some(Error::InvalidId),
+            new_context(&[0], &SERVER_ID_LONG, AeadAlgorithm::default()).err()
+        );
+        assert!(new_context(&[0; 6], &[1], AeadAlgorithm::A128Gcm).is_ok());
+        assert_eq!(
+            Some(Error::InvalidId),
+            new_context(&[0], &[1; 7], AeadAlgorithm::A128Gcm).err()
+        );
+    }
+
+    #[test]
+    fn protection() {
+        let mut req_security_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+        req_security_context.set_sender_sequence_number(REQ_SSN);
+        assert_eq!(
+            &REQ_PROTECTED[..],
+            &req_security_context
+                .protect_request(&REQ_UNPROTECTED)
+                .unwrap()
+                .0[..]
+        );
+
+        let mut res_security_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+        assert_eq!(
+            &RES_PROTECTED[..],
+            &res_security_context
+                .protect_response(&RES_UNPROTECTED, &REQ_PROTECTED, true)
+                .unwrap()[..]
+        );
+        // No need to reinitialize the security context, because the previous
+        // protection didn't increment the sender sequence number
+        assert_eq!(
+            &RES_PIV_PROTECTED[..],
+            &res_security_context
+                .protect_response(&RES_UNPROTECTED, &REQ_PROTECTED, false)
+                .unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn unprotection() {
+        let mut req_security_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+        assert_eq!(
+            &REQ_UNPROTECTED[..],
+            &req_security_context
+                .unprotect_request(&REQ_PROTECTED)
+                .unwrap()[..]
+        );
+
+        let mut res_security_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+        let mut binding =
+            RequestBinding::new(&REQ_PROTECTED, Transport::Udp).unwrap();
+        // A forged response doesn't use up the binding
+        let mut forged = RES_PIV_PROTECTED.to_vec();
+        let last = forged.len() - 1;
+        forged[last] ^= 1;
+        assert_eq!(
+            Error::Aead,
+            res_security_context
+                .unprotect_response(&forged, &mut binding)
+                .unwrap_err()
+        );
+        // The two vectors are alternative responses to the same request
+        assert_eq!(
+            &RES_UNPROTECTED[..],
+            &res_security_context
+                .unprotect_response(&RES_PROTECTED, &mut binding.clone())
+                .unwrap()[..]
+        );
+        assert_eq!(
+            &RES_UNPROTECTED[..],
+            &res_security_context
+                .unprotect_response(&RES_PIV_PROTECTED, &mut binding)
+                .unwrap()[..]
+        );
+        // But only one of them is accepted
+        assert_eq!(
+            Error::ReplayDetected,
+            res_security_context
+                .unprotect_response(&RES_PROTECTED, &mut binding)
+                .unwrap_err()
+        );
+    }
+
+    #[test]
+    fn packets() {
+        let mut client_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+        let mut server_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+
+        // The test vectors come out the same without serializing in between
+        client_context.set_sender_sequence_number(REQ_SSN);
+        let (request, mut binding) = client_context
+            .protect_request_packet(
+                Packet::from_bytes(&REQ_UNPROTECTED).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(&REQ_PROTECTED[..], &request.to_bytes().unwrap()[..]);
+        let unprotected = server_context
+            .unprotect_request_packet(request.clone())
+            .unwrap();
+        assert_eq!(&REQ_UNPROTECTED[..], &unprotected.to_bytes().unwrap()[..]);
+
+        let response = server_context
+            .protect_response_packet(
+                Packet::from_bytes(&RES_UNPROTECTED).unwrap(),
+                &request,
+                true,
+            )
+            .unwrap();
+        assert_eq!(&RES_PROTECTED[..], &response.to_bytes().unwrap()[..]);
+        let unprotected = client_context
+            .unprotect_response_packet(response, &mut binding)
+            .unwrap();
+        assert_eq!(&RES_UNPROTECTED[..], &unprotected.to_bytes().unwrap()[..]);
+    }
+
+    #[test]
+    fn request_binding() {
+        let mut client_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+        let mut server_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+
+        // Two requests in flight at the same time, with different tokens
+        let mut other = Packet::from_bytes(&REQ_UNPROTECTED).unwrap();
+        other.set_token(vec![0x01]);
+        let (first, mut first_binding) =
+            client_context.protect_request(&REQ_UNPROTECTED).unwrap();
+        let (second, mut second_binding) = client_context
+            .protect_request(&other.to_bytes().unwrap())
+            .unwrap();
+        server_context.unprotect_request(&first).unwrap();
+        server_context.unprotect_request(&second).unwrap();
+
+        // The responses arrive in the opposite order
+        let mut response = Packet::from_bytes(&RES_UNPROTECTED).unwrap();
+        response.set_token(vec![0x01]);
+        let second_response = server_context
+            .protect_response(&response.to_bytes().unwrap(), &second, true)
+            .unwrap();
+        let first_response = server_context
+            .protect_response(&RES_UNPROTECTED, &first, false)
+            .unwrap();
+        // Mixing them up doesn't work
+        assert_eq!(
+            Error::TokenMismatch,
+            client_context
+                .unprotect_response(&second_response, &mut first_binding)
+                .unwrap_err()
+        );
+        assert_eq!(
+            &response.to_bytes().unwrap()[..],
+            &client_context
+                .unprotect_response(&second_response, &mut second_binding)
+                .unwrap()[..]
+        );
+        assert_eq!(
+            &RES_UNPROTECTED[..],
+            &client_context
+                .unprotect_response(&first_response, &mut first_binding)
+                .unwrap()[..]
+        );
+
+        // Even with the same token, a response only fits its own request
+        let mut first_binding =
+            RequestBinding::new(&first, Transport::Udp).unwrap();
+        let (third, _) =
+            client_context.protect_request(&REQ_UNPROTECTED).unwrap();
+        server_context.unprotect_request(&third).unwrap();
+        let third_response = server_context
+            .protect_response(&RES_UNPROTECTED, &third, true)
+            .unwrap();
+        assert_eq!(
+            Error::Aead,
+            client_context
+                .unprotect_response(&third_response, &mut first_binding)
+                .unwrap_err()
+        );
+    }
+
+    #[test]
+    fn reliable_transports() {
+        for &framing in [Transport::Tcp, Transport::WebSocket].iter() {
+            let mut client_context = SecurityContext::new(
+                MASTER_SECRET.to_vec(),
+                MASTER_SALT.to_vec(),
+                None,
+                CLIENT_ID.to_vec(),
+                SERVER_ID.to_vec(),
+            )
+            .unwrap();
+            client_context.set_transport(framing);
+            let mut server_context = SecurityContext::new(
+                MASTER_SECRET.to_vec(),
+                MASTER_SALT.to_vec(),
+                None,
+                SERVER_ID.to_vec(),
+                CLIENT_ID.to_vec(),
+            )
+            .unwrap();
+            server_context.set_transport(framing);
+            let request = transport::encode(
+                &Packet::from_bytes(&REQ_UNPROTECTED).unwrap(),
+                framing,
+            )
+            .unwrap();
+            let response = transport::encode(
+                &Packet::from_bytes(&RES_UNPROTECTED).unwrap(),
+                framing,
+            )
+            .unwrap();
+
+            // The protected message is the same as over UDP, apart from the
+            // header
+            client_context.set_sender_sequence_number(REQ_SSN);
+            let (req_protected, mut binding) =
+                client_context.protect_request(&request).unwrap();
+            let udp = transport::encode(
+                &transport::decode(&req_protected, framing).unwrap(),
+                Transport::Udp,
+            )
+            .unwrap();
+            assert_eq!(&REQ_PROTECTED[4..], &udp[4..]);
+            assert_eq!(
+                request,
+                server_context.unprotect_request(&req_protected).unwrap()
+            );
+
+            let res_protected = server_context
+                .protect_response(&response, &req_protected, true)
+                .unwrap();
+            assert_eq!(
+                response,
+                client_context
+                    .unprotect_response(&res_protected, &mut binding)
+                    .unwrap()
+            );
+
+            // Signaling messages are refused, here a 7.01 CSM and a 7.02 Ping
+            assert_eq!(
+                Error::Signaling,
+                client_context.protect_request(&[0x00, 0xE1]).unwrap_err()
+            );
+            assert_eq!(
+                Error::Signaling,
+                server_context.unprotect_request(&[0x00, 0xE2]).unwrap_err()
+            );
+            // The in-place methods only know UDP
+            assert_eq!(
+                Error::UnsupportedTransport,
+                client_context
+                    .protect_request_into(&request, &mut [0; 64])
+                    .unwrap_err()
+            );
+        }
+    }
+
+    #[test]
+    fn id_context() {
+        let mut client_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            Some(ID_CONTEXT.to_vec()),
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+        client_context.set_sender_sequence_number(REQ_SSN);
+        let (req_protected, mut binding) =
+            client_context.protect_request(&REQ_UNPROTECTED).unwrap();
+
+        // The kid context is transported in the OSCORE option
+        let packet = Packet::from_bytes(&req_protected).unwrap();
+        assert_eq!(
+            &REQ_CTX_OPTION[..],
+            &packet
+                .get_option(CoapOption::Oscore)
+                .unwrap()
+                .front()
+                .unwrap()[..]
+        );
+        // And a server can extract it to pick the right context
+        assert_eq!(
+            (CLIENT_ID.to_vec(), Some(ID_CONTEXT.to_vec())),
+            extract_request_ids(&req_protected, Transport::Udp).unwrap()
+        );
+
+        // A context with another ID Context refuses it
+        let mut other_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            Some(vec![0x01]),
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+        assert_eq!(
+            Error::IdContextMismatch,
+            other_context.unprotect_request(&req_protected).unwrap_err()
+        );
+
+        // The matching one accepts it
+        let mut server_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            Some(ID_CONTEXT.to_vec()),
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+        assert_eq!(
+            &REQ_UNPROTECTED[..],
+            &server_context.unprotect_request(&req_protected).unwrap()[..]
+        );
+
+        // Responses work as usual
+        let res_protected = server_context
+            .protect_response(&RES_UNPROTECTED, &req_protected, true)
+            .unwrap();
+        assert_eq!(
+            &RES_UNPROTECTED[..],
+            &client_context
+                .unprotect_response(&res_protected, &mut binding)
+                .unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn observe() {
+        let mut client_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+        let mut server_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+
+        // Register the observation
+        let mut registration = Packet::new();
+        registration.header.code = MessageClass::Request(RequestType::Get);
+        registration.set_token(vec![0x4A]);
+        registration.add_option(CoapOption::Observe, vec![]);
+        registration.add_option(CoapOption::UriPath, b"temp".to_vec());
+        let registration = registration.to_bytes().unwrap();
+        let (req_protected, _) =
+            client_context.protect_request(&registration).unwrap();
+        // Observe is both an inner and an outer option
+        let req_coap = Packet::from_bytes(&req_protected).unwrap();
+        assert!(req_coap.get_option(CoapOption::Observe).is_some());
+        assert!(req_coap.get_option(CoapOption::UriPath).is_none());
+        // So proxies can relay it, the outer code is FETCH
+        assert_eq!(transport::FETCH, req_protected[1]);
+        let mut in_place_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+        let mut buf = [0; 64];
+        let len = in_place_context
+            .protect_request_into(&registration, &mut buf)
+            .unwrap()
+            .0;
+        assert_eq!(transport::FETCH, buf[1]);
+        assert_eq!(&req_protected[..], &buf[..len]);
+        assert_eq!(
+            &registration[..],
+            &server_context.unprotect_request(&req_protected).unwrap()[..]
+        );
+
+        // Send a few notifications
+        let notifications: Vec<(Vec<u8>, Vec<u8>)> = (1..=3)
+            .map(|n| {
+                let mut notification = Packet::new();
+                notification.header.code =
+                    MessageClass::Response(ResponseType::Content);
+                notification.set_token(vec![0x4A]);
+                notification.add_option(CoapOption::Observe, vec![n]);
+                notification.payload = vec![20 + n];
+                let notification = notification.to_bytes().unwrap();
+                // Even when asked to reuse the piv, there is a fresh one
+                let protected = server_context
+                    .protect_response(&notification, &req_protected, true)
+                    .unwrap();
+                (notification, protected)
+            })
+            .collect();
+        let option = Packet::from_bytes(&notifications[0].1)
+            .unwrap()
+            .get_option(CoapOption::Oscore)
+            .unwrap()
+            .front()
+            .unwrap()
+            .clone();
+        assert_eq!(&[0x01, 0x00][..], &option[..]);
+
+        // Unprotect them with the observation
+        let mut observation =
+            Observation::new(&req_protected, Transport::Udp).unwrap();
+        assert_eq!(
+            &notifications[0].0[..],
+            &client_context
+                .unprotect_notification(&mut observation, &notifications[0].1)
+                .unwrap()[..]
+        );
+        assert_eq!(
+            &notifications[2].0[..],
+            &client_context
+                .unprotect_notification(&mut observation, &notifications[2].1)
+                .unwrap()[..]
+        );
+        // Older notifications are refused
+        assert_eq!(
+            Error::ReplayDetected,
+            client_context
+                .unprotect_notification(&mut observation, &notifications[1].1)
+                .unwrap_err()
+        );
+        // Same for duplicates
+        assert_eq!(
+            Error::ReplayDetected,
+            client_context
+                .unprotect_notification(&mut observation, &notifications[2].1)
+                .unwrap_err()
+        );
+    }
+
+    #[test]
+    fn block_wise() {
+        let mut client_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+        let mut server_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+
+        // Inner block options are protected like any class E option
+        let mut request = Packet::new();
+        request.header.code = MessageClass::Request(RequestType::Put);
+        request.add_option(CoapOption::UriPath, b"firmware".to_vec());
+        request.add_option(CoapOption::Block1, vec![0x0E]);
+        request.add_option(CoapOption::Size1, vec![0x10, 0x00]);
+        request.payload = vec![0xAB; 1024];
+        let request = request.to_bytes().unwrap();
+        let (req_protected, _) =
+            client_context.protect_request(&request).unwrap();
+        let req_coap = Packet::from_bytes(&req_protected).unwrap();
+        assert!(req_coap.get_option(CoapOption::Block1).is_none());
+        assert!(req_coap.get_option(CoapOption::Size1).is_none());
+
+        // The protected message can additionally be split into outer blocks
+        let blocks =
+            block::fragment(&req_protected, 4, Transport::Udp).unwrap();
+        assert_eq!(5, blocks.len());
+        let mut reassembler = block::Reassembler::new(2048, Transport::Udp);
+        let mut reassembled = None;
+        for block in &blocks {
+            reassembled = reassembler.add_block(block).unwrap();
+        }
+        assert_eq!(
+            &request[..],
+            &server_context
+                .unprotect_request(&reassembled.unwrap())
+                .unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn no_response() {
+        let mut client_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+        let mut server_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+
+        let mut request = Packet::new();
+        request.header.code = MessageClass::Request(RequestType::Post);
+        request.add_option(CoapOption::UriPath, b"telemetry".to_vec());
+        request.add_option(CoapOption::NoResponse, vec![2 | 8 | 16]);
+        let request = request.to_bytes().unwrap();
+
+        // By default, it's only an inner option
+        let (req_protected, _) =
+            client_context.protect_request(&request).unwrap();
+        assert!(Packet::from_bytes(&req_protected)
+            .unwrap()
+            .get_option(CoapOption::NoResponse)
+            .is_none());
+        let req_unprotected =
+            server_context.unprotect_request(&req_protected).unwrap();
+        assert_eq!(&request[..], &req_unprotected[..]);
+        assert!(is_response_suppressed(
+            &req_unprotected,
+            MessageClass::Response(ResponseType::Changed),
+            Transport::Udp,
+        )
+        .unwrap());
+
+        // But it can be exposed to proxies as well
+        client_context.set_outer_no_response(true);
+        let (req_protected, _) =
+            client_context.protect_request(&request).unwrap();
+        assert_eq!(
+            &[2 | 8 | 16][..],
+            &Packet::from_bytes(&req_protected)
+                .unwrap()
+                .get_option(CoapOption::NoResponse)
+                .unwrap()
+                .front()
+                .unwrap()[..]
+        );
+        assert_eq!(
+            &request[..],
+            &server_context.unprotect_request(&req_protected).unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn echo_challenge() {
+        let mut client_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+        let mut server_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+        // Pretend the server has rebooted
+        server_context.invalidate_replay_window();
+
+        // The first request is refused
+        let (req_protected, mut binding) =
+            client_context.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert_eq!(
+            Error::EchoRequired,
+            server_context
+                .unprotect_request(&req_protected)
+                .unwrap_err()
+        );
+        let echo_value = [0xEC; 8];
+        let res_protected = server_context
+            .protect_echo_challenge(&req_protected, &echo_value, 0x1234)
+            .unwrap();
+
+        // The client gets the challenge
+        let res_unprotected = client_context
+            .unprotect_response(&res_protected, &mut binding)
+            .unwrap();
+        assert_eq!(
+            MessageClass::Response(ResponseType::Unauthorized),
+            Packet::from_bytes(&res_unprotected).unwrap().header.code
+        );
+        let echo_received =
+            echo::extract_echo(&res_unprotected, Transport::Udp).unwrap();
+        assert_eq!(Some(echo_value.to_vec()), echo_received);
+
+        // A request with the wrong Echo value is still refused
+        let wrong =
+            echo::add_echo(&REQ_UNPROTECTED, &[0x01], Transport::Udp).unwrap();
+        let (wrong_protected, _) =
+            client_context.protect_request(&wrong).unwrap();
+        assert_eq!(
+            Error::EchoRequired,
+            server_context
+                .unprotect_request(&wrong_protected)
+                .unwrap_err()
+        );
+
+        // But the right one is accepted
+        let retry = echo::add_echo(
+            &REQ_UNPROTECTED,
+            &echo_received.unwrap(),
+            Transport::Udp,
+        )
+        .unwrap();
+        let (retry_protected, _) =
+            client_context.protect_request(&retry).unwrap();
+        assert_eq!(
+            &retry[..],
+            &server_context.unprotect_request(&retry_protected).unwrap()[..]
+        );
+
+        // And now the replay window protects against older requests
+        assert_eq!(
+            Error::ReplayDetected,
+            server_context
+                .unprotect_request(&req_protected)
+                .unwrap_err()
+        );
+        assert_eq!(
+            Error::ReplayDetected,
+            server_context
+                .unprotect_request(&wrong_protected)
+                .unwrap_err()
+        );
+        let (req_protected, _) =
+            client_context.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert!(server_context.unprotect_request(&req_protected).is_ok());
+    }
+
+    #[test]
+    fn send() {
+        // Contexts can be moved to other threads
+        fn assert_send<T: Send>() {}
+        assert_send::<SecurityContext>();
+    }
+
+    /// Simulates persistent memory, which survives the context.
+    struct TestStorage {
+        memory: Arc<Mutex<Option<u64>>>,
+        writes: Arc<Mutex<Vec<u64>>>,
+        fail: bool,
+    }
+
+    impl SequenceNumberStorage for TestStorage {
+        fn load(&mut self) -> Result<Option<u64>> {
+            Ok(*self.memory.lock().unwrap())
+        }
+
+        fn store(&mut self, sequence_number: u64) -> Result<()> {
+            if self.fail {
+                return Err(Error::Storage);
+            }
+            *self.memory.lock().unwrap() = Some(sequence_number);
+            self.writes.lock().unwrap().push(sequence_number);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn sequence_number_storage() {
+        let memory = Arc::new(Mutex::new(None));
+        let writes = Arc::new(Mutex::new(vec![]));
+        let mut server_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+
+        // Start with empty storage and send some messages
+        let mut client_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+        let storage = TestStorage {
+            memory: memory.clone(),
+            writes: writes.clone(),
+            fail: false,
+        };
+        client_context
+            .set_sequence_number_storage(Box::new(storage), 10)
+            .unwrap();
+        for _ in 0..25 {
+            let (req, _) =
+                client_context.protect_request(&REQ_UNPROTECTED).unwrap();
+            assert!(server_context.unprotect_request(&req).is_ok());
+        }
+        // Only every 10th sequence number was written
+        assert_eq!(vec![0, 10, 20], *writes.lock().unwrap());
+
+        // Crash and restore, which skips ahead
+        for (restored, last_write) in &[(30, 30), (40, 40)] {
+            let mut client_context = SecurityContext::new(
+                MASTER_SECRET.to_vec(),
+                MASTER_SALT.to_vec(),
+                None,
+                CLIENT_ID.to_vec(),
+                SERVER_ID.to_vec(),
+            )
+            .unwrap();
+            let storage = TestStorage {
+                memory: memory.clone(),
+                writes: writes.clone(),
+                fail: false,
+            };
+            client_context
+                .set_sequence_number_storage(Box::new(storage), 10)
+                .unwrap();
+            assert_eq!(
+                *restored,
+                client_context.sender_context.sender_sequence_number
+            );
+            assert_eq!(Some(last_write), writes.lock().unwrap().last());
+            // The nonces are fresh, so the server accepts the requests
+            for _ in 0..5 {
+                let (req, _) =
+                    client_context.protect_request(&REQ_UNPROTECTED).unwrap();
+                assert!(server_context.unprotect_request(&req).is_ok());
+            }
+        }
+    }
 
     #[test]
-    fn protection() {
-        let mut req_security_context = SecurityContext::new(
+    fn sequence_number_storage_failure() {
+        let mut client_context = SecurityContext::new(
             MASTER_SECRET.to_vec(),
             MASTER_SALT.to_vec(),
+            None,
             CLIENT_ID.to_vec(),
             SERVER_ID.to_vec(),
         )
         .unwrap();
-        req_security_context.set_sender_sequence_number(REQ_SSN);
+        let storage = TestStorage {
+            memory: Arc::new(Mutex::new(Some(5))),
+            writes: Arc::new(Mutex::new(vec![])),
+            fail: true,
+        };
         assert_eq!(
-            &REQ_PROTECTED[..],
-            &req_security_context
-                .protect_request(&REQ_UNPROTECTED)
-                .unwrap()[..]
+            Error::InvalidCommitInterval,
+            client_context
+                .set_sequence_number_storage(Box::new(storage), 0)
+                .unwrap_err()
         );
 
-        let mut res_security_context = SecurityContext::new(
+        let storage = TestStorage {
+            memory: Arc::new(Mutex::new(Some(5))),
+            writes: Arc::new(Mutex::new(vec![])),
+            fail: true,
+        };
+        assert_eq!(
+            Error::Storage,
+            client_context
+                .set_sequence_number_storage(Box::new(storage), 1)
+                .unwrap_err()
+        );
+    }
+
+    #[test]
+    fn sequence_number_exhaustion() {
+        let mut client_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+        let mut server_context = SecurityContext::new(
             MASTER_SECRET.to_vec(),
             MASTER_SALT.to_vec(),
+            None,
             SERVER_ID.to_vec(),
             CLIENT_ID.to_vec(),
         )
         .unwrap();
+        let warnings = Arc::new(Mutex::new(vec![]));
+        let warnings_callback = warnings.clone();
+        client_context.set_exhaustion_warning(
+            MAX_SEQUENCE_NUMBER - 1,
+            Box::new(move |n| warnings_callback.lock().unwrap().push(n)),
+        );
+
+        client_context.set_sender_sequence_number(MAX_SEQUENCE_NUMBER - 2);
+        let (req, _) =
+            client_context.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert!(server_context.unprotect_request(&req).is_ok());
+        assert!(warnings.lock().unwrap().is_empty());
+        // Reaching the threshold triggers the warning
+        let (req, _) =
+            client_context.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert!(server_context.unprotect_request(&req).is_ok());
+        assert_eq!(vec![MAX_SEQUENCE_NUMBER - 1], *warnings.lock().unwrap());
+        // The last one still works, and the warning isn't repeated
+        let (req, mut binding) =
+            client_context.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert!(server_context.unprotect_request(&req).is_ok());
+        assert_eq!(1, warnings.lock().unwrap().len());
+        let res = server_context
+            .protect_response(&RES_UNPROTECTED, &req, false)
+            .unwrap();
+        assert!(client_context
+            .unprotect_response(&res, &mut binding)
+            .is_ok());
+
+        // After that, both sides are exhausted
         assert_eq!(
-            &RES_PROTECTED[..],
-            &res_security_context
-                .protect_response(&RES_UNPROTECTED, &REQ_PROTECTED, true)
-                .unwrap()[..]
+            Error::SequenceNumberExhausted,
+            client_context
+                .protect_request(&REQ_UNPROTECTED)
+                .unwrap_err()
         );
-        // No need to reinitialize the security context, because the previous
-        // protection didn't increment the sender sequence number
+        server_context.set_sender_sequence_number(MAX_SEQUENCE_NUMBER + 1);
         assert_eq!(
-            &RES_PIV_PROTECTED[..],
-            &res_security_context
-                .protect_response(&RES_UNPROTECTED, &REQ_PROTECTED, false)
-                .unwrap()[..]
+            Error::SequenceNumberExhausted,
+            server_context
+                .protect_response(&RES_UNPROTECTED, &req, false)
+                .unwrap_err()
         );
+        // Reusing the request's piv still works
+        assert!(server_context
+            .protect_response(&RES_UNPROTECTED, &req, true)
+            .is_ok());
     }
 
     #[test]
-    fn unprotection() {
-        let mut req_security_context = SecurityContext::new(
+    fn export_restore() {
+        let mut client_context = SecurityContext::new(
             MASTER_SECRET.to_vec(),
             MASTER_SALT.to_vec(),
+            Some(ID_CONTEXT.to_vec()),
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+        let mut server_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            Some(ID_CONTEXT.to_vec()),
             SERVER_ID.to_vec(),
             CLIENT_ID.to_vec(),
         )
         .unwrap();
+        server_context.set_replay_window_size(128).unwrap();
+        server_context.set_outer_no_response(true);
+
+        // Use them a bit, leaving a gap in the server's replay window
+        let (old_req, _) =
+            client_context.protect_request(&REQ_UNPROTECTED).unwrap();
+        for _ in 0..100 {
+            let (req, _) =
+                client_context.protect_request(&REQ_UNPROTECTED).unwrap();
+            server_context.unprotect_request(&req).unwrap();
+        }
+        let exported = server_context.export().unwrap();
+        let mut restored = SecurityContext::restore(&exported).unwrap();
+
         assert_eq!(
-            &REQ_UNPROTECTED[..],
-            &req_security_context
-                .unprotect_request(&REQ_PROTECTED)
-                .unwrap()[..]
+            &server_context.common_context.common_iv,
+            &restored.common_context.common_iv
+        );
+        assert_eq!(Some(&ID_CONTEXT[..]), restored.get_id_context());
+        assert_eq!(
+            server_context.sender_context.sender_key,
+            restored.sender_context.sender_key
+        );
+        assert_eq!(
+            server_context.recipient_context.replay_window,
+            restored.recipient_context.replay_window
+        );
+        assert!(restored.option_classes.get_outer_no_response());
+        assert_eq!(
+            server_context.common_context.algorithms,
+            restored.common_context.algorithms
         );
 
-        let mut res_security_context = SecurityContext::new(
+        // The replay window was restored too
+        let (req, mut binding) =
+            client_context.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert!(restored.unprotect_request(&old_req).is_ok());
+        assert_eq!(
+            Error::ReplayDetected,
+            restored.unprotect_request(&old_req).unwrap_err()
+        );
+        assert!(restored.unprotect_request(&req).is_ok());
+        // And the sender sequence number continues where it left off
+        let res = restored.protect_response(&RES_UNPROTECTED, &req, false);
+        assert!(client_context
+            .unprotect_response(&res.unwrap(), &mut binding)
+            .is_ok());
+        assert_eq!(
+            server_context.sender_context.sender_sequence_number + 1,
+            restored.sender_context.sender_sequence_number
+        );
+    }
+
+    #[test]
+    fn export_restore_window_state() {
+        let mut server_context = SecurityContext::new(
             MASTER_SECRET.to_vec(),
             MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
             CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+        server_context.invalidate_replay_window();
+        let restored =
+            SecurityContext::restore(&server_context.export().unwrap())
+                .unwrap();
+        assert_eq!(
+            WindowState::Unknown,
+            restored.recipient_context.window_state
+        );
+    }
+
+    #[test]
+    fn restore_invalid() {
+        let server_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
             SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
         )
         .unwrap();
-        res_security_context.set_sender_sequence_number(REQ_SSN + 1);
+        let exported = server_context.export().unwrap();
+
+        // Unknown version
+        let mut other_version = exported.clone();
+        other_version[1] = 2;
         assert_eq!(
-            &RES_UNPROTECTED[..],
-            &res_security_context
-                .unprotect_response(&RES_PROTECTED)
-                .unwrap()[..]
+            Error::InvalidState,
+            SecurityContext::restore(&other_version).err().unwrap()
         );
+        // Truncated
+        assert!(
+            SecurityContext::restore(&exported[..exported.len() - 1]).is_err()
+        );
+        // Wrong key length
+        let (version, mut state) =
+            cbor::decode::<(u8, State)>(&exported).unwrap();
+        state.3 = ByteBuf::from(vec![0; 15]);
+        let wrong_key = cbor::encode((version, state)).unwrap();
         assert_eq!(
-            &RES_UNPROTECTED[..],
-            &res_security_context
-                .unprotect_response(&RES_PIV_PROTECTED)
-                .unwrap()[..]
+            Error::InvalidState,
+            SecurityContext::restore(&wrong_key).err().unwrap()
         );
     }
 
@@ -591,6 +3106,7 @@ mod tests {
         let mut req_ctx = SecurityContext::new(
             MASTER_SECRET.to_vec(),
             MASTER_SALT.to_vec(),
+            None,
             CLIENT_ID.to_vec(),
             SERVER_ID.to_vec(),
         )
@@ -598,6 +3114,7 @@ mod tests {
         let mut res_ctx = SecurityContext::new(
             MASTER_SECRET.to_vec(),
             MASTER_SALT.to_vec(),
+            None,
             SERVER_ID.to_vec(),
             CLIENT_ID.to_vec(),
         )
@@ -612,7 +3129,8 @@ mod tests {
         );
         let protected_bytes = &req_ctx
             .protect_request(&packet.to_bytes().unwrap())
-            .unwrap();
+            .unwrap()
+            .0;
         let protected_coap = Packet::from_bytes(protected_bytes).unwrap();
         // Check the only unprotected options are the OSCORE option and
         // the new Proxy-Uri
@@ -647,11 +3165,153 @@ mod tests {
         );
     }
 
+    #[test]
+    fn proxy_forms() {
+        let mut req_ctx = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+        let mut res_ctx = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+        res_ctx.set_recompose_proxy_uri(true);
+
+        let mut proxy_uri = Packet::new();
+        proxy_uri.add_option(
+            CoapOption::ProxyUri,
+            b"coap://[2001:db8::1]:5683/sensors/temp%20c?unit=c".to_vec(),
+        );
+        let mut proxy_scheme = Packet::new();
+        proxy_scheme.add_option(CoapOption::ProxyScheme, b"coap".to_vec());
+        proxy_scheme
+            .add_option(CoapOption::UriHost, b"[2001:DB8::1]".to_vec());
+        proxy_scheme.add_option(CoapOption::UriPath, b"sensors".to_vec());
+        proxy_scheme.add_option(CoapOption::UriPath, b"temp c".to_vec());
+        proxy_scheme.add_option(CoapOption::UriQuery, b"unit=c".to_vec());
+
+        // The proxy gets the same request either way
+        let mut requests = vec![];
+        let mut outer_options = vec![];
+        for packet in [proxy_uri, proxy_scheme].iter() {
+            let (protected, _) = req_ctx
+                .protect_request(&packet.to_bytes().unwrap())
+                .unwrap();
+            // Intermediaries see the same options apart from the PIV
+            let outer = Packet::from_bytes(&protected).unwrap();
+            outer_options.push(
+                outer
+                    .options()
+                    .filter(|(&number, _)| {
+                        number != usize::from(CoapOption::Oscore)
+                    })
+                    .map(|(&number, values)| (number, values.clone()))
+                    .collect::<Vec<_>>(),
+            );
+            let unprotected = res_ctx.unprotect_request(&protected).unwrap();
+            requests.push(Packet::from_bytes(&unprotected).unwrap());
+        }
+        for request in requests.iter() {
+            assert_eq!(
+                b"coap://[2001:db8::1]/sensors/temp%20c?unit=c"[..],
+                request
+                    .get_option(CoapOption::ProxyUri)
+                    .unwrap()
+                    .front()
+                    .unwrap()[..]
+            );
+            assert!(request.get_option(CoapOption::UriPath).is_none());
+            assert!(request.get_option(CoapOption::UriHost).is_none());
+        }
+        assert_eq!(outer_options[0], outer_options[1]);
+        assert_eq!(
+            vec![(
+                usize::from(CoapOption::ProxyUri),
+                [b"coap://[2001:db8::1]".to_vec()].iter().cloned().collect()
+            )],
+            outer_options[0]
+        );
+
+        // Recomposing isn't done in place
+        let (protected, _) = req_ctx
+            .protect_request(&requests[0].to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(
+            Error::UnsupportedOption(CoapOption::ProxyUri),
+            res_ctx
+                .unprotect_request_into(&protected, &mut [0; 128])
+                .unwrap_err()
+        );
+    }
+
+    #[test]
+    fn option_classes() {
+        let mut req_ctx = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+        let mut res_ctx = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+        // A private option the proxies have to see
+        req_ctx.register_option(65000, OptionClass::Outer).unwrap();
+        res_ctx.register_option(65000, OptionClass::Outer).unwrap();
+
+        let mut packet = Packet::new();
+        // Hop-Limit is class U, Request-Tag class E
+        packet.add_option(CoapOption::Unknown(16), vec![0x10]);
+        packet.add_option(CoapOption::Unknown(292), vec![0x01]);
+        packet.add_option(CoapOption::Unknown(65000), vec![0xAA]);
+        packet.add_option(CoapOption::Unknown(65001), vec![0xBB]);
+        let (protected, _) = req_ctx
+            .protect_request(&packet.to_bytes().unwrap())
+            .unwrap();
+        let protected = Packet::from_bytes(&protected).unwrap();
+        assert!(protected.get_option(CoapOption::Unknown(16)).is_some());
+        assert!(protected.get_option(CoapOption::Unknown(292)).is_none());
+        assert!(protected.get_option(CoapOption::Unknown(65000)).is_some());
+        assert!(protected.get_option(CoapOption::Unknown(65001)).is_none());
+
+        let unprotected = res_ctx
+            .unprotect_request(&protected.to_bytes().unwrap())
+            .unwrap();
+        let unprotected = Packet::from_bytes(&unprotected).unwrap();
+        for &number in [16, 292, 65000, 65001].iter() {
+            assert!(unprotected
+                .get_option(CoapOption::Unknown(number))
+                .is_some());
+        }
+        assert!(unprotected.get_option(CoapOption::Oscore).is_none());
+
+        assert_eq!(
+            Err(Error::UnsupportedOption(CoapOption::Oscore)),
+            req_ctx.register_option(9, OptionClass::Outer)
+        );
+    }
+
     #[test]
     fn replay() {
         let mut req_security_context = SecurityContext::new(
             MASTER_SECRET.to_vec(),
             MASTER_SALT.to_vec(),
+            None,
             SERVER_ID.to_vec(),
             CLIENT_ID.to_vec(),
         )
@@ -660,8 +3320,394 @@ mod tests {
         assert!(req_security_context
             .unprotect_request(&REQ_PROTECTED)
             .is_ok());
-        assert!(req_security_context
-            .unprotect_request(&REQ_PROTECTED)
-            .is_err())
+        assert_eq!(
+            Error::ReplayDetected,
+            req_security_context
+                .unprotect_request(&REQ_PROTECTED)
+                .unwrap_err()
+        );
+    }
+
+    #[test]
+    fn reordering() {
+        let mut client_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+        let mut server_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+        server_context.set_replay_window_size(4).unwrap();
+
+        let requests: Vec<Vec<u8>> = (0..6)
+            .map(|_| {
+                client_context.protect_request(&REQ_UNPROTECTED).unwrap().0
+            })
+            .collect();
+
+        // Newer requests arriving first don't prevent older ones
+        assert!(server_context.unprotect_request(&requests[3]).is_ok());
+        assert!(server_context.unprotect_request(&requests[1]).is_ok());
+        assert!(server_context.unprotect_request(&requests[2]).is_ok());
+        // But duplicates are still detected
+        assert_eq!(
+            Error::ReplayDetected,
+            server_context.unprotect_request(&requests[1]).unwrap_err()
+        );
+        // Moving the window leaves the oldest ones behind
+        assert!(server_context.unprotect_request(&requests[5]).is_ok());
+        assert_eq!(
+            Error::ReplayDetected,
+            server_context.unprotect_request(&requests[0]).unwrap_err()
+        );
+        assert!(server_context.unprotect_request(&requests[4]).is_ok());
+    }
+
+    #[test]
+    fn forgery_not_remembered() {
+        let mut server_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+
+        // Tamper with the ciphertext
+        let mut forged = REQ_PROTECTED;
+        forged[REQ_PROTECTED.len() - 1] ^= 0xFF;
+        assert_eq!(
+            Error::Aead,
+            server_context.unprotect_request(&forged).unwrap_err()
+        );
+        // The genuine request is still accepted afterwards
+        assert!(server_context.unprotect_request(&REQ_PROTECTED).is_ok());
+    }
+
+    #[test]
+    fn replay_window_size() {
+        let mut security_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            Error::InvalidReplayWindow,
+            security_context.set_replay_window_size(0).unwrap_err()
+        );
+        assert!(security_context.set_replay_window_size(64).is_ok());
+        assert_eq!(
+            ReplayWindow::new(64).unwrap(),
+            security_context.recipient_context.replay_window
+        );
+    }
+
+    #[test]
+    fn in_place() {
+        let mut buf = [0; 64];
+        let mut client_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+        let mut server_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+
+        // The test vectors come out the same
+        client_context.set_sender_sequence_number(REQ_SSN);
+        let (len, mut binding) = client_context
+            .protect_request_into(&REQ_UNPROTECTED, &mut buf)
+            .unwrap();
+        assert_eq!(&REQ_PROTECTED[..], &buf[..len]);
+        assert_eq!(
+            RequestBinding::new(&REQ_PROTECTED, Transport::Udp).unwrap(),
+            binding
+        );
+        // The plaintext is decrypted after the header and Uri-Host
+        assert_eq!(
+            Error::BufferTooSmall,
+            server_context
+                .unprotect_request_into(&REQ_PROTECTED, &mut buf[..30])
+                .unwrap_err()
+        );
+        let len = server_context
+            .unprotect_request_into(&REQ_PROTECTED, &mut buf[..31])
+            .unwrap();
+        assert_eq!(&REQ_UNPROTECTED[..], &buf[..len]);
+        assert_eq!(
+            Error::ReplayDetected,
+            server_context
+                .unprotect_request_into(&REQ_PROTECTED, &mut buf)
+                .unwrap_err()
+        );
+        let len = server_context
+            .protect_response_into(
+                &RES_UNPROTECTED,
+                &REQ_PROTECTED,
+                true,
+                &mut buf,
+            )
+            .unwrap();
+        assert_eq!(&RES_PROTECTED[..], &buf[..len]);
+        let len = server_context
+            .protect_response_into(
+                &RES_UNPROTECTED,
+                &REQ_PROTECTED,
+                false,
+                &mut buf,
+            )
+            .unwrap();
+        assert_eq!(&RES_PIV_PROTECTED[..], &buf[..len]);
+        for response in [&RES_PROTECTED[..], &RES_PIV_PROTECTED[..]].iter() {
+            let len = client_context
+                .unprotect_response_into(
+                    response,
+                    &mut binding.clone(),
+                    &mut buf,
+                )
+                .unwrap();
+            assert_eq!(&RES_UNPROTECTED[..], &buf[..len]);
+        }
+        // A request only gets a single response
+        client_context
+            .unprotect_response_into(&RES_PROTECTED, &mut binding, &mut buf)
+            .unwrap();
+        assert_eq!(
+            Error::ReplayDetected,
+            client_context
+                .unprotect_response_into(
+                    &RES_PROTECTED,
+                    &mut binding,
+                    &mut buf
+                )
+                .unwrap_err()
+        );
+        assert_eq!(
+            Error::BufferTooSmall,
+            client_context
+                .protect_request_into(&REQ_UNPROTECTED, &mut buf[..34])
+                .unwrap_err()
+        );
+
+        // Proxy-Uri would need to be decomposed
+        let mut request = Packet::from_bytes(&REQ_UNPROTECTED).unwrap();
+        request.add_option(CoapOption::ProxyUri, b"coap://a/b".to_vec());
+        assert_eq!(
+            Error::UnsupportedOption(CoapOption::ProxyUri),
+            client_context
+                .protect_request_into(&request.to_bytes().unwrap(), &mut buf)
+                .unwrap_err()
+        );
+        // And Proxy-Scheme would need to be composed into one
+        let mut request = Packet::from_bytes(&REQ_UNPROTECTED).unwrap();
+        request.add_option(CoapOption::ProxyScheme, b"coap".to_vec());
+        assert_eq!(
+            Error::UnsupportedOption(CoapOption::ProxyScheme),
+            client_context
+                .protect_request_into(&request.to_bytes().unwrap(), &mut buf)
+                .unwrap_err()
+        );
+    }
+
+    #[test]
+    fn in_place_options() {
+        // A message with options of both classes, some of them repeated
+        let mut request = Packet::new();
+        request.header.code = MessageClass::Request(RequestType::Put);
+        request.set_token(vec![0x4A, 0x4B, 0x4C]);
+        request.add_option(CoapOption::UriHost, b"example.com".to_vec());
+        request.add_option(CoapOption::Observe, vec![]);
+        request.add_option(CoapOption::UriPort, vec![0x16, 0x33]);
+        request.add_option(CoapOption::UriPath, b"sensors".to_vec());
+        request.add_option(CoapOption::UriPath, b"temp".to_vec());
+        request.add_option(CoapOption::ContentFormat, vec![0x3C]);
+        request.add_option(CoapOption::NoResponse, vec![0x1A]);
+        request.add_option(CoapOption::Unknown(echo::ECHO), vec![0xEC; 20]);
+        request.payload = b"{\"value\": 21.5}".to_vec();
+        let request = request.to_bytes().unwrap();
+
+        for &aead in
+            [AeadAlgorithm::AesCcm16_64_128, AeadAlgorithm::A256Gcm].iter()
+        {
+            let algorithms = Algorithms {
+                aead,
+                ..Default::default()
+            };
+            let pair = || {
+                let mut client_context = SecurityContext::with_algorithms(
+                    MASTER_SECRET.to_vec(),
+                    MASTER_SALT.to_vec(),
+                    Some(ID_CONTEXT.to_vec()),
+                    CLIENT_ID.to_vec(),
+                    SERVER_ID.to_vec(),
+                    algorithms,
+                )
+                .unwrap();
+                client_context.set_outer_no_response(true);
+                let server_context = SecurityContext::with_algorithms(
+                    MASTER_SECRET.to_vec(),
+                    MASTER_SALT.to_vec(),
+                    Some(ID_CONTEXT.to_vec()),
+                    SERVER_ID.to_vec(),
+                    CLIENT_ID.to_vec(),
+                    algorithms,
+                )
+                .unwrap();
+                (client_context, server_context)
+            };
+            let (mut client_context, mut server_context) = pair();
+            let (mut client_in_place, mut server_in_place) = pair();
+            let mut buf = [0; 128];
+
+            // Both ways of protecting give the same result
+            let (protected, mut binding) =
+                client_context.protect_request(&request).unwrap();
+            let (len, mut binding_in_place) = client_in_place
+                .protect_request_into(&request, &mut buf)
+                .unwrap();
+            assert_eq!(protected, &buf[..len]);
+            assert_eq!(binding, binding_in_place);
+
+            // And so do both ways of unprotecting
+            let unprotected =
+                server_context.unprotect_request(&protected).unwrap();
+            let len = server_in_place
+                .unprotect_request_into(&protected, &mut buf)
+                .unwrap();
+            assert_eq!(unprotected, &buf[..len]);
+
+            let mut response = Packet::new();
+            response.header.code =
+                MessageClass::Response(ResponseType::Content);
+            response.set_token(vec![0x4A, 0x4B, 0x4C]);
+            response.add_option(CoapOption::Observe, vec![0x07]);
+            response.add_option(CoapOption::MaxAge, vec![0x3C]);
+            response.payload = b"21.5".to_vec();
+            let response = response.to_bytes().unwrap();
+            let protected_response = server_context
+                .protect_response(&response, &protected, true)
+                .unwrap();
+            let len = server_in_place
+                .protect_response_into(&response, &protected, true, &mut buf)
+                .unwrap();
+            assert_eq!(protected_response, &buf[..len]);
+            let unprotected = client_context
+                .unprotect_response(&protected_response, &mut binding)
+                .unwrap();
+            let len = client_in_place
+                .unprotect_response_into(
+                    &protected_response,
+                    &mut binding_in_place,
+                    &mut buf,
+                )
+                .unwrap();
+            assert_eq!(unprotected, &buf[..len]);
+        }
+    }
+
+    #[test]
+    fn outer_max_age() {
+        let pair = || {
+            let client_context = SecurityContext::new(
+                MASTER_SECRET.to_vec(),
+                MASTER_SALT.to_vec(),
+                None,
+                CLIENT_ID.to_vec(),
+                SERVER_ID.to_vec(),
+            )
+            .unwrap();
+            let mut server_context = SecurityContext::new(
+                MASTER_SECRET.to_vec(),
+                MASTER_SALT.to_vec(),
+                None,
+                SERVER_ID.to_vec(),
+                CLIENT_ID.to_vec(),
+            )
+            .unwrap();
+            server_context.set_outer_max_age(true);
+            // An outer option after Max-Age
+            server_context
+                .register_option(65000, OptionClass::Outer)
+                .unwrap();
+            (client_context, server_context)
+        };
+        let (mut client_context, mut server_context) = pair();
+        let (_, mut server_in_place) = pair();
+        let mut buf = [0; 128];
+
+        let (protected, mut binding) =
+            client_context.protect_request(&REQ_UNPROTECTED).unwrap();
+        // Requests don't get it
+        assert!(Packet::from_bytes(&protected)
+            .unwrap()
+            .get_option(CoapOption::MaxAge)
+            .is_none());
+        server_context.unprotect_request(&protected).unwrap();
+        server_in_place
+            .unprotect_request_into(&protected, &mut buf)
+            .unwrap();
+
+        let mut response = Packet::from_bytes(&RES_UNPROTECTED).unwrap();
+        response.add_option(CoapOption::Observe, vec![0x07]);
+        response.add_option(CoapOption::MaxAge, vec![0x3C]);
+        response.add_option(CoapOption::Unknown(65000), vec![0xAA]);
+        let response = response.to_bytes().unwrap();
+        let protected_response = server_context
+            .protect_response(&response, &protected, true)
+            .unwrap();
+        let len = server_in_place
+            .protect_response_into(&response, &protected, true, &mut buf)
+            .unwrap();
+        assert_eq!(protected_response, &buf[..len]);
+        // The outer value is 0, the inner one is protected
+        let outer = Packet::from_bytes(&protected_response).unwrap();
+        assert_eq!(
+            &vec![Vec::<u8>::new()],
+            &outer
+                .get_option(CoapOption::MaxAge)
+                .unwrap()
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>()
+        );
+
+        // The client gets the inner one back
+        let unprotected = client_context
+            .unprotect_response(&protected_response, &mut binding)
+            .unwrap();
+        let unprotected = Packet::from_bytes(&unprotected).unwrap();
+        assert_eq!(
+            &vec![vec![0x3C]],
+            &unprotected
+                .get_option(CoapOption::MaxAge)
+                .unwrap()
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(b"Hello World!", &unprotected.payload[..]);
     }
 }
@@ -0,0 +1,189 @@
+use alloc::vec::Vec;
+use coap_lite::{CoapOption, Packet, ResponseType};
+
+use super::{
+    error::Error,
+    error_response,
+    transport::{self, Transport},
+    Result,
+};
+
+/// The option number of the Echo option (RFC 9175).
+pub const ECHO: usize = 252;
+/// The maximum length of an Echo value.
+pub const MAX_ECHO_LEN: usize = 40;
+
+/// Whether the replay window of a security context can be trusted.
+///
+/// After a reboot, the state of the replay window may be lost, which would
+/// allow previously received requests to be replayed. RFC 8613 Appendix
+/// B.1.2 solves this by challenging the client with an Echo value, which it
+/// has to return in a fresh request before the replay window is
+/// reinitialized from that request's partial IV.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum WindowState {
+    /// The replay window is up to date.
+    Valid,
+    /// The replay window is unknown and no challenge has been sent yet.
+    Unknown,
+    /// The replay window is unknown and this Echo value has been sent.
+    Challenged(Vec<u8>),
+}
+
+/// Returns the value of the Echo option in the CoAP message, if present.
+///
+/// # Arguments
+/// * `coap_msg` - The unprotected CoAP message.
+/// * `transport` - The transport the message is framed for.
+pub fn extract_echo(
+    coap_msg: &[u8],
+    transport: Transport,
+) -> Result<Option<Vec<u8>>> {
+    // Parse the CoAP message
+    let packet = transport::decode(coap_msg, transport)?;
+
+    Ok(get_echo(&packet).cloned())
+}
+
+/// Returns the value of the Echo option in the parsed CoAP message, if
+/// present.
+pub(crate) fn get_echo(packet: &Packet) -> Option<&Vec<u8>> {
+    packet
+        .get_option(CoapOption::from(ECHO))
+        .and_then(|value_list| value_list.front())
+}
+
+/// Returns the CoAP message with the Echo option set to the given value.
+///
+/// A client uses this to repeat its request with the Echo value it received
+/// in the server's challenge.
+///
+/// # Arguments
+/// * `coap_msg` - The unprotected CoAP message.
+/// * `echo` - The Echo value.
+/// * `transport` - The transport the message is framed for.
+pub fn add_echo(
+    coap_msg: &[u8],
+    echo: &[u8],
+    transport: Transport,
+) -> Result<Vec<u8>> {
+    // Parse the CoAP message
+    let mut packet = transport::decode(coap_msg, transport)?;
+    // Replace any existing Echo option
+    packet.clear_option(CoapOption::from(ECHO));
+    packet.add_option(CoapOption::from(ECHO), echo.to_vec());
+
+    transport::encode(&packet, transport)
+}
+
+/// Returns the unprotected 4.01 (Unauthorized) response with the Echo
+/// challenge for a request.
+///
+/// # Arguments
+/// * `request` - The request to respond to.
+/// * `echo` - The Echo value, between 1 and `MAX_ECHO_LEN` bytes.
+/// * `message_id` - The fresh Message ID of a non-confirmable response. The
+///   piggybacked response to a confirmable request has the request's.
+/// * `transport` - The transport the request is framed for.
+pub fn build_challenge(
+    request: &[u8],
+    echo: &[u8],
+    message_id: u16,
+    transport: Transport,
+) -> Result<Vec<u8>> {
+    let request = transport::decode(request, transport)?;
+
+    transport::encode(
+        &build_challenge_packet(&request, echo, message_id)?,
+        transport,
+    )
+}
+
+/// Returns the unprotected 4.01 (Unauthorized) response with the Echo
+/// challenge for a parsed request.
+pub(crate) fn build_challenge_packet(
+    request: &Packet,
+    echo: &[u8],
+    message_id: u16,
+) -> Result<Packet> {
+    if echo.is_empty() || echo.len() > MAX_ECHO_LEN {
+        return Err(Error::InvalidEcho);
+    }
+
+    let mut response = error_response::response_packet(
+        request,
+        ResponseType::Unauthorized,
+        message_id,
+    );
+    response.add_option(CoapOption::from(ECHO), echo.to_vec());
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_vectors::*;
+    use super::*;
+    use coap_lite::MessageClass;
+
+    #[test]
+    fn echo_option() {
+        assert_eq!(
+            None,
+            extract_echo(&REQ_UNPROTECTED, Transport::Udp).unwrap()
+        );
+
+        let with_echo =
+            add_echo(&REQ_UNPROTECTED, &[0x01, 0x02], Transport::Udp).unwrap();
+        assert_eq!(
+            Some(vec![0x01, 0x02]),
+            extract_echo(&with_echo, Transport::Udp).unwrap()
+        );
+
+        // The value is replaced
+        let with_echo = add_echo(&with_echo, &[0x03], Transport::Udp).unwrap();
+        assert_eq!(
+            Some(vec![0x03]),
+            extract_echo(&with_echo, Transport::Udp).unwrap()
+        );
+    }
+
+    #[test]
+    fn challenge() {
+        let challenge = build_challenge(
+            &REQ_UNPROTECTED,
+            &[0xEC; 8],
+            0x1234,
+            Transport::Udp,
+        )
+        .unwrap();
+        let packet = Packet::from_bytes(&challenge).unwrap();
+        let request = Packet::from_bytes(&REQ_UNPROTECTED).unwrap();
+        assert_eq!(
+            MessageClass::Response(ResponseType::Unauthorized),
+            packet.header.code
+        );
+        assert_eq!(request.get_token(), packet.get_token());
+        assert_eq!(request.header.message_id, packet.header.message_id);
+        assert_eq!(
+            Some(vec![0xEC; 8]),
+            extract_echo(&challenge, Transport::Udp).unwrap()
+        );
+
+        assert_eq!(
+            Error::InvalidEcho,
+            build_challenge(&REQ_UNPROTECTED, &[], 0x1234, Transport::Udp)
+                .unwrap_err()
+        );
+        assert_eq!(
+            Error::InvalidEcho,
+            build_challenge(
+                &REQ_UNPROTECTED,
+                &[0; MAX_ECHO_LEN + 1],
+                0x1234,
+                Transport::Udp
+            )
+            .unwrap_err()
+        );
+    }
+}
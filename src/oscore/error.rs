@@ -3,6 +3,7 @@ use core::fmt;
 #[cfg(feature = "std")]
 use std::error;
 
+use super::option::OptionError;
 use crate::cbor;
 
 /// The catch-all error type for this module, mostly just wrapping errors from
@@ -13,8 +14,54 @@ pub enum Error {
     NoOscoreOption,
     /// CoAP request doesn't have kid or piv.
     NoKidPiv,
-    /// This message has been received already.
+    /// This message has been received already or is too old to tell.
     ReplayDetected,
+    /// The replay window size is not supported.
+    InvalidReplayWindow,
+    /// The ID Context is too long.
+    InvalidIdContext,
+    /// A sender or recipient ID is too long for the nonce of the AEAD.
+    InvalidId,
+    /// The request's kid context doesn't match the ID Context.
+    IdContextMismatch,
+    /// The response's token doesn't match the one of the request.
+    TokenMismatch,
+    /// Block-wise transfer is inconsistent or uses an invalid block.
+    InvalidBlock,
+    /// The message exceeds the maximum unfragmented size.
+    MessageTooLarge,
+    /// The buffer is too small for the message.
+    BufferTooSmall,
+    /// The replay window needs to be reinitialized with an Echo challenge.
+    EchoRequired,
+    /// The Echo value has an invalid length.
+    InvalidEcho,
+    /// The commit interval for the sender sequence number is invalid.
+    InvalidCommitInterval,
+    /// The sender sequence number couldn't be persisted.
+    Storage,
+    /// The exported context state is invalid or has an unknown version.
+    InvalidState,
+    /// All sender sequence numbers have been used, a new context is needed.
+    SequenceNumberExhausted,
+    /// The algorithm is not supported.
+    UnsupportedAlgorithm,
+    /// There is no security context for this message.
+    UnknownContext,
+    /// A security context with the same IDs exists already.
+    DuplicateContext,
+    /// The key update is invalid or hasn't progressed far enough.
+    InvalidKeyUpdate,
+    /// The message is protected in a mode that isn't supported here.
+    UnsupportedMode,
+    /// Signaling messages (RFC 8323) can't be protected.
+    Signaling,
+    /// The transport isn't supported for this operation.
+    UnsupportedTransport,
+    /// The HTTP message can't be mapped to CoAP or the other way around.
+    Http,
+    /// The OSCORE option is malformed.
+    InvalidOption(OptionError),
     /// Error while parsing Proxy-Uri.
     InvalidProxyUri,
     /// Message contains an unsupported option.
@@ -23,12 +70,20 @@ pub enum Error {
     Cbor(cbor::CborError),
     /// Wraps errors from `hkdf`.
     Hkdf(hkdf::InvalidLength),
-    /// Error in `aes_ccm`.
+    /// Error in the AEAD.
     Aead,
+    /// Error in the countersignature or the signature keys.
+    Signature,
     /// Wraps errors from `coap_lite`.
     Coap(coap::MessageError),
 }
 
+impl From<OptionError> for Error {
+    fn from(e: OptionError) -> Error {
+        Error::InvalidOption(e)
+    }
+}
+
 impl From<cbor::CborError> for Error {
     fn from(e: cbor::CborError) -> Error {
         Error::Cbor(e)
@@ -47,6 +102,12 @@ impl From<aes_ccm::Error> for Error {
     }
 }
 
+impl From<ed25519_dalek::SignatureError> for Error {
+    fn from(_: ed25519_dalek::SignatureError) -> Error {
+        Error::Signature
+    }
+}
+
 impl From<coap::MessageError> for Error {
     fn from(e: coap::MessageError) -> Error {
         Error::Coap(e)
@@ -71,6 +132,71 @@ impl fmt::Display for Error {
             Error::ReplayDetected => {
                 write!(f, "This message has been received already")
             }
+            Error::InvalidReplayWindow => {
+                write!(f, "The replay window size is not supported")
+            }
+            Error::InvalidIdContext => write!(f, "The ID Context is too long"),
+            Error::InvalidId => {
+                write!(f, "The sender or recipient ID is too long")
+            }
+            Error::IdContextMismatch => {
+                write!(f, "The request's kid context doesn't match")
+            }
+            Error::TokenMismatch => {
+                write!(f, "The response's token doesn't match the request's")
+            }
+            Error::InvalidBlock => {
+                write!(f, "Block-wise transfer is inconsistent")
+            }
+            Error::MessageTooLarge => {
+                write!(f, "The message exceeds the maximum unfragmented size")
+            }
+            Error::BufferTooSmall => {
+                write!(f, "The buffer is too small for the message")
+            }
+            Error::EchoRequired => write!(
+                f,
+                "The replay window needs to be reinitialized with an Echo \
+                 challenge"
+            ),
+            Error::InvalidEcho => {
+                write!(f, "The Echo value has an invalid length")
+            }
+            Error::InvalidCommitInterval => {
+                write!(f, "The commit interval is invalid")
+            }
+            Error::Storage => {
+                write!(f, "The sender sequence number couldn't be persisted")
+            }
+            Error::InvalidState => {
+                write!(f, "The exported context state is invalid")
+            }
+            Error::SequenceNumberExhausted => {
+                write!(f, "All sender sequence numbers have been used")
+            }
+            Error::UnsupportedAlgorithm => {
+                write!(f, "The algorithm is not supported")
+            }
+            Error::UnknownContext => {
+                write!(f, "There is no security context for this message")
+            }
+            Error::DuplicateContext => {
+                write!(f, "A security context with the same IDs exists")
+            }
+            Error::InvalidKeyUpdate => write!(f, "The key update is invalid"),
+            Error::UnsupportedMode => {
+                write!(f, "The message is protected in an unsupported mode")
+            }
+            Error::Signaling => {
+                write!(f, "Signaling messages can't be protected")
+            }
+            Error::UnsupportedTransport => {
+                write!(f, "The transport isn't supported for this operation")
+            }
+            Error::Http => {
+                write!(f, "The message can't be mapped between HTTP and CoAP")
+            }
+            Error::InvalidOption(e) => e.fmt(f),
             Error::InvalidProxyUri => {
                 write!(f, "Error while parsing Proxy-Uri")
             }
@@ -80,6 +206,7 @@ impl fmt::Display for Error {
             Error::Cbor(e) => e.fmt(f),
             Error::Hkdf(e) => e.fmt(f),
             Error::Aead => write!(f, "Error using AEAD"),
+            Error::Signature => write!(f, "Error using the countersignature"),
             Error::Coap(e) => e.fmt(f),
         }
     }
@@ -0,0 +1,270 @@
+use alloc::{string::ToString, vec::Vec};
+use coap_lite::{CoapOption, MessageClass, MessageType, Packet, ResponseType};
+
+use super::{
+    error::Error,
+    transport::{self, Transport},
+    Result,
+};
+
+/// Returns the unprotected CoAP error response for a request that couldn't
+/// be unprotected, as described in RFC 8613 Section 8.2.
+///
+/// Failing to decode the OSCORE option or COSE object results in 4.02 (Bad
+/// Option), a missing security context or a replay in 4.01 (Unauthorized)
+/// and a failed decryption in 4.00 (Bad Request). Errors that aren't caused
+/// by the request result in 5.00 (Internal Server Error). The response has
+/// a Max-Age of 0, so it isn't cached.
+///
+/// For `Error::EchoRequired`, the server should send an Echo challenge with
+/// `protect_echo_challenge` instead.
+///
+/// # Arguments
+/// * `error` - The error returned while unprotecting the request.
+/// * `request` - The OSCORE request.
+/// * `diagnostic` - Whether to include a diagnostic payload.
+/// * `message_id` - The fresh Message ID of a non-confirmable response. The
+///   piggybacked response to a confirmable request has the request's.
+/// * `transport` - The transport the request is framed for.
+pub fn build_error_response(
+    error: &Error,
+    request: &[u8],
+    diagnostic: bool,
+    message_id: u16,
+    transport: Transport,
+) -> Result<Vec<u8>> {
+    let request = transport::decode(request, transport)?;
+    let (code, message) = match error {
+        Error::NoOscoreOption
+        | Error::NoKidPiv
+        | Error::InvalidIdContext
+        | Error::UnsupportedAlgorithm
+        | Error::UnsupportedMode
+        | Error::UnsupportedOption(_)
+        | Error::InvalidOption(_)
+        | Error::InvalidProxyUri
+        | Error::InvalidBlock
+        | Error::InvalidEcho
+        | Error::Cbor(_)
+        | Error::Coap(_) => (ResponseType::BadOption, None),
+        Error::UnknownContext | Error::IdContextMismatch => (
+            ResponseType::Unauthorized,
+            Some("Security context not found"),
+        ),
+        Error::ReplayDetected => {
+            (ResponseType::Unauthorized, Some("Replay detected"))
+        }
+        Error::EchoRequired => (ResponseType::Unauthorized, None),
+        Error::Aead | Error::Signature => {
+            (ResponseType::BadRequest, Some("Decryption failed"))
+        }
+        Error::MessageTooLarge => (ResponseType::RequestEntityTooLarge, None),
+        _ => (ResponseType::InternalServerError, None),
+    };
+
+    let mut response = response_packet(&request, code, message_id);
+    response.add_option(CoapOption::MaxAge, vec![]);
+    if diagnostic {
+        // Fall back to our own description
+        response.payload = match message {
+            Some(message) => message.as_bytes().to_vec(),
+            None => error.to_string().into_bytes(),
+        };
+    }
+
+    transport::encode(&response, transport)
+}
+
+/// Returns the empty unprotected response with this code for a request.
+///
+/// Only a response piggybacked on the ACK takes the request's Message ID,
+/// otherwise it gets `message_id`.
+pub(crate) fn response_packet(
+    request: &Packet,
+    code: ResponseType,
+    message_id: u16,
+) -> Packet {
+    let mut response = Packet::new();
+    // Piggyback on the ACK for confirmable requests
+    let (message_type, message_id) = match request.header.get_type() {
+        MessageType::Confirmable => {
+            (MessageType::Acknowledgement, request.header.message_id)
+        }
+        _ => (MessageType::NonConfirmable, message_id),
+    };
+    response.header.set_type(message_type);
+    response.header.message_id = message_id;
+    response.header.code = MessageClass::Response(code);
+    response.set_token(request.get_token().clone());
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{test_vectors::*, SecurityContext};
+    use super::*;
+
+    /// The Message ID of non-confirmable responses.
+    const MID: u16 = 0x1234;
+
+    fn new_context() -> SecurityContext {
+        SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn error_responses() {
+        let request = Packet::from_bytes(&REQ_PROTECTED).unwrap();
+
+        // A replayed request
+        let mut server_context = new_context();
+        server_context.unprotect_request(&REQ_PROTECTED).unwrap();
+        let error = server_context
+            .unprotect_request(&REQ_PROTECTED)
+            .unwrap_err();
+        let response = Packet::from_bytes(
+            &build_error_response(
+                &error,
+                &REQ_PROTECTED,
+                true,
+                MID,
+                Transport::Udp,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            MessageClass::Response(ResponseType::Unauthorized),
+            response.header.code
+        );
+        assert_eq!(request.get_token(), response.get_token());
+        assert_eq!(request.header.message_id, response.header.message_id);
+        assert_eq!(MessageType::Acknowledgement, response.header.get_type());
+        assert_eq!(&b"Replay detected"[..], &response.payload[..]);
+        assert!(response
+            .get_option(CoapOption::MaxAge)
+            .unwrap()
+            .front()
+            .unwrap()
+            .is_empty());
+        // It isn't protected
+        assert!(response.get_option(CoapOption::Oscore).is_none());
+
+        // A non-confirmable request gets a fresh Message ID
+        let mut non = request.clone();
+        non.header.set_type(MessageType::NonConfirmable);
+        let response = Packet::from_bytes(
+            &build_error_response(
+                &Error::NoKidPiv,
+                &non.to_bytes().unwrap(),
+                false,
+                MID,
+                Transport::Udp,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(MessageType::NonConfirmable, response.header.get_type());
+        assert_eq!(MID, response.header.message_id);
+        assert_eq!(request.get_token(), response.get_token());
+
+        // A tampered ciphertext
+        let mut tampered = REQ_PROTECTED;
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0x01;
+        let error = new_context().unprotect_request(&tampered).unwrap_err();
+        let response = Packet::from_bytes(
+            &build_error_response(
+                &error,
+                &tampered,
+                true,
+                MID,
+                Transport::Udp,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            MessageClass::Response(ResponseType::BadRequest),
+            response.header.code
+        );
+        assert_eq!(&b"Decryption failed"[..], &response.payload[..]);
+
+        // A missing kid, without the diagnostic payload
+        let response = Packet::from_bytes(
+            &build_error_response(
+                &Error::NoKidPiv,
+                &REQ_PROTECTED,
+                false,
+                MID,
+                Transport::Udp,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            MessageClass::Response(ResponseType::BadOption),
+            response.header.code
+        );
+        assert!(response.payload.is_empty());
+
+        // Errors of the server itself
+        let response = Packet::from_bytes(
+            &build_error_response(
+                &Error::Storage,
+                &REQ_PROTECTED,
+                true,
+                MID,
+                Transport::Udp,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            MessageClass::Response(ResponseType::InternalServerError),
+            response.header.code
+        );
+        assert_eq!(
+            Error::Storage.to_string().as_bytes(),
+            &response.payload[..]
+        );
+    }
+
+    #[test]
+    fn reliable_transports() {
+        // The request framed for TCP, without type and message ID
+        let request = Packet::from_bytes(&REQ_PROTECTED).unwrap();
+        let tcp = transport::encode(&request, Transport::Tcp).unwrap();
+        let response = build_error_response(
+            &Error::NoKidPiv,
+            &tcp,
+            false,
+            MID,
+            Transport::Tcp,
+        )
+        .unwrap();
+        let response = transport::decode(&response, Transport::Tcp).unwrap();
+        assert_eq!(
+            MessageClass::Response(ResponseType::BadOption),
+            response.header.code
+        );
+        assert_eq!(request.get_token(), response.get_token());
+
+        // It can't be read as UDP
+        assert!(build_error_response(
+            &Error::NoKidPiv,
+            &tcp,
+            false,
+            MID,
+            Transport::Udp
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,1162 @@
+//! Group OSCORE, in group mode and pairwise mode.
+//!
+//! A group security context is shared by all members of a group, which is
+//! identified by its Group ID (the ID Context). Every member derives its own
+//! Sender Key and a Recipient Key for each other member from the common
+//! master secret and salt, so messages sent to the whole group, e.g. with
+//! multicast CoAP, can be verified by each recipient individually.
+//!
+//! Since every member knows all keys, the AEAD alone doesn't authenticate the
+//! sender. Each message is therefore countersigned with the sender's Ed25519
+//! key, and the signature is encrypted with a keystream derived from the
+//! Group Encryption Key before it's appended to the ciphertext. The replay
+//! windows are kept per sender ID.
+//!
+//! Two members that know each other's static X25519 key can also talk in
+//! pairwise mode, which doesn't need a signature. The pairwise keys are
+//! derived from their Sender and Recipient Keys together with the ECDH
+//! shared secret, so only the two of them can unprotect the messages. The
+//! mode can be chosen per request, and a response always uses the mode of
+//! its request.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use coap_lite::{CoapOption, Packet};
+use ed25519_dalek::{Keypair, PublicKey, Signature};
+use serde_bytes::Bytes;
+use sha2::Sha512;
+use x25519_dalek::StaticSecret;
+
+use crate::cbor;
+
+use super::{
+    algorithm::{AeadAlgorithm, Algorithms},
+    classes::{OptionClass, OptionClasses},
+    context::{self, MAX_SEQUENCE_NUMBER},
+    error::Error,
+    option::OscoreOptionRef,
+    replay::ReplayWindow,
+    transport::{self, Transport},
+    util, Result,
+};
+
+/// The length of an Ed25519 countersignature.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// The COSE identifier of EdDSA, the signature algorithm.
+const ALG_SIGNATURE: i32 = -8;
+
+/// The common context part of the group security context.
+struct CommonContext {
+    master_secret: Vec<u8>,
+    master_salt: Vec<u8>,
+    algorithms: Algorithms,
+    gid: Vec<u8>,
+    common_iv: Vec<u8>,
+    group_encryption_key: Vec<u8>,
+}
+
+/// The sender context part of the group security context.
+struct SenderContext {
+    sender_id: Vec<u8>,
+    sender_key: Vec<u8>,
+    sender_sequence_number: u64,
+    // The Ed25519 key pair, secret key followed by public key
+    keypair: Vec<u8>,
+    // The static X25519 key for pairwise mode, if any
+    ecdh_secret: Option<StaticSecret>,
+}
+
+/// The recipient context of another group member.
+struct RecipientContext {
+    recipient_key: Vec<u8>,
+    public_key: PublicKey,
+    replay_window: ReplayWindow,
+    // The member's static X25519 key for pairwise mode, if known
+    ecdh_public: Option<x25519_dalek::PublicKey>,
+    // The pairwise sender and recipient keys, once both X25519 keys are known
+    pairwise_keys: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// The mode a message is protected in.
+#[derive(Clone, Copy)]
+enum Mode<'a> {
+    /// Group mode, for a request or a response.
+    Group { is_request: bool },
+    /// Pairwise mode, with the member with this sender ID.
+    Pairwise { peer: &'a [u8] },
+}
+
+/// The security context of a group member.
+pub struct GroupContext {
+    common_context: CommonContext,
+    sender_context: SenderContext,
+    /// The recipient contexts, by the sender ID of the other member.
+    recipient_contexts: BTreeMap<Vec<u8>, RecipientContext>,
+    /// The classes deciding which options are protected.
+    option_classes: OptionClasses,
+}
+
+impl GroupContext {
+    /// Creates a new `GroupContext` using the default algorithms.
+    ///
+    /// # Arguments
+    /// * `master_secret` - The master secret of the group.
+    /// * `master_salt` - The master salt of the group.
+    /// * `gid` - The Group ID, which is used as the ID Context. It can be at
+    ///   most 255 bytes long.
+    /// * `sender_id` - The own ID in the group.
+    /// * `keypair` - The own Ed25519 key pair. First 32 bytes are the secret
+    ///   key, the other 32 bytes the public key.
+    pub fn new(
+        master_secret: Vec<u8>,
+        master_salt: Vec<u8>,
+        gid: Vec<u8>,
+        sender_id: Vec<u8>,
+        keypair: &[u8],
+    ) -> Result<GroupContext> {
+        GroupContext::with_algorithms(
+            master_secret,
+            master_salt,
+            gid,
+            sender_id,
+            keypair,
+            Algorithms::default(),
+        )
+    }
+
+    /// Creates a new `GroupContext` using the given algorithms.
+    ///
+    /// The AEAD algorithm is used both for the messages and the Group
+    /// Encryption Key. The arguments are the same as for `new`.
+    pub fn with_algorithms(
+        master_secret: Vec<u8>,
+        master_salt: Vec<u8>,
+        gid: Vec<u8>,
+        sender_id: Vec<u8>,
+        keypair: &[u8],
+        algorithms: Algorithms,
+    ) -> Result<GroupContext> {
+        // The Group ID is the kid context, the length of which has to fit
+        // into a single byte in the OSCORE option
+        if gid.len() > 255 {
+            return Err(Error::InvalidIdContext);
+        }
+        util::check_id(&sender_id, algorithms.aead)?;
+        // Make sure the key pair is usable before we need it
+        Keypair::from_bytes(keypair)?;
+
+        let alg_aead = algorithms.aead.id();
+        let key_len = algorithms.aead.key_len();
+        let nonce_len = algorithms.aead.nonce_len();
+        let common_iv = util::hkdf(
+            algorithms.hkdf,
+            &master_secret,
+            &master_salt,
+            &util::build_info(&[], Some(&gid), alg_aead, "IV", nonce_len)?,
+            nonce_len,
+        )?;
+        let group_encryption_key = util::hkdf(
+            algorithms.hkdf,
+            &master_secret,
+            &master_salt,
+            &util::build_info(
+                &[],
+                Some(&gid),
+                alg_aead,
+                "Group Encryption Key",
+                key_len,
+            )?,
+            key_len,
+        )?;
+
+        let common_context = CommonContext {
+            master_secret,
+            master_salt,
+            algorithms,
+            gid,
+            common_iv,
+            group_encryption_key,
+        };
+        let sender_key = derive_key(&common_context, &sender_id)?;
+        let sender_context = SenderContext {
+            sender_id,
+            sender_key,
+            sender_sequence_number: 0,
+            keypair: keypair.to_vec(),
+            ecdh_secret: None,
+        };
+
+        Ok(GroupContext {
+            common_context,
+            sender_context,
+            recipient_contexts: BTreeMap::new(),
+            option_classes: OptionClasses::new(),
+        })
+    }
+
+    /// Adds another member of the group, deriving its Recipient Key.
+    ///
+    /// # Arguments
+    /// * `recipient_id` - The sender ID of the member.
+    /// * `public_key` - The member's Ed25519 public key.
+    pub fn add_recipient(
+        &mut self,
+        recipient_id: Vec<u8>,
+        public_key: &[u8],
+    ) -> Result<()> {
+        if recipient_id == self.sender_context.sender_id
+            || self.recipient_contexts.contains_key(&recipient_id)
+        {
+            return Err(Error::DuplicateContext);
+        }
+        util::check_id(&recipient_id, self.aead())?;
+        let recipient_context = RecipientContext {
+            recipient_key: derive_key(&self.common_context, &recipient_id)?,
+            public_key: PublicKey::from_bytes(public_key)?,
+            replay_window: ReplayWindow::default(),
+            ecdh_public: None,
+            pairwise_keys: None,
+        };
+        self.recipient_contexts
+            .insert(recipient_id, recipient_context);
+
+        Ok(())
+    }
+
+    /// Sets the own static X25519 key, enabling pairwise mode with the
+    /// members whose key is known.
+    ///
+    /// # Arguments
+    /// * `ecdh_secret` - The own static X25519 secret key.
+    pub fn set_ecdh_secret(&mut self, ecdh_secret: [u8; 32]) -> Result<()> {
+        self.sender_context.ecdh_secret =
+            Some(StaticSecret::from(ecdh_secret));
+        // Derive the pairwise keys with every member we can
+        let recipient_ids: Vec<Vec<u8>> =
+            self.recipient_contexts.keys().cloned().collect();
+        for recipient_id in recipient_ids {
+            self.update_pairwise_keys(&recipient_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the static X25519 key of a member, enabling pairwise mode with
+    /// it once the own key is set as well.
+    ///
+    /// # Arguments
+    /// * `recipient_id` - The sender ID of the member.
+    /// * `ecdh_public` - The member's static X25519 public key.
+    pub fn set_recipient_ecdh_key(
+        &mut self,
+        recipient_id: &[u8],
+        ecdh_public: [u8; 32],
+    ) -> Result<()> {
+        self.recipient_contexts
+            .get_mut(recipient_id)
+            .ok_or(Error::UnknownContext)?
+            .ecdh_public = Some(x25519_dalek::PublicKey::from(ecdh_public));
+
+        self.update_pairwise_keys(recipient_id)
+    }
+
+    /// Removes a member of the group, returning whether it was known.
+    pub fn remove_recipient(&mut self, recipient_id: &[u8]) -> bool {
+        self.recipient_contexts.remove(recipient_id).is_some()
+    }
+
+    /// Registers the class of an option that isn't defined in a standard.
+    ///
+    /// # Arguments
+    /// * `number` - The option number.
+    /// * `class` - The class of the option.
+    pub fn register_option(
+        &mut self,
+        number: usize,
+        class: OptionClass,
+    ) -> Result<()> {
+        self.option_classes.register(number, class)
+    }
+
+    /// Returns the Group ID.
+    pub fn get_gid(&self) -> &[u8] {
+        &self.common_context.gid
+    }
+
+    /// Returns the own sender ID.
+    pub fn get_sender_id(&self) -> &[u8] {
+        &self.sender_context.sender_id
+    }
+
+    /// Returns an OSCORE message protecting the CoAP request in group mode.
+    ///
+    /// # Arguments
+    /// * `coap_msg` - The original CoAP request to protect.
+    pub fn protect_request(&mut self, coap_msg: &[u8]) -> Result<Vec<u8>> {
+        let piv = self.get_piv();
+        let sender_id = self.sender_context.sender_id.clone();
+        // A request always carries the kid, piv and Group ID
+        self.increment_sequence_number()?;
+        let option = OscoreOptionRef {
+            kid: Some(&sender_id),
+            piv: Some(&piv),
+            kid_context: Some(&self.common_context.gid),
+            group: true,
+            ..Default::default()
+        }
+        .encode()?;
+
+        self.protect_message(
+            coap_msg,
+            &sender_id,
+            &piv,
+            &piv,
+            option,
+            Mode::Group { is_request: true },
+        )
+    }
+
+    /// Returns an OSCORE message protecting the CoAP request in pairwise
+    /// mode, so only the member with this sender ID can unprotect it.
+    ///
+    /// # Arguments
+    /// * `coap_msg` - The original CoAP request to protect.
+    /// * `recipient_id` - The sender ID of the member the request is for.
+    pub fn protect_pairwise_request(
+        &mut self,
+        coap_msg: &[u8],
+        recipient_id: &[u8],
+    ) -> Result<Vec<u8>> {
+        // Fail before the sequence number is used
+        self.get_pairwise_keys(recipient_id)?;
+        let piv = self.get_piv();
+        let sender_id = self.sender_context.sender_id.clone();
+        self.increment_sequence_number()?;
+        let option = util::build_oscore_option(
+            Some(&sender_id),
+            Some(&piv),
+            Some(&self.common_context.gid),
+        )?;
+
+        self.protect_message(
+            coap_msg,
+            &sender_id,
+            &piv,
+            &piv,
+            option,
+            Mode::Pairwise { peer: recipient_id },
+        )
+    }
+
+    /// Returns an OSCORE message protecting the CoAP response, in the same
+    /// mode as the request.
+    ///
+    /// Since the request may have been received by several members, the
+    /// response always carries the own sender ID and a fresh `piv`.
+    ///
+    /// # Arguments
+    /// * `coap_msg` - The original CoAP response to protect.
+    /// * `request` - The OSCORE request to which to respond.
+    pub fn protect_response(
+        &mut self,
+        coap_msg: &[u8],
+        request: &[u8],
+    ) -> Result<Vec<u8>> {
+        let (request_kid, request_piv, group) = extract_request(request)?;
+        let mode = if group {
+            Mode::Group { is_request: false }
+        } else {
+            self.get_pairwise_keys(&request_kid)?;
+            Mode::Pairwise { peer: &request_kid }
+        };
+        let piv = self.get_piv();
+        self.increment_sequence_number()?;
+        let option = OscoreOptionRef {
+            kid: Some(&self.sender_context.sender_id),
+            piv: Some(&piv),
+            group,
+            ..Default::default()
+        }
+        .encode()?;
+
+        self.protect_message(
+            coap_msg,
+            &request_kid,
+            &request_piv,
+            &piv,
+            option,
+            mode,
+        )
+    }
+
+    /// Returns the original CoAP request protected in group or pairwise
+    /// mode.
+    ///
+    /// The sender needs to have been added with `add_recipient`. The replay
+    /// window of a member is the same for both modes.
+    ///
+    /// # Arguments
+    /// * `oscore_msg` - The OSCORE message protecting the CoAP request.
+    pub fn unprotect_request(&mut self, oscore_msg: &[u8]) -> Result<Vec<u8>> {
+        let original = Packet::from_bytes(oscore_msg)?;
+        let (kid, piv, kid_context) =
+            util::extract_kid_piv_context(&original)?;
+        let (kid, piv) =
+            (kid.ok_or(Error::NoKidPiv)?, piv.ok_or(Error::NoKidPiv)?);
+        let group = has_group_flag(&original)?;
+        if kid_context.as_ref() != Some(&self.common_context.gid) {
+            return Err(Error::IdContextMismatch);
+        }
+
+        // Verify that the partial IV has not been received from this sender
+        let sequence_number = util::piv_to_u64(&piv);
+        self.recipient_contexts
+            .get(&kid)
+            .ok_or(Error::UnknownContext)?
+            .replay_window
+            .check(sequence_number)?;
+
+        let mode = if group {
+            Mode::Group { is_request: true }
+        } else {
+            Mode::Pairwise { peer: &kid }
+        };
+        let unprotected =
+            self.unprotect_message(original, &kid, &kid, &piv, &piv, mode)?;
+
+        // Only remember the partial IV once the message has been verified
+        if let Some(recipient_context) = self.recipient_contexts.get_mut(&kid)
+        {
+            recipient_context.replay_window.update(sequence_number);
+        }
+
+        Ok(unprotected)
+    }
+
+    /// Returns the original CoAP response protected in group or pairwise
+    /// mode.
+    ///
+    /// The responding member needs to have been added with `add_recipient`,
+    /// which can be checked beforehand with `extract_request_ids`, since the
+    /// response carries the member's sender ID as well.
+    ///
+    /// Since every response carries its own partial IV, it's checked
+    /// against the same replay window as the member's requests.
+    ///
+    /// # Arguments
+    /// * `oscore_msg` - The OSCORE message protecting the CoAP response.
+    /// * `request` - The OSCORE request it responds to.
+    pub fn unprotect_response(
+        &mut self,
+        oscore_msg: &[u8],
+        request: &[u8],
+    ) -> Result<Vec<u8>> {
+        let (request_kid, request_piv, _) = extract_request(request)?;
+        let original = Packet::from_bytes(oscore_msg)?;
+        let (kid, piv, _) = util::extract_kid_piv_context(&original)?;
+        let (kid, piv) =
+            (kid.ok_or(Error::NoKidPiv)?, piv.ok_or(Error::NoKidPiv)?);
+        let mode = if has_group_flag(&original)? {
+            Mode::Group { is_request: false }
+        } else {
+            Mode::Pairwise { peer: &kid }
+        };
+
+        // Verify that the partial IV has not been received from this sender
+        let sequence_number = util::piv_to_u64(&piv);
+        self.recipient_contexts
+            .get(&kid)
+            .ok_or(Error::UnknownContext)?
+            .replay_window
+            .check(sequence_number)?;
+
+        let unprotected = self.unprotect_message(
+            original,
+            &kid,
+            &request_kid,
+            &request_piv,
+            &piv,
+            mode,
+        )?;
+
+        // Only remember the partial IV once the message has been verified
+        if let Some(recipient_context) = self.recipient_contexts.get_mut(&kid)
+        {
+            recipient_context.replay_window.update(sequence_number);
+        }
+
+        Ok(unprotected)
+    }
+
+    /// Returns the protected OSCORE message, countersigned in group mode.
+    ///
+    /// # Arguments
+    /// * `coap_msg` - The original CoAP message to protect.
+    /// * `request_kid` - The `kid` of the request.
+    /// * `request_piv` - The `piv` of the request.
+    /// * `piv` - The own `piv` used for this message.
+    /// * `option` - The value of the OSCORE option.
+    /// * `mode` - The mode to protect the message in.
+    fn protect_message(
+        &self,
+        coap_msg: &[u8],
+        request_kid: &[u8],
+        request_piv: &[u8],
+        piv: &[u8],
+        option: Vec<u8>,
+        mode: Mode,
+    ) -> Result<Vec<u8>> {
+        let keypair = Keypair::from_bytes(&self.sender_context.keypair)?;
+        let aad_arr = self.build_aad_array(
+            request_kid,
+            request_piv,
+            &option,
+            keypair.public.as_bytes(),
+        )?;
+        let nonce = util::compute_nonce(
+            piv,
+            &self.sender_context.sender_id,
+            &self.common_context.common_iv,
+        );
+
+        let key = match mode {
+            Mode::Group { .. } => &self.sender_context.sender_key,
+            Mode::Pairwise { peer } => &self.get_pairwise_keys(peer)?.0,
+        };
+
+        let mut packet = context::encrypt_message(
+            Packet::from_bytes(coap_msg)?,
+            self.aead(),
+            key,
+            &build_aad(&aad_arr)?,
+            &nonce,
+            option,
+            &self.option_classes,
+        )?;
+        let is_request = match mode {
+            Mode::Group { is_request } => is_request,
+            // The key already authenticates the sender
+            Mode::Pairwise { .. } => {
+                return transport::encode_oscore(&packet, Transport::Udp)
+            }
+        };
+
+        // Sign the ciphertext, then encrypt the signature and append it
+        let to_be_signed = build_to_be_signed(&aad_arr, &packet.payload)?;
+        let mut signature = keypair.sign::<Sha512>(&to_be_signed).to_bytes();
+        let keystream =
+            self.keystream(&self.sender_context.sender_id, piv, is_request)?;
+        for (b, k) in signature.iter_mut().zip(keystream) {
+            *b ^= k;
+        }
+        packet.payload.extend(&signature[..]);
+
+        transport::encode_oscore(&packet, Transport::Udp)
+    }
+
+    /// Returns the original CoAP message, if its ciphertext and in group
+    /// mode its countersignature could be verified.
+    ///
+    /// # Arguments
+    /// * `original` - The OSCORE message protecting the CoAP message.
+    /// * `kid` - The sender ID of the member that sent the message.
+    /// * `request_kid` - The `kid` of the request.
+    /// * `request_piv` - The `piv` of the request.
+    /// * `piv` - The `piv` of the message.
+    /// * `mode` - The mode the message is protected in.
+    fn unprotect_message(
+        &self,
+        mut original: Packet,
+        kid: &[u8],
+        request_kid: &[u8],
+        request_piv: &[u8],
+        piv: &[u8],
+        mode: Mode,
+    ) -> Result<Vec<u8>> {
+        let recipient_context = self
+            .recipient_contexts
+            .get(kid)
+            .ok_or(Error::UnknownContext)?;
+        let option = original
+            .get_option(CoapOption::Oscore)
+            .and_then(|values| values.front())
+            .ok_or(Error::NoOscoreOption)?
+            .clone();
+        let aad_arr = self.build_aad_array(
+            request_kid,
+            request_piv,
+            &option,
+            recipient_context.public_key.as_bytes(),
+        )?;
+
+        let key = match mode {
+            Mode::Group { is_request } => {
+                self.verify_signature(
+                    &mut original,
+                    kid,
+                    &recipient_context.public_key,
+                    piv,
+                    is_request,
+                    &aad_arr,
+                )?;
+                &recipient_context.recipient_key
+            }
+            Mode::Pairwise { .. } => {
+                &recipient_context
+                    .pairwise_keys
+                    .as_ref()
+                    .ok_or(Error::UnsupportedMode)?
+                    .1
+            }
+        };
+
+        let nonce =
+            util::compute_nonce(piv, kid, &self.common_context.common_iv);
+        Ok(context::decrypt_message(
+            original,
+            self.aead(),
+            key,
+            &build_aad(&aad_arr)?,
+            &nonce,
+            &self.option_classes,
+        )?
+        .to_bytes()?)
+    }
+
+    /// Splits off the countersignature from the payload and verifies it.
+    ///
+    /// # Arguments
+    /// * `original` - The OSCORE message protecting the CoAP message.
+    /// * `kid` - The sender ID of the member that sent the message.
+    /// * `public_key` - The public key of the member.
+    /// * `piv` - The `piv` of the message.
+    /// * `is_request` - Whether the message is a request.
+    /// * `aad_arr` - The AAD array of the message.
+    fn verify_signature(
+        &self,
+        original: &mut Packet,
+        kid: &[u8],
+        public_key: &PublicKey,
+        piv: &[u8],
+        is_request: bool,
+        aad_arr: &[u8],
+    ) -> Result<()> {
+        // Split off the signature and decrypt it
+        if original.payload.len() < SIGNATURE_LEN + self.aead().tag_len() {
+            return Err(Error::Signature);
+        }
+        let mut signature = original
+            .payload
+            .split_off(original.payload.len() - SIGNATURE_LEN);
+        for (b, k) in signature
+            .iter_mut()
+            .zip(self.keystream(kid, piv, is_request)?)
+        {
+            *b ^= k;
+        }
+        // Verify it before spending any effort on the ciphertext
+        let to_be_signed = build_to_be_signed(aad_arr, &original.payload)?;
+        public_key.verify::<Sha512>(
+            &to_be_signed,
+            &Signature::from_bytes(&signature)?,
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the CBOR encoded AAD array of Group OSCORE.
+    ///
+    /// Besides the values of the regular AAD array, it binds the Group ID,
+    /// the OSCORE option and the public key of the sender.
+    fn build_aad_array(
+        &self,
+        request_kid: &[u8],
+        request_piv: &[u8],
+        option: &[u8],
+        public_key: &[u8],
+    ) -> Result<Vec<u8>> {
+        // (oscore_version, algorithms, request_kid, request_piv, options,
+        // request_kid_context, OSCORE_option, sender_cred)
+        let arr = (
+            1,
+            [self.aead().id(), ALG_SIGNATURE],
+            Bytes::new(request_kid),
+            Bytes::new(request_piv),
+            Bytes::new(&[]),
+            Bytes::new(&self.common_context.gid),
+            Bytes::new(option),
+            Bytes::new(public_key),
+        );
+
+        Ok(cbor::encode(arr)?)
+    }
+
+    /// Returns the keystream with which the signature of a message is
+    /// encrypted.
+    ///
+    /// # Arguments
+    /// * `id` - The sender ID of the member that sent the message.
+    /// * `piv` - The `piv` of the message.
+    /// * `is_request` - Whether the message is a request.
+    fn keystream(
+        &self,
+        id: &[u8],
+        piv: &[u8],
+        is_request: bool,
+    ) -> Result<Vec<u8>> {
+        // (id, id_context, type, label, L)
+        let info = (
+            Bytes::new(id),
+            Bytes::new(&self.common_context.gid),
+            is_request,
+            "SEKeystream",
+            SIGNATURE_LEN,
+        );
+
+        util::hkdf(
+            self.common_context.algorithms.hkdf,
+            &self.common_context.group_encryption_key,
+            piv,
+            &cbor::encode(info)?,
+            SIGNATURE_LEN,
+        )
+    }
+
+    /// Derives the pairwise keys with the member, if both X25519 keys are
+    /// known.
+    fn update_pairwise_keys(&mut self, recipient_id: &[u8]) -> Result<()> {
+        let recipient_context = self
+            .recipient_contexts
+            .get(recipient_id)
+            .ok_or(Error::UnknownContext)?;
+        let (ecdh_secret, ecdh_public) = match (
+            &self.sender_context.ecdh_secret,
+            &recipient_context.ecdh_public,
+        ) {
+            (Some(secret), Some(public)) => (secret, public),
+            _ => return Ok(()),
+        };
+        let shared_secret = ecdh_secret.diffie_hellman(ecdh_public);
+        let own_key = &self.sender_context.keypair[32..];
+        let peer_key = recipient_context.public_key.as_bytes();
+
+        // Each key is bound to both members' public keys, starting with the
+        // one of the member it belongs to
+        let mut ikm_sender = own_key.to_vec();
+        ikm_sender.extend(&peer_key[..]);
+        ikm_sender.extend(shared_secret.as_bytes());
+        let mut ikm_recipient = peer_key.to_vec();
+        ikm_recipient.extend(own_key);
+        ikm_recipient.extend(shared_secret.as_bytes());
+        let pairwise_keys = (
+            self.derive_pairwise_key(
+                &ikm_sender,
+                &self.sender_context.sender_key,
+                &self.sender_context.sender_id,
+            )?,
+            self.derive_pairwise_key(
+                &ikm_recipient,
+                &recipient_context.recipient_key,
+                recipient_id,
+            )?,
+        );
+
+        if let Some(recipient_context) =
+            self.recipient_contexts.get_mut(recipient_id)
+        {
+            recipient_context.pairwise_keys = Some(pairwise_keys);
+        }
+
+        Ok(())
+    }
+
+    /// Returns a pairwise key, which is derived with the Sender or Recipient
+    /// Key as the salt.
+    ///
+    /// # Arguments
+    /// * `ikm` - Both public keys and the ECDH shared secret.
+    /// * `key` - The Sender or Recipient Key.
+    /// * `id` - The sender ID of the member the key belongs to.
+    fn derive_pairwise_key(
+        &self,
+        ikm: &[u8],
+        key: &[u8],
+        id: &[u8],
+    ) -> Result<Vec<u8>> {
+        let algorithms = self.common_context.algorithms;
+        let key_len = algorithms.aead.key_len();
+
+        util::hkdf(
+            algorithms.hkdf,
+            ikm,
+            key,
+            &util::build_info(
+                id,
+                Some(&self.common_context.gid),
+                algorithms.aead.id(),
+                "Key",
+                key_len,
+            )?,
+            key_len,
+        )
+    }
+
+    /// Returns the pairwise sender and recipient keys with the member.
+    fn get_pairwise_keys(
+        &self,
+        recipient_id: &[u8],
+    ) -> Result<&(Vec<u8>, Vec<u8>)> {
+        self.recipient_contexts
+            .get(recipient_id)
+            .ok_or(Error::UnknownContext)?
+            .pairwise_keys
+            .as_ref()
+            .ok_or(Error::UnsupportedMode)
+    }
+
+    /// Increments the sender sequence number after it has been used.
+    fn increment_sequence_number(&mut self) -> Result<()> {
+        if self.sender_context.sender_sequence_number > MAX_SEQUENCE_NUMBER {
+            return Err(Error::SequenceNumberExhausted);
+        }
+        self.sender_context.sender_sequence_number += 1;
+
+        Ok(())
+    }
+
+    /// Returns the byte representation of the partial IV.
+    fn get_piv(&self) -> Vec<u8> {
+        util::format_piv(self.sender_context.sender_sequence_number)
+    }
+
+    /// Returns the AEAD algorithm used by this context.
+    fn aead(&self) -> AeadAlgorithm {
+        self.common_context.algorithms.aead
+    }
+}
+
+/// Returns the Sender Key or Recipient Key of the member with this ID.
+fn derive_key(common_context: &CommonContext, id: &[u8]) -> Result<Vec<u8>> {
+    let algorithms = common_context.algorithms;
+    let key_len = algorithms.aead.key_len();
+
+    util::hkdf(
+        algorithms.hkdf,
+        &common_context.master_secret,
+        &common_context.master_salt,
+        &util::build_info(
+            id,
+            Some(&common_context.gid),
+            algorithms.aead.id(),
+            "Key",
+            key_len,
+        )?,
+        key_len,
+    )
+}
+
+/// Returns the AAD for the AEAD from the AAD array.
+fn build_aad(aad_arr: &[u8]) -> Result<Vec<u8>> {
+    let aad = ("Encrypt0", Bytes::new(&[]), Bytes::new(aad_arr));
+
+    Ok(cbor::encode(aad)?)
+}
+
+/// Returns the `Countersign_structure` that is signed.
+fn build_to_be_signed(aad_arr: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+    let countersign_struct = (
+        "CounterSignature0",
+        Bytes::new(&[]), // body_protected
+        Bytes::new(&[]), // sign_protected
+        Bytes::new(aad_arr),
+        Bytes::new(ciphertext),
+    );
+
+    Ok(cbor::encode(countersign_struct)?)
+}
+
+/// Returns whether the Group Flag is set in the OSCORE option.
+fn has_group_flag(message: &Packet) -> Result<bool> {
+    let option = message
+        .get_option(CoapOption::Oscore)
+        .and_then(|values| values.front())
+        .ok_or(Error::NoOscoreOption)?;
+
+    Ok(OscoreOptionRef::decode(option)?.group)
+}
+
+/// Returns the `kid` and `piv` of the OSCORE request, and whether it's in
+/// group mode.
+fn extract_request(request: &[u8]) -> Result<(Vec<u8>, Vec<u8>, bool)> {
+    let request = Packet::from_bytes(request)?;
+    let (kid, piv, _) = util::extract_kid_piv_context(&request)?;
+
+    Ok((
+        kid.ok_or(Error::NoKidPiv)?,
+        piv.ok_or(Error::NoKidPiv)?,
+        has_group_flag(&request)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{test_vectors::*, SecurityContext};
+    use super::*;
+    use ed25519_dalek::SecretKey;
+
+    const GID: [u8; 3] = [0x37, 0xCB, 0xF3];
+    const CLIENT: [u8; 1] = [0x25];
+    const SERVER_1: [u8; 1] = [0x52];
+    const SERVER_2: [u8; 1] = [0x77];
+
+    /// Returns the key pair bytes for this seed.
+    fn keypair(seed: u8) -> Vec<u8> {
+        let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+        let public = PublicKey::from_secret::<Sha512>(&secret);
+        let mut keypair = secret.to_bytes().to_vec();
+        keypair.extend(&public.to_bytes());
+
+        keypair
+    }
+
+    /// Returns the X25519 public key for this seed.
+    fn ecdh_public(seed: u8) -> [u8; 32] {
+        *x25519_dalek::PublicKey::from(&StaticSecret::from([seed; 32]))
+            .as_bytes()
+    }
+
+    /// Returns the context of a member, knowing all others.
+    fn member(id: &[u8], seed: u8) -> GroupContext {
+        let mut context = GroupContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            GID.to_vec(),
+            id.to_vec(),
+            &keypair(seed),
+        )
+        .unwrap();
+        for (other, other_seed) in
+            [(CLIENT, 1), (SERVER_1, 2), (SERVER_2, 3)].iter()
+        {
+            if &other[..] != id {
+                context
+                    .add_recipient(other.to_vec(), &keypair(*other_seed)[32..])
+                    .unwrap();
+            }
+        }
+
+        context
+    }
+
+    #[test]
+    fn group_mode() {
+        let mut client = member(&CLIENT, 1);
+        let mut servers = [member(&SERVER_1, 2), member(&SERVER_2, 3)];
+
+        let req = client.protect_request(&REQ_UNPROTECTED).unwrap();
+        for server in servers.iter_mut() {
+            assert_eq!(
+                &REQ_UNPROTECTED[..],
+                &server.unprotect_request(&req).unwrap()[..]
+            );
+            // Each member keeps its own replay window for the client
+            assert_eq!(
+                Error::ReplayDetected,
+                server.unprotect_request(&req).unwrap_err()
+            );
+
+            let res = server.protect_response(&RES_UNPROTECTED, &req).unwrap();
+            assert_eq!(
+                &RES_UNPROTECTED[..],
+                &client.unprotect_response(&res, &req).unwrap()[..]
+            );
+            // Responses can't be replayed either
+            assert_eq!(
+                Error::ReplayDetected,
+                client.unprotect_response(&res, &req).unwrap_err()
+            );
+        }
+
+        // The same piv from another sender is fine
+        let req = servers[0].protect_request(&REQ_UNPROTECTED).unwrap();
+        assert_eq!(
+            &REQ_UNPROTECTED[..],
+            &servers[1].unprotect_request(&req).unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn signature() {
+        let mut client = member(&CLIENT, 1);
+        let mut server = member(&SERVER_1, 2);
+        let req = client.protect_request(&REQ_UNPROTECTED).unwrap();
+
+        // Tampering with the signature or the ciphertext is detected
+        for i in &[1, SIGNATURE_LEN + 1] {
+            let mut tampered = req.clone();
+            let position = tampered.len() - i;
+            tampered[position] ^= 1;
+            assert_eq!(
+                Error::Signature,
+                server.unprotect_request(&tampered).unwrap_err()
+            );
+        }
+        // And so is a member signing with someone else's key
+        let mut impostor = GroupContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            GID.to_vec(),
+            SERVER_2.to_vec(),
+            &keypair(4),
+        )
+        .unwrap();
+        let forged = impostor.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert_eq!(
+            Error::Signature,
+            server.unprotect_request(&forged).unwrap_err()
+        );
+
+        // The failures weren't remembered
+        assert!(server.unprotect_request(&req).is_ok());
+    }
+
+    #[test]
+    fn pairwise_mode() {
+        let mut client = member(&CLIENT, 1);
+        let mut server_1 = member(&SERVER_1, 2);
+        let mut server_2 = member(&SERVER_2, 3);
+        // Without the X25519 keys, only group mode is possible
+        assert_eq!(
+            Error::UnsupportedMode,
+            client
+                .protect_pairwise_request(&REQ_UNPROTECTED, &SERVER_1)
+                .unwrap_err()
+        );
+        for (context, seed) in
+            &mut [(&mut client, 1), (&mut server_1, 2), (&mut server_2, 3)]
+        {
+            context.set_ecdh_secret([*seed; 32]).unwrap();
+            for (other, other_seed) in
+                [(CLIENT, 1), (SERVER_1, 2), (SERVER_2, 3)].iter()
+            {
+                if &other[..] != context.get_sender_id() {
+                    context
+                        .set_recipient_ecdh_key(
+                            other,
+                            ecdh_public(*other_seed),
+                        )
+                        .unwrap();
+                }
+            }
+        }
+
+        let req = client
+            .protect_pairwise_request(&REQ_UNPROTECTED, &SERVER_1)
+            .unwrap();
+        // It's shorter, since there's no signature
+        let group_req = member(&CLIENT, 1).protect_request(&REQ_UNPROTECTED);
+        assert_eq!(group_req.unwrap().len() - SIGNATURE_LEN, req.len());
+        // Only the member it's meant for can unprotect it
+        assert_eq!(Error::Aead, server_2.unprotect_request(&req).unwrap_err());
+        assert_eq!(
+            &REQ_UNPROTECTED[..],
+            &server_1.unprotect_request(&req).unwrap()[..]
+        );
+        assert_eq!(
+            Error::ReplayDetected,
+            server_1.unprotect_request(&req).unwrap_err()
+        );
+        // The response is in pairwise mode as well
+        let res = server_1.protect_response(&RES_UNPROTECTED, &req).unwrap();
+        assert!(!has_group_flag(&Packet::from_bytes(&res).unwrap()).unwrap());
+        assert_eq!(
+            &RES_UNPROTECTED[..],
+            &client.unprotect_response(&res, &req).unwrap()[..]
+        );
+        assert_eq!(
+            Error::ReplayDetected,
+            client.unprotect_response(&res, &req).unwrap_err()
+        );
+
+        // The next request can be in group mode again, sharing the replay
+        // window with pairwise mode
+        let req = client.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert_eq!(
+            &REQ_UNPROTECTED[..],
+            &server_1.unprotect_request(&req).unwrap()[..]
+        );
+        let res = server_1.protect_response(&RES_UNPROTECTED, &req).unwrap();
+        assert!(has_group_flag(&Packet::from_bytes(&res).unwrap()).unwrap());
+        assert_eq!(
+            &RES_UNPROTECTED[..],
+            &client.unprotect_response(&res, &req).unwrap()[..]
+        );
+        assert_eq!(
+            Error::UnknownContext,
+            client.set_recipient_ecdh_key(&[0x01], [0; 32]).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn membership() {
+        let mut client = member(&CLIENT, 1);
+        let mut server = member(&SERVER_1, 2);
+        assert_eq!(&GID, server.get_gid());
+        assert_eq!(&SERVER_1, server.get_sender_id());
+        assert_eq!(
+            Error::DuplicateContext,
+            server
+                .add_recipient(CLIENT.to_vec(), &keypair(1)[32..])
+                .unwrap_err()
+        );
+        assert_eq!(
+            Error::DuplicateContext,
+            server
+                .add_recipient(SERVER_1.to_vec(), &keypair(2)[32..])
+                .unwrap_err()
+        );
+        assert_eq!(
+            Error::Signature,
+            server.add_recipient(vec![0x01], &[0x00; 3]).unwrap_err()
+        );
+
+        // Messages from removed members are refused
+        let req = client.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert!(server.remove_recipient(&CLIENT));
+        assert!(!server.remove_recipient(&CLIENT));
+        assert_eq!(
+            Error::UnknownContext,
+            server.unprotect_request(&req).unwrap_err()
+        );
+
+        // As are those of a different group
+        let mut stranger = GroupContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            vec![0x01],
+            CLIENT.to_vec(),
+            &keypair(1),
+        )
+        .unwrap();
+        let req = stranger.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert_eq!(
+            Error::IdContextMismatch,
+            server.unprotect_request(&req).unwrap_err()
+        );
+
+        // And those that aren't in group mode
+        let mut one_to_one = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            Some(GID.to_vec()),
+            SERVER_2.to_vec(),
+            SERVER_1.to_vec(),
+        )
+        .unwrap();
+        let (req, _) = one_to_one.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert_eq!(
+            Error::UnsupportedMode,
+            server.unprotect_request(&req).unwrap_err()
+        );
+    }
+}
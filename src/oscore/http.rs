@@ -0,0 +1,629 @@
+//! Mapping OSCORE messages to and from HTTP (RFC 8613 Section 11).
+//!
+//! OSCORE protected messages can travel through an HTTP-CoAP cross proxy
+//! (RFC 8075). Over HTTP, the OSCORE option is carried base64url encoded in
+//! the `OSCORE` header field and the ciphertext is the body, with the content
+//! type `application/oscore`.
+//!
+//! An HTTP client can also protect its requests end-to-end. It maps the HTTP
+//! request to CoAP with `coap_request`, protects it with `protect_request`
+//! and sends it as HTTP again with `encode_request`. The way back goes
+//! through `decode_response`, `unprotect_response` and `http_response`.
+//!
+//! Since this doesn't depend on an HTTP library, messages are represented by
+//! `HttpRequest` and `HttpResponse`, which hold what the mapping needs.
+
+use alloc::{
+    collections::LinkedList,
+    string::{String, ToString},
+    vec::Vec,
+};
+use coap_lite::{CoapOption, MessageClass, Packet, RequestType, ResponseType};
+use core::convert::TryFrom;
+
+use super::{error::Error, transport, util::ProxyUri, Result};
+
+/// The name of the header field carrying the OSCORE option.
+pub const OSCORE_HEADER: &str = "OSCORE";
+/// The OSCORE header field carrying the empty option, which is 0x00.
+const EMPTY_OPTION_HEADER: &str = "AA";
+/// The name of the header field with the content type.
+pub const CONTENT_TYPE_HEADER: &str = "Content-Type";
+/// The content type of OSCORE protected messages.
+pub const OSCORE_CONTENT_TYPE: &str = "application/oscore";
+
+/// The content types we can map to a CoAP Content-Format and back.
+static CONTENT_FORMATS: [(&str, u16); 7] = [
+    ("text/plain; charset=utf-8", 0),
+    ("application/link-format", 40),
+    ("application/xml", 41),
+    ("application/octet-stream", 42),
+    ("application/exi", 47),
+    ("application/json", 50),
+    ("application/cbor", 60),
+];
+
+/// The base64url alphabet (RFC 4648 Section 5).
+static BASE64URL: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// An HTTP request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpRequest {
+    /// The method, like `GET`.
+    pub method: String,
+    /// The absolute URI of the target, or just a path for the server
+    /// itself.
+    pub uri: String,
+    /// The header fields as (name, value) pairs.
+    pub headers: Vec<(String, String)>,
+    /// The body.
+    pub body: Vec<u8>,
+}
+
+/// An HTTP response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpResponse {
+    /// The status code, like 200.
+    pub status: u16,
+    /// The header fields as (name, value) pairs.
+    pub headers: Vec<(String, String)>,
+    /// The body.
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Returns the value of the header field, if present.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+impl HttpResponse {
+    /// Returns the value of the header field, if present.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+/// Returns the CoAP request for a plain HTTP request, which can then be
+/// protected with `protect_request`.
+///
+/// The URI becomes the Proxy-Uri option, which is split up into its public
+/// part and the protected path and query during protection.
+///
+/// # Arguments
+/// * `request` - The HTTP request.
+pub fn coap_request(request: &HttpRequest) -> Result<Vec<u8>> {
+    let mut packet = Packet::new();
+    packet.header.code = MessageClass::Request(match &request.method[..] {
+        "GET" => RequestType::Get,
+        "POST" => RequestType::Post,
+        "PUT" => RequestType::Put,
+        "DELETE" => RequestType::Delete,
+        _ => return Err(Error::Http),
+    });
+    // Make sure we'll be able to split it up
+    ProxyUri::try_from(request.uri.as_bytes())?;
+    packet.add_option(CoapOption::ProxyUri, request.uri.as_bytes().to_vec());
+    if let Some(content_type) = request.header(CONTENT_TYPE_HEADER) {
+        let content_format = CONTENT_FORMATS
+            .iter()
+            .find(|(name, _)| same_content_type(name, content_type))
+            .ok_or(Error::Http)?
+            .1;
+        packet.add_option(
+            CoapOption::ContentFormat,
+            encode_uint(content_format),
+        );
+    }
+    packet.payload = request.body.clone();
+
+    Ok(packet.to_bytes()?)
+}
+
+/// Returns the plain HTTP response for a CoAP response, after it's been
+/// unprotected with `unprotect_response`.
+///
+/// # Arguments
+/// * `coap_msg` - The CoAP response.
+pub fn http_response(coap_msg: &[u8]) -> Result<HttpResponse> {
+    let packet = Packet::from_bytes(coap_msg)?;
+    let mut headers = vec![];
+    if let Some(value) = packet
+        .get_option(CoapOption::ContentFormat)
+        .and_then(|values| values.front())
+    {
+        let content_format = decode_uint(value)?;
+        let content_type = CONTENT_FORMATS
+            .iter()
+            .find(|(_, number)| u32::from(*number) == content_format)
+            .ok_or(Error::Http)?
+            .0;
+        headers
+            .push((CONTENT_TYPE_HEADER.to_string(), content_type.to_string()));
+    }
+
+    Ok(HttpResponse {
+        status: status_code(packet.header.code)?,
+        headers,
+        body: packet.payload,
+    })
+}
+
+/// Returns the HTTP request carrying the OSCORE protected CoAP request.
+///
+/// The target is taken from the outer Proxy-Uri, or from Uri-Host and
+/// Uri-Port if there is none. Other outer options have no equivalent in
+/// HTTP and are dropped.
+///
+/// # Arguments
+/// * `oscore_msg` - The OSCORE request.
+pub fn encode_request(oscore_msg: &[u8]) -> Result<HttpRequest> {
+    let packet = Packet::from_bytes(oscore_msg)?;
+    // Observations are FETCH, which coap_lite doesn't know
+    let method = match oscore_msg[1] {
+        transport::FETCH => "FETCH",
+        _ if packet.header.code
+            == MessageClass::Request(RequestType::Post) =>
+        {
+            "POST"
+        }
+        _ => return Err(Error::Http),
+    };
+    let option =
+        get_first(&packet, CoapOption::Oscore).ok_or(Error::NoOscoreOption)?;
+    let uri = if let Some(proxy_uri) = get_first(&packet, CoapOption::ProxyUri)
+    {
+        String::from_utf8(proxy_uri.clone())?
+    } else if let Some(host) = get_first(&packet, CoapOption::UriHost) {
+        let mut uri = String::from("coap://");
+        uri += &String::from_utf8(host.clone())?;
+        if let Some(port) = get_first(&packet, CoapOption::UriPort) {
+            uri += &format!(":{}", decode_uint(port)?);
+        }
+        uri
+    } else {
+        String::from("/")
+    };
+
+    Ok(HttpRequest {
+        method: String::from(method),
+        uri,
+        headers: oscore_headers(option, &packet.payload),
+        body: packet.payload,
+    })
+}
+
+/// Returns the OSCORE protected CoAP request carried by the HTTP request.
+///
+/// Only the scheme, host and port of an absolute URI end up in the outer
+/// Proxy-Uri, since the path and query are protected.
+///
+/// # Arguments
+/// * `request` - The HTTP request.
+pub fn decode_request(request: &HttpRequest) -> Result<Vec<u8>> {
+    let code = match &request.method[..] {
+        "POST" => MessageClass::Request(RequestType::Post).into(),
+        "FETCH" => transport::FETCH,
+        _ => return Err(Error::Http),
+    };
+    let mut packet = oscore_packet(&request.headers, &request.body)?;
+    if request.uri.contains("://") {
+        let proxy_uri = ProxyUri::try_from(request.uri.as_bytes())?;
+        let mut uri_list = LinkedList::new();
+        uri_list.push_back(proxy_uri.compose_proxy_uri());
+        packet.set_option(CoapOption::ProxyUri, uri_list);
+    }
+    let mut coap_msg = packet.to_bytes()?;
+    coap_msg[1] = code;
+
+    Ok(coap_msg)
+}
+
+/// Returns the HTTP response carrying the OSCORE protected CoAP response.
+///
+/// # Arguments
+/// * `oscore_msg` - The OSCORE response.
+pub fn encode_response(oscore_msg: &[u8]) -> Result<HttpResponse> {
+    let packet = Packet::from_bytes(oscore_msg)?;
+    let option =
+        get_first(&packet, CoapOption::Oscore).ok_or(Error::NoOscoreOption)?;
+
+    Ok(HttpResponse {
+        status: status_code(packet.header.code)?,
+        headers: oscore_headers(option, &packet.payload),
+        body: packet.payload,
+    })
+}
+
+/// Returns the OSCORE protected CoAP response carried by the HTTP response.
+///
+/// # Arguments
+/// * `response` - The HTTP response.
+pub fn decode_response(response: &HttpResponse) -> Result<Vec<u8>> {
+    // The outer code of a protected response is always 2.04 (Changed)
+    if response.status != 200 {
+        return Err(Error::Http);
+    }
+    let mut packet = oscore_packet(&response.headers, &response.body)?;
+    packet.header.code = MessageClass::Response(ResponseType::Changed);
+
+    Ok(packet.to_bytes()?)
+}
+
+/// Returns the header fields for the OSCORE option and ciphertext.
+fn oscore_headers(option: &[u8], body: &[u8]) -> Vec<(String, String)> {
+    // The header can't be empty, so the empty option is sent as 0x00 (RFC
+    // 8613 Section 11.1)
+    let option = if option.is_empty() {
+        EMPTY_OPTION_HEADER.to_string()
+    } else {
+        encode_base64url(option)
+    };
+    let mut headers = vec![(OSCORE_HEADER.to_string(), option)];
+    if !body.is_empty() {
+        headers.push((
+            CONTENT_TYPE_HEADER.to_string(),
+            OSCORE_CONTENT_TYPE.to_string(),
+        ));
+    }
+
+    headers
+}
+
+/// Returns the CoAP message with the OSCORE option and ciphertext from the
+/// header fields and body, still lacking the code.
+fn oscore_packet(headers: &[(String, String)], body: &[u8]) -> Result<Packet> {
+    let option =
+        find_header(headers, OSCORE_HEADER).ok_or(Error::NoOscoreOption)?;
+    if !body.is_empty()
+        && !find_header(headers, CONTENT_TYPE_HEADER)
+            .is_some_and(|value| same_content_type(OSCORE_CONTENT_TYPE, value))
+    {
+        return Err(Error::Http);
+    }
+
+    let option = match option {
+        EMPTY_OPTION_HEADER => vec![],
+        "" => return Err(Error::Http),
+        option => decode_base64url(option)?,
+    };
+
+    let mut packet = Packet::new();
+    packet.add_option(CoapOption::Oscore, option);
+    packet.payload = body.to_vec();
+
+    Ok(packet)
+}
+
+/// Returns the HTTP status code for the CoAP response code (RFC 8075
+/// Section 7).
+fn status_code(code: MessageClass) -> Result<u16> {
+    let response = match code {
+        MessageClass::Response(response) => response,
+        _ => return Err(Error::Http),
+    };
+
+    Ok(match response {
+        ResponseType::Created => 201,
+        ResponseType::Deleted | ResponseType::Changed => 200,
+        ResponseType::Content => 200,
+        ResponseType::Valid => 304,
+        ResponseType::BadRequest | ResponseType::BadOption => 400,
+        // Both 401 and 405 would require header fields we can't provide
+        ResponseType::Unauthorized | ResponseType::Forbidden => 403,
+        ResponseType::MethodNotAllowed => 400,
+        ResponseType::NotFound => 404,
+        ResponseType::NotAcceptable => 406,
+        ResponseType::PreconditionFailed => 412,
+        ResponseType::RequestEntityTooLarge => 413,
+        ResponseType::UnsupportedContentFormat => 415,
+        ResponseType::TooManyRequests => 429,
+        ResponseType::InternalServerError => 500,
+        ResponseType::NotImplemented => 501,
+        ResponseType::BadGateway | ResponseType::ProxyingNotSupported => 502,
+        ResponseType::ServiceUnavailable => 503,
+        ResponseType::GatewayTimeout => 504,
+        _ => return Err(Error::Http),
+    })
+}
+
+/// Returns the value of the header field, ignoring the case of its name.
+fn find_header<'a>(
+    headers: &'a [(String, String)],
+    name: &str,
+) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// Returns whether the content types are the same, ignoring the case and
+/// whitespace around the parameters.
+fn same_content_type(a: &str, b: &str) -> bool {
+    let mut a = a.split(';').map(str::trim);
+    let mut b = b.split(';').map(str::trim);
+    // The charset of text/plain is implied
+    loop {
+        match (a.next(), b.next()) {
+            (Some(x), Some(y)) if x.eq_ignore_ascii_case(y) => continue,
+            (None, None) | (Some("charset=utf-8"), None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Returns the value of the first option with this number, if present.
+fn get_first(packet: &Packet, option: CoapOption) -> Option<&Vec<u8>> {
+    packet.get_option(option).and_then(|values| values.front())
+}
+
+/// Returns the minimal encoding of an unsigned integer option value.
+fn encode_uint(value: u16) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let first_nonzero = bytes.iter().position(|&x| x != 0).unwrap_or(2);
+
+    bytes[first_nonzero..].to_vec()
+}
+
+/// Returns the unsigned integer of an option value.
+fn decode_uint(value: &[u8]) -> Result<u32> {
+    if value.len() > 4 {
+        return Err(Error::Http);
+    }
+
+    Ok(value
+        .iter()
+        .fold(0, |value, &byte| value << 8 | u32::from(byte)))
+}
+
+/// Returns the base64url encoding of the bytes, without padding.
+fn encode_base64url(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity((bytes.len() * 4).div_ceil(3));
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0, |bits, (i, &byte)| {
+            bits | u32::from(byte) << (16 - 8 * i)
+        });
+        // Each byte starts a new character, plus the one for the rest
+        for i in 0..=chunk.len() {
+            let index = (bits >> (18 - 6 * i)) & 0x3F;
+            encoded.push(char::from(BASE64URL[index as usize]));
+        }
+    }
+
+    encoded
+}
+
+/// Returns the bytes of a base64url encoding without padding.
+fn decode_base64url(encoded: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len() * 3 / 4);
+    for chunk in encoded.as_bytes().chunks(4) {
+        // A single character can't encode a whole byte
+        if chunk.len() == 1 {
+            return Err(Error::Http);
+        }
+        let mut bits = 0;
+        for (i, &c) in chunk.iter().enumerate() {
+            let index =
+                BASE64URL.iter().position(|&x| x == c).ok_or(Error::Http)?;
+            bits |= (index as u32) << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            bytes.push((bits >> (16 - 8 * i)) as u8);
+        }
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{test_vectors::*, RequestBinding, SecurityContext};
+    use super::*;
+
+    #[test]
+    fn base64url() {
+        for &(bytes, encoded) in [
+            (&[][..], ""),
+            (&[0x09, 0x14], "CRQ"),
+            (&[0x19, 0x14, 0x08], "GRQI"),
+            (&[0xFB, 0xFF, 0xBF, 0x01], "-_-_AQ"),
+        ]
+        .iter()
+        {
+            assert_eq!(encoded, encode_base64url(bytes));
+            assert_eq!(bytes, &decode_base64url(encoded).unwrap()[..]);
+        }
+        // Padding and the characters of regular base64 aren't allowed
+        assert!(decode_base64url("CRQ=").is_err());
+        assert!(decode_base64url("+/").is_err());
+        assert!(decode_base64url("GRQIA").is_err());
+    }
+
+    #[test]
+    fn oscore_messages() {
+        // Test vector 5 over HTTP
+        let request = encode_request(&REQ_PROTECTED).unwrap();
+        assert_eq!("POST", request.method);
+        assert_eq!("coap://localhost", request.uri);
+        assert_eq!(Some("CRQ"), request.header("oscore"));
+        assert_eq!(
+            Some(OSCORE_CONTENT_TYPE),
+            request.header(CONTENT_TYPE_HEADER)
+        );
+        assert_eq!(&REQ_PROTECTED[22..], &request.body[..]);
+        // Back in CoAP, the server can unprotect it
+        let mut server_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+        let coap = decode_request(&request).unwrap();
+        let unprotected = server_context.unprotect_request(&coap).unwrap();
+        let unprotected = Packet::from_bytes(&unprotected).unwrap();
+        assert_eq!(
+            MessageClass::Request(RequestType::Get),
+            unprotected.header.code
+        );
+        assert_eq!(
+            &b"tv1"[..],
+            &get_first(&unprotected, CoapOption::UriPath).unwrap()[..]
+        );
+
+        // Test vector 7 with the empty OSCORE option
+        let response = encode_response(&RES_PROTECTED).unwrap();
+        assert_eq!(200, response.status);
+        assert_eq!(Some("AA"), response.header(OSCORE_HEADER));
+        let coap = decode_response(&response).unwrap();
+        assert_eq!(&RES_PROTECTED[8..], &coap[4..]);
+        // Which is never sent as an empty header
+        let mut empty = response.clone();
+        empty.headers[0].1.clear();
+        assert_eq!(Error::Http, decode_response(&empty).unwrap_err());
+
+        // Unprotected messages are refused
+        assert_eq!(Error::Http, encode_request(&REQ_UNPROTECTED).unwrap_err());
+        assert_eq!(
+            Error::NoOscoreOption,
+            encode_response(&RES_UNPROTECTED).unwrap_err()
+        );
+        let mut plain = request.clone();
+        plain.headers.retain(|(name, _)| name != OSCORE_HEADER);
+        assert_eq!(Error::NoOscoreOption, decode_request(&plain).unwrap_err());
+        let mut wrong_type = request.clone();
+        wrong_type.headers[1].1 = String::from("application/cbor");
+        assert_eq!(Error::Http, decode_request(&wrong_type).unwrap_err());
+        let mut wrong_method = request.clone();
+        wrong_method.method = String::from("GET");
+        assert_eq!(Error::Http, decode_request(&wrong_method).unwrap_err());
+
+        // Observations are FETCH
+        let mut fetch = REQ_PROTECTED;
+        fetch[1] = transport::FETCH;
+        let request = encode_request(&fetch).unwrap();
+        assert_eq!("FETCH", request.method);
+        assert_eq!(transport::FETCH, decode_request(&request).unwrap()[1]);
+    }
+
+    #[test]
+    fn end_to_end() {
+        let mut client_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+        let mut server_context = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+
+        // The HTTP client protects its request
+        let request = HttpRequest {
+            method: String::from("PUT"),
+            uri: String::from("coap://sensor.example:5684/config?unit=c"),
+            headers: vec![(
+                String::from("content-type"),
+                String::from("application/json"),
+            )],
+            body: b"{\"interval\":60}".to_vec(),
+        };
+        let (protected, mut binding): (Vec<u8>, RequestBinding) =
+            client_context
+                .protect_request(&coap_request(&request).unwrap())
+                .unwrap();
+        let http_request = encode_request(&protected).unwrap();
+        // Only the public part of the URI is visible
+        assert_eq!("coap://sensor.example:5684", http_request.uri);
+        assert_eq!("POST", http_request.method);
+
+        // The cross proxy forwards it to the CoAP server
+        let coap = decode_request(&http_request).unwrap();
+        let unprotected = Packet::from_bytes(
+            &server_context.unprotect_request(&coap).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            MessageClass::Request(RequestType::Put),
+            unprotected.header.code
+        );
+        assert_eq!(
+            &b"config"[..],
+            &get_first(&unprotected, CoapOption::UriPath).unwrap()[..]
+        );
+        assert_eq!(
+            &b"unit=c"[..],
+            &get_first(&unprotected, CoapOption::UriQuery).unwrap()[..]
+        );
+        assert_eq!(
+            &[50][..],
+            &get_first(&unprotected, CoapOption::ContentFormat).unwrap()[..]
+        );
+        assert_eq!(request.body, unprotected.payload);
+
+        // And the response makes its way back
+        let mut response = Packet::new();
+        response.header.code = MessageClass::Response(ResponseType::Changed);
+        response.add_option(CoapOption::ContentFormat, vec![]);
+        response.payload = b"ok".to_vec();
+        let response = server_context
+            .protect_response(&response.to_bytes().unwrap(), &coap, true)
+            .unwrap();
+        let protected_response = encode_response(&response).unwrap();
+        let response = client_context
+            .unprotect_response(
+                &decode_response(&protected_response).unwrap(),
+                &mut binding,
+            )
+            .unwrap();
+        let http_response = http_response(&response).unwrap();
+        assert_eq!(200, http_response.status);
+        assert_eq!(
+            Some("text/plain; charset=utf-8"),
+            http_response.header(CONTENT_TYPE_HEADER)
+        );
+        assert_eq!(&b"ok"[..], &http_response.body[..]);
+
+        // Methods and content types without a mapping are refused
+        let mut patch = request.clone();
+        patch.method = String::from("PATCH");
+        assert_eq!(Error::Http, coap_request(&patch).unwrap_err());
+        let mut html = request;
+        html.headers[0].1 = String::from("text/html");
+        assert_eq!(Error::Http, coap_request(&html).unwrap_err());
+    }
+
+    #[test]
+    fn status_codes() {
+        assert_eq!(
+            201,
+            status_code(MessageClass::Response(ResponseType::Created))
+                .unwrap()
+        );
+        assert_eq!(
+            403,
+            status_code(MessageClass::Response(ResponseType::Unauthorized))
+                .unwrap()
+        );
+        assert_eq!(
+            504,
+            status_code(MessageClass::Response(ResponseType::GatewayTimeout))
+                .unwrap()
+        );
+        assert_eq!(
+            Error::Http,
+            status_code(MessageClass::Request(RequestType::Get)).unwrap_err()
+        );
+    }
+}
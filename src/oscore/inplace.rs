@@ -0,0 +1,535 @@
+//! Building blocks for protecting and unprotecting messages without heap
+//! allocations.
+//!
+//! Instead of parsing messages into a `Packet`, they are read directly from
+//! their byte representation and written into a caller-supplied buffer.
+
+use coap_lite::error::MessageError;
+
+use super::{error::Error, option::OscoreOptionRef, Result};
+
+/// The option number of the OSCORE option.
+pub const OSCORE: usize = 9;
+
+/// The size of the buffer the AAD is built in, which limits the length of
+/// the `kid` to a bit over 200 bytes.
+pub const MAX_AAD_LEN: usize = 256;
+
+/// The length of the longest nonce of the supported AEAD algorithms.
+pub const MAX_NONCE_LEN: usize = 13;
+
+/// The payload marker.
+const PAYLOAD_MARKER: u8 = 0xFF;
+
+/// The parts of a CoAP message.
+pub struct Message<'a> {
+    /// The fixed header followed by the token.
+    pub header: &'a [u8],
+    /// The encoded options.
+    pub options: &'a [u8],
+    /// The payload, without the payload marker.
+    pub payload: &'a [u8],
+}
+
+impl<'a> Message<'a> {
+    /// Splits the CoAP message into its parts.
+    pub fn parse(msg: &'a [u8]) -> Result<Message<'a>> {
+        if msg.len() < 4 {
+            return Err(MessageError::InvalidPacketLength.into());
+        }
+        let token_length = (msg[0] & 0x0F) as usize;
+        if token_length > 8 || 4 + token_length > msg.len() {
+            return Err(MessageError::InvalidTokenLength.into());
+        }
+        let (header, rest) = msg.split_at(4 + token_length);
+
+        // Skip over the options to find the payload marker
+        let mut position = 0;
+        let mut number = 0;
+        while let Some((n, _, end)) = read_option(rest, position, number)? {
+            number = n;
+            position = end;
+        }
+        let (options, payload) = rest.split_at(position);
+        // Drop the payload marker
+        let payload = payload.get(1..).unwrap_or(&[]);
+
+        Ok(Message {
+            header,
+            options,
+            payload,
+        })
+    }
+
+    /// Returns the options as `(number, value)` pairs.
+    pub fn options(&self) -> Options<'a> {
+        Options {
+            data: self.options,
+            position: 0,
+            number: 0,
+        }
+    }
+
+    /// Returns the value of the first option with this number, if present.
+    pub fn option(&self, number: usize) -> Result<Option<&'a [u8]>> {
+        for option in self.options() {
+            let (n, value) = option?;
+            if n == number {
+                return Ok(Some(value));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// An iterator over encoded options.
+pub struct Options<'a> {
+    data: &'a [u8],
+    position: usize,
+    number: usize,
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = Result<(usize, &'a [u8])>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match read_option(self.data, self.position, self.number) {
+            Ok(Some((number, start, end))) => {
+                self.number = number;
+                self.position = end;
+                Some(Ok((number, &self.data[start..end])))
+            }
+            Ok(None) => None,
+            Err(e) => {
+                // Don't keep going after a malformed option
+                self.position = self.data.len();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Reads the option starting at this position, returning its number and the
+/// start and end position of its value.
+///
+/// Returns `None` at the end of the data or at the payload marker.
+///
+/// # Arguments
+/// * `data` - The encoded options, possibly followed by the payload.
+/// * `position` - The position of the option.
+/// * `number` - The number of the previous option, or 0 for the first.
+pub fn read_option(
+    data: &[u8],
+    mut position: usize,
+    number: usize,
+) -> Result<Option<(usize, usize, usize)>> {
+    let byte = match data.get(position) {
+        None | Some(&PAYLOAD_MARKER) => return Ok(None),
+        Some(&byte) => byte,
+    };
+    position += 1;
+
+    let delta = read_extended(data, &mut position, byte >> 4)
+        .ok_or(MessageError::InvalidOptionDelta)?;
+    let length = read_extended(data, &mut position, byte & 0x0F)
+        .ok_or(MessageError::InvalidOptionLength)?;
+    if position + length > data.len() {
+        return Err(MessageError::InvalidOptionLength.into());
+    }
+
+    Ok(Some((number + delta, position, position + length)))
+}
+
+/// Returns the option delta or length encoded in the nibble, advancing the
+/// position over any extended bytes.
+fn read_extended(
+    data: &[u8],
+    position: &mut usize,
+    nibble: u8,
+) -> Option<usize> {
+    match nibble {
+        13 => {
+            let value = *data.get(*position)? as usize + 13;
+            *position += 1;
+            Some(value)
+        }
+        14 => {
+            let bytes = data.get(*position..*position + 2)?;
+            *position += 2;
+            Some(u16::from_be_bytes([bytes[0], bytes[1]]) as usize + 269)
+        }
+        15 => None,
+        n => Some(n as usize),
+    }
+}
+
+/// Returns the length of an option's header with this delta and length.
+pub fn option_header_len(delta: usize, length: usize) -> usize {
+    1 + extended_len(delta) + extended_len(length)
+}
+
+/// Returns the number of extended bytes for an option delta or length.
+fn extended_len(value: usize) -> usize {
+    match value {
+        0..=12 => 0,
+        13..=268 => 1,
+        _ => 2,
+    }
+}
+
+/// Writes into a buffer, failing once it's full.
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    position: usize,
+    /// The number of the last option written.
+    number: usize,
+}
+
+impl<'a> Writer<'a> {
+    /// Creates a `Writer` starting at this position of the buffer.
+    pub fn new(buf: &'a mut [u8], position: usize) -> Writer<'a> {
+        Writer {
+            buf,
+            position,
+            number: 0,
+        }
+    }
+
+    /// Returns the position that will be written next.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Returns the whole buffer, including what hasn't been written yet.
+    pub fn buffer(&self) -> &[u8] {
+        self.buf
+    }
+
+    /// Starts a new list of options, which will be delta encoded from 0.
+    pub fn restart_options(&mut self) {
+        self.number = 0;
+    }
+
+    /// Appends the bytes.
+    pub fn push(&mut self, bytes: &[u8]) -> Result<()> {
+        self.reserve(bytes.len())?.copy_from_slice(bytes);
+
+        Ok(())
+    }
+
+    /// Appends a single byte.
+    pub fn push_byte(&mut self, byte: u8) -> Result<()> {
+        self.push(&[byte])
+    }
+
+    /// Returns the next `len` bytes of the buffer to write into, which count
+    /// as written.
+    pub fn reserve(&mut self, len: usize) -> Result<&mut [u8]> {
+        let end = self.position + len;
+        if end > self.buf.len() {
+            return Err(Error::BufferTooSmall);
+        }
+        let reserved = &mut self.buf[self.position..end];
+        self.position = end;
+
+        Ok(reserved)
+    }
+
+    /// Appends an option, which can't have a lower number than the previous
+    /// one.
+    pub fn option(&mut self, number: usize, value: &[u8]) -> Result<()> {
+        self.option_header(number, value.len())?;
+        self.push(value)
+    }
+
+    /// Appends an option with the value found at this range of the buffer,
+    /// which must not be before the position.
+    pub fn option_within(
+        &mut self,
+        number: usize,
+        start: usize,
+        end: usize,
+    ) -> Result<()> {
+        self.option_header(number, end - start)?;
+        self.copy_within(start, end)
+    }
+
+    /// Appends the bytes found at this range of the buffer, which must not
+    /// be before the position.
+    pub fn copy_within(&mut self, start: usize, end: usize) -> Result<()> {
+        let position = self.position;
+        self.reserve(end - start)?;
+        self.buf.copy_within(start..end, position);
+
+        Ok(())
+    }
+
+    /// Appends the header of an option.
+    fn option_header(&mut self, number: usize, length: usize) -> Result<()> {
+        let delta = number - self.number;
+        self.number = number;
+
+        let header = self.reserve(option_header_len(delta, length))?;
+        header[0] = (nibble(delta) << 4) | nibble(length);
+        let position = write_extended(header, 1, delta);
+        write_extended(header, position, length);
+
+        Ok(())
+    }
+}
+
+/// Returns the nibble encoding an option delta or length.
+fn nibble(value: usize) -> u8 {
+    match value {
+        0..=12 => value as u8,
+        13..=268 => 13,
+        _ => 14,
+    }
+}
+
+/// Writes the extended bytes of an option delta or length at this position,
+/// returning the position after them.
+fn write_extended(header: &mut [u8], position: usize, value: usize) -> usize {
+    match value {
+        0..=12 => position,
+        13..=268 => {
+            header[position] = (value - 13) as u8;
+            position + 1
+        }
+        _ => {
+            header[position..position + 2]
+                .copy_from_slice(&((value - 269) as u16).to_be_bytes());
+            position + 2
+        }
+    }
+}
+
+/// Writes the OSCORE option, encoded by `OscoreOptionRef`.
+pub fn write_oscore_option(
+    writer: &mut Writer,
+    option: &OscoreOptionRef,
+) -> Result<()> {
+    let length = option.encoded_len()?;
+    writer.option_header(OSCORE, length)?;
+    option.encode_into(writer.reserve(length)?)?;
+
+    Ok(())
+}
+
+/// Writes the AAD into the buffer, returning its length.
+///
+/// This is the same encoding as `util::build_aad`.
+pub fn build_aad(
+    alg_aead: i32,
+    request_kid: &[u8],
+    request_piv: &[u8],
+    buf: &mut [u8; MAX_AAD_LEN],
+) -> Result<usize> {
+    // The length of the AAD array, which is needed upfront
+    let aad_arr_len = 1
+        + 1
+        + 1
+        + int_len(alg_aead)
+        + head_len(request_kid.len() as u64)
+        + request_kid.len()
+        + head_len(request_piv.len() as u64)
+        + request_piv.len()
+        + 1;
+
+    let mut writer = Writer::new(buf, 0);
+    // ("Encrypt0", h'', aad_arr)
+    write_head(&mut writer, 4, 3)?;
+    write_head(&mut writer, 3, 8)?;
+    writer.push(b"Encrypt0")?;
+    write_head(&mut writer, 2, 0)?;
+    write_head(&mut writer, 2, aad_arr_len as u64)?;
+    // (oscore_version, algorithms, request_kid, request_piv, options)
+    write_head(&mut writer, 4, 5)?;
+    write_head(&mut writer, 0, 1)?;
+    write_head(&mut writer, 4, 1)?;
+    write_int(&mut writer, alg_aead)?;
+    write_head(&mut writer, 2, request_kid.len() as u64)?;
+    writer.push(request_kid)?;
+    write_head(&mut writer, 2, request_piv.len() as u64)?;
+    writer.push(request_piv)?;
+    write_head(&mut writer, 2, 0)?;
+
+    Ok(writer.position())
+}
+
+/// Returns the length of a CBOR head with this value.
+fn head_len(value: u64) -> usize {
+    match value {
+        0..=23 => 1,
+        24..=0xFF => 2,
+        0x100..=0xFFFF => 3,
+        0x1_0000..=0xFFFF_FFFF => 5,
+        _ => 9,
+    }
+}
+
+/// Returns the length of a CBOR integer.
+fn int_len(value: i32) -> usize {
+    if value < 0 {
+        head_len((-1 - value as i64) as u64)
+    } else {
+        head_len(value as u64)
+    }
+}
+
+/// Writes a CBOR head with this major type and value.
+fn write_head(writer: &mut Writer, major: u8, value: u64) -> Result<()> {
+    let major = major << 5;
+    match head_len(value) {
+        1 => writer.push_byte(major | value as u8),
+        2 => writer.push(&[major | 24, value as u8]),
+        3 => {
+            writer.push_byte(major | 25)?;
+            writer.push(&(value as u16).to_be_bytes())
+        }
+        5 => {
+            writer.push_byte(major | 26)?;
+            writer.push(&(value as u32).to_be_bytes())
+        }
+        _ => {
+            writer.push_byte(major | 27)?;
+            writer.push(&value.to_be_bytes())
+        }
+    }
+}
+
+/// Writes a CBOR integer.
+fn write_int(writer: &mut Writer, value: i32) -> Result<()> {
+    if value < 0 {
+        write_head(writer, 1, (-1 - value as i64) as u64)
+    } else {
+        write_head(writer, 0, value as u64)
+    }
+}
+
+/// Returns the `piv` in its correct format (no leading zero bytes), written
+/// into the buffer.
+///
+/// This is the same as `util::format_piv`.
+pub fn format_piv(piv: u64, buf: &mut [u8; 8]) -> &[u8] {
+    *buf = piv.to_be_bytes();
+    // Keep at least one byte, for a piv of 0
+    let first_nonzero = buf.iter().position(|&x| x != 0).unwrap_or(7);
+
+    &buf[first_nonzero..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{test_vectors::*, util};
+    use super::*;
+
+    #[test]
+    fn message() {
+        let msg = Message::parse(&REQ_UNPROTECTED).unwrap();
+        assert_eq!(&REQ_UNPROTECTED[..8], msg.header);
+        assert!(msg.payload.is_empty());
+        let options: Vec<(usize, &[u8])> =
+            msg.options().map(Result::unwrap).collect();
+        assert_eq!(vec![(3, &b"localhost"[..]), (11, &b"tv1"[..])], options);
+        assert_eq!(Some(&b"tv1"[..]), msg.option(11).unwrap());
+        assert_eq!(None, msg.option(12).unwrap());
+
+        let msg = Message::parse(&RES_UNPROTECTED).unwrap();
+        assert_eq!(&b"Hello World!"[..], msg.payload);
+
+        // Malformed messages are refused
+        assert!(Message::parse(&REQ_UNPROTECTED[..3]).is_err());
+        assert!(Message::parse(&REQ_UNPROTECTED[..10]).is_err());
+        assert!(Message::parse(&[0x49, 0x01, 0x00, 0x00]).is_err());
+        assert!(Message::parse(&[0x40, 0x01, 0x00, 0x00, 0xF0]).is_err());
+    }
+
+    #[test]
+    fn writer() {
+        // Every size of option delta and length is encoded like coap_lite
+        for &(number, length) in [
+            (1, 0),
+            (13, 12),
+            (14, 13),
+            (268, 268),
+            (300, 269),
+            (1000, 999),
+        ]
+        .iter()
+        {
+            let mut packet = coap_lite::Packet::new();
+            let value = vec![0xAB; length];
+            packet.add_option(number.into(), value.clone());
+            let expected = packet.to_bytes().unwrap();
+
+            let mut buf = [0; 1100];
+            let mut writer = Writer::new(&mut buf, 0);
+            writer.push(&expected[..4]).unwrap();
+            writer.option(number, &value).unwrap();
+            let len = writer.position();
+            assert_eq!(&expected[..], &buf[..len]);
+            let msg = Message::parse(&buf[..len]).unwrap();
+            assert_eq!(Some(&value[..]), msg.option(number).unwrap());
+        }
+
+        // Running out of space is an error
+        let mut buf = [0; 3];
+        let mut writer = Writer::new(&mut buf, 1);
+        writer.push_byte(0x01).unwrap();
+        assert_eq!(
+            Error::BufferTooSmall,
+            writer.push(&[0x02; 2]).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn encoding() {
+        let mut buf = [0; MAX_AAD_LEN];
+        let len = build_aad(10, &EXAMPLE_KID, &EXAMPLE_PIV, &mut buf).unwrap();
+        assert_eq!(&EXAMPLE_AAD[..], &buf[..len]);
+        for &(alg, kid) in
+            [(30, &[0x01; 30][..]), (-8, &[0x02; 200][..])].iter()
+        {
+            let len = build_aad(alg, kid, &[0x05], &mut buf).unwrap();
+            assert_eq!(
+                &util::build_aad(alg, kid, &[0x05]).unwrap()[..],
+                &buf[..len]
+            );
+        }
+        assert_eq!(
+            Error::BufferTooSmall,
+            build_aad(10, &[0; 300], &[0x05], &mut buf).unwrap_err()
+        );
+
+        let mut piv = [0; 8];
+        for &n in [0, 1, 0xFF, 0x100, (1 << 40) - 1].iter() {
+            assert_eq!(&util::format_piv(n)[..], format_piv(n, &mut piv));
+        }
+
+        for &(kid, piv, kid_context, option) in [
+            (EX1_KID, EX1_PIV, None, &EX1_OPTION[..]),
+            (EX2_KID, EX2_PIV, None, &EX2_OPTION[..]),
+            (EX3_KID, EX3_PIV, EX3_KID_CONTEXT, &EX3_OPTION[..]),
+            (EX4_KID, EX4_PIV, None, &EX4_OPTION[..]),
+            (EX5_KID, EX5_PIV, None, &EX5_OPTION[..]),
+        ]
+        .iter()
+        {
+            let mut buf = [0; 16];
+            let mut writer = Writer::new(&mut buf, 0);
+            let value = OscoreOptionRef {
+                kid,
+                piv,
+                kid_context,
+                ..Default::default()
+            };
+            write_oscore_option(&mut writer, &value).unwrap();
+            let len = writer.position();
+            let (_, start, end) =
+                read_option(&buf[..len], 0, 0).unwrap().unwrap();
+            assert_eq!(option, &buf[start..end]);
+        }
+    }
+}
@@ -0,0 +1,477 @@
+//! Key update for OSCORE (KUDOS).
+//!
+//! This renews the keys of a security context without another key exchange,
+//! in a single request/response exchange. Both parties contribute a nonce,
+//! which is carried in the OSCORE option together with a byte `x` encoding
+//! its length, and new contexts are derived from the current one.
+//!
+//! 1. The client derives an intermediate context from its `x` and nonce and
+//!    protects a request with it, carrying them (`KeyUpdate`).
+//! 2. The server derives the same intermediate context to verify the request
+//!    and the new context from both `x` and nonces, with which it protects
+//!    the response carrying its own (`Responder`).
+//! 3. The client verifies the response with the new context, which replaces
+//!    the old one.
+//! 4. The server keeps accepting requests with the old context until the
+//!    first one protected with the new context arrives (`Transition`).
+//!
+//! The intermediate context continues the sender sequence number and replay
+//! window of the current one, so a replay of the request carrying the update
+//! is detected. They start over in the new context.
+
+use alloc::{boxed::Box, vec::Vec};
+
+use super::{
+    error::Error,
+    transport::{self, Transport},
+    util, RequestBinding, Result, SecurityContext,
+};
+
+/// The maximum length of a nonce, since its length is encoded in 4 bits.
+pub const MAX_NONCE_LEN: usize = 16;
+
+/// The client side of a key update.
+pub struct KeyUpdate {
+    /// The intermediate context used for the request.
+    intermediate: SecurityContext,
+    x: u8,
+    nonce: Vec<u8>,
+    /// The binding of the request, once it's been protected.
+    request: Option<RequestBinding>,
+}
+
+impl KeyUpdate {
+    /// Starts a key update of the context.
+    ///
+    /// # Arguments
+    /// * `context` - The current security context, whose next sequence
+    ///   number is used for the request.
+    /// * `nonce` - A random nonce of 1 to `MAX_NONCE_LEN` bytes, which must
+    ///   not be reused.
+    pub fn new(
+        context: &mut SecurityContext,
+        nonce: Vec<u8>,
+    ) -> Result<KeyUpdate> {
+        let x = encode_x(&nonce)?;
+        let intermediate = context.update_intermediate(&[x], &nonce)?;
+        context.skip_sequence_number()?;
+
+        Ok(KeyUpdate {
+            intermediate,
+            x,
+            nonce,
+            request: None,
+        })
+    }
+
+    /// Returns an OSCORE message based on the original CoAP request, which
+    /// carries the key update.
+    ///
+    /// # Arguments
+    /// * `coap_msg` - The original CoAP request to protect.
+    pub fn protect_request(&mut self, coap_msg: &[u8]) -> Result<Vec<u8>> {
+        let transport = self.intermediate.get_transport();
+        let request = self.intermediate.protect_request_kudos(
+            transport::decode(coap_msg, transport)?,
+            Some((self.x, &self.nonce)),
+        )?;
+        // Remember the request the response will be bound to
+        self.request = Some(RequestBinding::from_packet(&request)?);
+
+        transport::encode_oscore(&request, transport)
+    }
+
+    /// Returns the original CoAP response protected in the OSCORE message,
+    /// together with the new security context that replaces the current
+    /// one.
+    ///
+    /// # Arguments
+    /// * `context` - The current security context, as passed to `new`.
+    /// * `oscore_msg` - The OSCORE message protecting the CoAP response.
+    pub fn unprotect_response(
+        &mut self,
+        context: &SecurityContext,
+        oscore_msg: &[u8],
+    ) -> Result<(Vec<u8>, SecurityContext)> {
+        let request = self.request.as_mut().ok_or(Error::InvalidKeyUpdate)?;
+        let transport = context.get_transport();
+        // Get the server's contribution
+        let (x, nonce) = extract_kudos(oscore_msg, transport)?;
+
+        let mut new = context
+            .update(&[self.x, x], &[&self.nonce[..], &nonce].concat())?;
+        let response = new.unprotect_response_packet(
+            transport::decode(oscore_msg, transport)?,
+            request,
+        )?;
+
+        Ok((transport::encode(&response, transport)?, new))
+    }
+}
+
+/// The server side of a key update.
+pub struct Responder {
+    /// The new context used for the response.
+    new: SecurityContext,
+    x: u8,
+    nonce: Vec<u8>,
+}
+
+impl Responder {
+    /// Returns the original CoAP request carrying a key update, together
+    /// with the `Responder` to protect the response with.
+    ///
+    /// # Arguments
+    /// * `context` - The current security context, whose replay window the
+    ///   request is checked against.
+    /// * `oscore_msg` - The OSCORE message protecting the CoAP request.
+    /// * `nonce` - A random nonce of 1 to `MAX_NONCE_LEN` bytes, which must
+    ///   not be reused.
+    pub fn new(
+        context: &mut SecurityContext,
+        oscore_msg: &[u8],
+        nonce: Vec<u8>,
+    ) -> Result<(Vec<u8>, Responder)> {
+        let x = encode_x(&nonce)?;
+        // Get the client's contribution
+        let (client_x, client_nonce) =
+            extract_kudos(oscore_msg, context.get_transport())?;
+
+        // Verify the request with the intermediate context, then remember
+        // its partial IV in the current one
+        let mut intermediate =
+            context.update_intermediate(&[client_x], &client_nonce)?;
+        let request = intermediate.unprotect_request(oscore_msg)?;
+        context.adopt_replay_window(&intermediate);
+        let new = context
+            .update(&[client_x, x], &[&client_nonce[..], &nonce].concat())?;
+
+        Ok((request, Responder { new, x, nonce }))
+    }
+
+    /// Returns an OSCORE message based on the original CoAP response, which
+    /// completes the key update, together with the new security context.
+    ///
+    /// The current context has to be kept until the client has confirmed
+    /// that it has the new one as well, which `Transition` takes care of.
+    ///
+    /// # Arguments
+    /// * `coap_msg` - The original CoAP response to protect.
+    /// * `request` - The OSCORE request carrying the key update.
+    pub fn protect_response(
+        mut self,
+        coap_msg: &[u8],
+        request: &[u8],
+    ) -> Result<(Vec<u8>, SecurityContext)> {
+        let transport = self.new.get_transport();
+        let response = self.new.protect_response_kudos(
+            transport::decode(coap_msg, transport)?,
+            &transport::decode(request, transport)?,
+            false,
+            Some((self.x, &self.nonce)),
+        )?;
+
+        Ok((transport::encode(&response, transport)?, self.new))
+    }
+}
+
+/// Holds the old and the new context on the server, until the client has
+/// confirmed the key update.
+pub struct Transition {
+    /// The old context, until the update is confirmed.
+    old: Option<SecurityContext>,
+    new: SecurityContext,
+    /// Whether the last request was unprotected with the old context.
+    used_old: bool,
+}
+
+impl Transition {
+    /// Creates a new `Transition` from the old to the new context.
+    pub fn new(old: SecurityContext, new: SecurityContext) -> Transition {
+        Transition {
+            old: Some(old),
+            new,
+            used_old: false,
+        }
+    }
+
+    /// Returns the original CoAP request protected in the OSCORE message.
+    ///
+    /// The new context is tried first. Once it succeeds, the update is
+    /// confirmed and the old context is dropped. Until then, the old one is
+    /// tried as well.
+    ///
+    /// # Arguments
+    /// * `oscore_msg` - The OSCORE message protecting the CoAP request.
+    pub fn unprotect_request(&mut self, oscore_msg: &[u8]) -> Result<Vec<u8>> {
+        match self.new.unprotect_request(oscore_msg) {
+            Ok(request) => {
+                self.old = None;
+                self.used_old = false;
+                Ok(request)
+            }
+            Err(e) => match &mut self.old {
+                Some(old) => {
+                    let request = old.unprotect_request(oscore_msg)?;
+                    self.used_old = true;
+                    Ok(request)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Returns an OSCORE message based on the original CoAP response, using
+    /// the context the request was unprotected with.
+    ///
+    /// # Arguments
+    /// * `coap_msg` - The original CoAP response to protect.
+    /// * `request` - The OSCORE request to which to respond.
+    /// * `reuse_piv` - Whether the request's `piv` should be reused.
+    pub fn protect_response(
+        &mut self,
+        coap_msg: &[u8],
+        request: &[u8],
+        reuse_piv: bool,
+    ) -> Result<Vec<u8>> {
+        match &mut self.old {
+            Some(old) if self.used_old => {
+                old.protect_response(coap_msg, request, reuse_piv)
+            }
+            _ => self.new.protect_response(coap_msg, request, reuse_piv),
+        }
+    }
+
+    /// Returns whether the client has used the new context.
+    pub fn is_confirmed(&self) -> bool {
+        self.old.is_none()
+    }
+
+    /// Returns the new context if the update is confirmed, or the
+    /// `Transition` itself otherwise.
+    pub fn into_context(
+        self,
+    ) -> core::result::Result<SecurityContext, Box<Self>> {
+        if self.is_confirmed() {
+            Ok(self.new)
+        } else {
+            Err(Box::new(self))
+        }
+    }
+}
+
+/// Returns whether the OSCORE message carries a key update.
+///
+/// # Arguments
+/// * `oscore_msg` - The OSCORE message.
+/// * `transport` - The transport the message is framed for.
+pub fn is_key_update(oscore_msg: &[u8], transport: Transport) -> Result<bool> {
+    let packet = transport::decode(oscore_msg, transport)?;
+
+    Ok(util::extract_kudos(&packet)?.is_some())
+}
+
+/// Returns the `x` and nonce of the key update in the OSCORE message.
+fn extract_kudos(
+    oscore_msg: &[u8],
+    transport: Transport,
+) -> Result<util::KudosValues> {
+    let packet = transport::decode(oscore_msg, transport)?;
+
+    util::extract_kudos(&packet)?.ok_or(Error::InvalidKeyUpdate)
+}
+
+/// Returns the `x` byte for the nonce, which encodes its length.
+fn encode_x(nonce: &[u8]) -> Result<u8> {
+    if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+        return Err(Error::InvalidKeyUpdate);
+    }
+
+    Ok(nonce.len() as u8 - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_vectors::*;
+    use super::*;
+    use coap_lite::Packet;
+
+    /// Returns the client and server contexts of test vector 1.
+    fn contexts() -> (SecurityContext, SecurityContext) {
+        let client = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            CLIENT_ID.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap();
+        let server = SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            None,
+            SERVER_ID.to_vec(),
+            CLIENT_ID.to_vec(),
+        )
+        .unwrap();
+
+        (client, server)
+    }
+
+    #[test]
+    fn key_update() {
+        let (mut client, mut server) = contexts();
+        // Use the old contexts for a while
+        for _ in 0..3 {
+            let (req, _) = client.protect_request(&REQ_UNPROTECTED).unwrap();
+            server.unprotect_request(&req).unwrap();
+        }
+
+        // The client starts the key update
+        let mut update = KeyUpdate::new(&mut client, vec![0x11; 8]).unwrap();
+        let req = update.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert!(is_key_update(&req, Transport::Udp).unwrap());
+        assert!(!is_key_update(&REQ_PROTECTED, Transport::Udp).unwrap());
+        // The old context can't read it
+        assert!(server.unprotect_request(&req).is_err());
+
+        // The server responds with its nonce
+        let (unprotected, responder) =
+            Responder::new(&mut server, &req, vec![0x22; 8]).unwrap();
+        assert_eq!(&REQ_UNPROTECTED[..], &unprotected[..]);
+        let (res, server_new) =
+            responder.protect_response(&RES_UNPROTECTED, &req).unwrap();
+        assert!(is_key_update(&res, Transport::Udp).unwrap());
+
+        // The client verifies the response with the new context
+        let (unprotected, mut client_new) =
+            update.unprotect_response(&client, &res).unwrap();
+        assert_eq!(&RES_UNPROTECTED[..], &unprotected[..]);
+
+        // Until the client uses the new context, the old one still works
+        let mut transition = Transition::new(server, server_new);
+        let (req, mut binding) =
+            client.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert!(transition.unprotect_request(&req).is_ok());
+        let res = transition
+            .protect_response(&RES_UNPROTECTED, &req, true)
+            .unwrap();
+        assert!(client.unprotect_response(&res, &mut binding).is_ok());
+        assert!(!transition.is_confirmed());
+        let mut transition = match transition.into_context() {
+            Ok(_) => panic!("The transition isn't confirmed yet"),
+            Err(transition) => *transition,
+        };
+
+        // The first request with the new context confirms it
+        let (req, mut binding) =
+            client_new.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert!(transition.unprotect_request(&req).is_ok());
+        assert!(transition.is_confirmed());
+        let res = transition
+            .protect_response(&RES_UNPROTECTED, &req, false)
+            .unwrap();
+        assert_eq!(
+            &RES_UNPROTECTED[..],
+            &client_new.unprotect_response(&res, &mut binding).unwrap()[..]
+        );
+
+        // After that, the old one is refused
+        let (req, _) = client.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert_eq!(
+            Error::Aead,
+            transition.unprotect_request(&req).unwrap_err()
+        );
+        assert!(transition.into_context().is_ok());
+    }
+
+    #[test]
+    fn fresh_keys() {
+        let (mut client, mut server) = contexts();
+        let (_, mut other_server) = contexts();
+        // Different nonces lead to different contexts
+        let mut update = KeyUpdate::new(&mut client, vec![0x11; 8]).unwrap();
+        let req = update.protect_request(&REQ_UNPROTECTED).unwrap();
+        let (_, responder) =
+            Responder::new(&mut server, &req, vec![0x22; 8]).unwrap();
+        let (res, _) =
+            responder.protect_response(&RES_UNPROTECTED, &req).unwrap();
+        let (_, responder) =
+            Responder::new(&mut other_server, &req, vec![0x33; 8]).unwrap();
+        let (other_res, _) =
+            responder.protect_response(&RES_UNPROTECTED, &req).unwrap();
+        assert_ne!(res, other_res);
+
+        // Tampering with the nonce breaks the update
+        let mut packet = Packet::from_bytes(&res).unwrap();
+        let mut option = packet
+            .get_option(coap_lite::CoapOption::Oscore)
+            .unwrap()
+            .front()
+            .unwrap()
+            .clone();
+        let last = option.len() - 1;
+        option[last] ^= 1;
+        packet.clear_option(coap_lite::CoapOption::Oscore);
+        packet.add_option(coap_lite::CoapOption::Oscore, option);
+        let tampered = packet.to_bytes().unwrap();
+        assert_eq!(
+            Error::Aead,
+            update.unprotect_response(&client, &tampered).err().unwrap()
+        );
+    }
+
+    #[test]
+    fn replay() {
+        let (mut client, mut server) = contexts();
+        let mut update = KeyUpdate::new(&mut client, vec![0x11; 8]).unwrap();
+        let req = update.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert!(Responder::new(&mut server, &req, vec![0x22; 8]).is_ok());
+        // The request carrying the update can't be replayed
+        assert_eq!(
+            Error::ReplayDetected,
+            Responder::new(&mut server, &req, vec![0x33; 8])
+                .err()
+                .unwrap()
+        );
+
+        // Its partial IV was used up in the old contexts as well
+        let (req, _) = client.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert!(server.unprotect_request(&req).is_ok());
+        assert_eq!(
+            Error::ReplayDetected,
+            server.unprotect_request(&req).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let (mut client, mut server) = contexts();
+        assert_eq!(
+            Error::InvalidKeyUpdate,
+            KeyUpdate::new(&mut client, vec![]).err().unwrap()
+        );
+        assert_eq!(
+            Error::InvalidKeyUpdate,
+            KeyUpdate::new(&mut client, vec![0; MAX_NONCE_LEN + 1])
+                .err()
+                .unwrap()
+        );
+        // A request without the update
+        assert_eq!(
+            Error::InvalidKeyUpdate,
+            Responder::new(&mut server, &REQ_PROTECTED, vec![0; 8])
+                .err()
+                .unwrap()
+        );
+        // A response before the request
+        let mut update = KeyUpdate::new(&mut client, vec![0; 8]).unwrap();
+        assert_eq!(
+            Error::InvalidKeyUpdate,
+            update
+                .unprotect_response(&client, &RES_PROTECTED)
+                .err()
+                .unwrap()
+        );
+    }
+}
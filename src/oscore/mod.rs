@@ -25,6 +25,7 @@
 //! let mut client_context = SecurityContext::new(
 //!     master_secret.to_vec(),
 //!     master_salt.to_vec(),
+//!     None,
 //!     client_id.to_vec(),
 //!     server_id.to_vec(),
 //! )
@@ -34,8 +35,8 @@
 //!     0x44, 0x01, 0x5D, 0x1F, 0x00, 0x00, 0x39, 0x74, 0x39, 0x6C, 0x6F,
 //!     0x63, 0x61, 0x6C, 0x68, 0x6F, 0x73, 0x74, 0x83, 0x74, 0x76, 0x31,
 //! ];
-//! // Protect the request
-//! let req_protected =
+//! // Protect the request, keeping its binding to unprotect the response
+//! let (req_protected, mut binding) =
 //!     client_context.protect_request(&req_unprotected).unwrap();
 //!
 //! // Server -----------------------------------------------------------------
@@ -45,6 +46,7 @@
 //! let mut server_context = SecurityContext::new(
 //!     master_secret.to_vec(),
 //!     master_salt.to_vec(),
+//!     None,
 //!     server_id.to_vec(),
 //!     client_id.to_vec(),
 //! )
@@ -67,20 +69,47 @@
 //! // Client -----------------------------------------------------------------
 //!
 //! // Unprotect the response
-//! let res_unprotected_local =
-//!     client_context.unprotect_response(&res_protected).unwrap();
+//! let res_unprotected_local = client_context
+//!     .unprotect_response(&res_protected, &mut binding)
+//!     .unwrap();
 //! assert_eq!(&res_unprotected[..], &res_unprotected_local[..]);
 //! ```
 
+mod algorithm;
+mod binding;
+pub mod block;
+mod ccm;
+mod classes;
 mod context;
+pub mod echo;
 #[cfg_attr(tarpaulin, skip)]
 mod error;
+mod error_response;
+pub mod group;
+pub mod http;
+mod inplace;
+pub mod kudos;
+mod no_response;
+mod observe;
+pub mod option;
+pub mod registry;
+mod replay;
+mod storage;
 #[cfg(test)]
 mod test_vectors;
+pub mod transport;
 mod util;
 
-pub use context::SecurityContext;
+pub use algorithm::{AeadAlgorithm, Algorithms, HkdfAlgorithm};
+pub use binding::RequestBinding;
+pub use classes::{OptionClass, OptionClasses};
+pub use context::{extract_request_ids, SecurityContext, MAX_SEQUENCE_NUMBER};
 pub use error::Error;
+pub use error_response::build_error_response;
+pub use no_response::is_response_suppressed;
+pub use observe::Observation;
+pub use replay::{DEFAULT_WINDOW_SIZE, MAX_WINDOW_SIZE};
+pub use storage::SequenceNumberStorage;
 
 /// The result type for the `oscore` module.
 pub type Result<T> = core::result::Result<T, Error>;
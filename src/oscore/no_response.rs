@@ -0,0 +1,103 @@
+use coap_lite::{CoapOption, MessageClass};
+
+use super::{
+    transport::{self, Transport},
+    Result,
+};
+
+/// Returns whether the client asked not to receive a response of this class.
+///
+/// Since No-Response is protected as a class E option, the server only
+/// learns about it after unprotecting the request. It can then use this to
+/// decide whether it should protect and send a response at all, as described
+/// in RFC 7967.
+///
+/// # Arguments
+/// * `request` - The unprotected CoAP request.
+/// * `response_class` - The class of the response the server would send.
+/// * `transport` - The transport the request is framed for.
+pub fn is_response_suppressed(
+    request: &[u8],
+    response_class: MessageClass,
+    transport: Transport,
+) -> Result<bool> {
+    // Parse the CoAP message
+    let request = transport::decode(request, transport)?;
+    // Without the option, the client is interested in every response
+    let value = match request.get_option(CoapOption::NoResponse) {
+        Some(value_list) => match value_list.front() {
+            // The empty value means the client is interested in everything
+            Some(value) => value.iter().fold(0, |acc, &b| acc << 8 | b as u32),
+            None => 0,
+        },
+        None => return Ok(false),
+    };
+
+    // Responses of class c are suppressed by bit c - 1
+    let class = u8::from(response_class) >> 5;
+    match response_class {
+        MessageClass::Response(_) if class > 0 => {
+            Ok(value & 1 << (class - 1) != 0)
+        }
+        _ => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use coap_lite::{Packet, ResponseType};
+
+    /// Returns a GET request with the given No-Response value.
+    fn request(value: Option<Vec<u8>>) -> Vec<u8> {
+        let mut packet = Packet::new();
+        if let Some(value) = value {
+            packet.add_option(CoapOption::NoResponse, value);
+        }
+        packet.to_bytes().unwrap()
+    }
+
+    #[test]
+    fn suppression() {
+        let content = MessageClass::Response(ResponseType::Content);
+        let not_found = MessageClass::Response(ResponseType::NotFound);
+        let internal =
+            MessageClass::Response(ResponseType::InternalServerError);
+
+        // No option, no suppression
+        let req = request(None);
+        assert!(
+            !is_response_suppressed(&req, content, Transport::Udp).unwrap()
+        );
+        assert!(
+            !is_response_suppressed(&req, not_found, Transport::Udp).unwrap()
+        );
+
+        // Empty value, interested in everything
+        let req = request(Some(vec![]));
+        assert!(
+            !is_response_suppressed(&req, content, Transport::Udp).unwrap()
+        );
+
+        // Not interested in 2.xx
+        let req = request(Some(vec![2]));
+        assert!(is_response_suppressed(&req, content, Transport::Udp).unwrap());
+        assert!(
+            !is_response_suppressed(&req, not_found, Transport::Udp).unwrap()
+        );
+        assert!(
+            !is_response_suppressed(&req, internal, Transport::Udp).unwrap()
+        );
+
+        // Not interested in anything
+        let req = request(Some(vec![2 | 8 | 16]));
+        assert!(is_response_suppressed(&req, content, Transport::Udp).unwrap());
+        assert!(
+            is_response_suppressed(&req, not_found, Transport::Udp).unwrap()
+        );
+        assert!(
+            is_response_suppressed(&req, internal, Transport::Udp).unwrap()
+        );
+    }
+}
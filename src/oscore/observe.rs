@@ -0,0 +1,126 @@
+use alloc::vec::Vec;
+
+use super::{
+    error::Error,
+    transport::{self, Transport},
+    util, Result,
+};
+
+/// Binds the notifications of an observation to the request that registered
+/// it.
+///
+/// Notifications are protected with the `kid` and `piv` of the original
+/// registration request in their AAD, and need to be checked for freshness
+/// individually, as described in RFC 8613 Section 7.4.1. A client creates one
+/// of these for every observation it registers and uses it to unprotect all
+/// notifications that belong to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Observation {
+    request_kid: Vec<u8>,
+    request_piv: Vec<u8>,
+    /// The largest partial IV of all notifications received so far.
+    notification_number: Option<u64>,
+    /// Whether any notification has been received yet.
+    received: bool,
+}
+
+impl Observation {
+    /// Creates a new `Observation` for the registration request.
+    ///
+    /// # Arguments
+    /// * `request` - The OSCORE request registering the observation.
+    /// * `transport` - The transport the request is framed for.
+    pub fn new(request: &[u8], transport: Transport) -> Result<Observation> {
+        // Parse the request
+        let request = transport::decode(request, transport)?;
+        // Extract the kid and piv from its OSCORE option
+        let (request_kid, request_piv, _) =
+            util::extract_kid_piv_context(&request)?;
+
+        Ok(Observation {
+            request_kid: request_kid.ok_or(Error::NoKidPiv)?,
+            request_piv: request_piv.ok_or(Error::NoKidPiv)?,
+            notification_number: None,
+            received: false,
+        })
+    }
+
+    /// Returns the `kid` of the registration request.
+    pub(crate) fn get_request_kid(&self) -> &[u8] {
+        &self.request_kid
+    }
+
+    /// Returns the `piv` of the registration request.
+    pub(crate) fn get_request_piv(&self) -> &[u8] {
+        &self.request_piv
+    }
+
+    /// Throws an error if a notification with this `piv` would not be fresh.
+    ///
+    /// Only the first notification may come without a `piv` of its own.
+    pub(crate) fn check(&self, piv: Option<&[u8]>) -> Result<()> {
+        match (piv, self.notification_number) {
+            // Notifications have to be newer than every previous one
+            (Some(piv), Some(number)) if util::piv_to_u64(piv) <= number => {
+                Err(Error::ReplayDetected)
+            }
+            (Some(_), _) => Ok(()),
+            // Reusing the request's piv is only possible once
+            (None, _) if self.received => Err(Error::ReplayDetected),
+            (None, _) => Ok(()),
+        }
+    }
+
+    /// Remembers a verified notification with this `piv`.
+    pub(crate) fn update(&mut self, piv: Option<&[u8]>) {
+        if let Some(piv) = piv {
+            self.notification_number = Some(util::piv_to_u64(piv));
+        }
+        self.received = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_vectors::*;
+    use super::*;
+
+    #[test]
+    fn creation() {
+        let observation =
+            Observation::new(&REQ_PROTECTED, Transport::Udp).unwrap();
+        assert_eq!(&CLIENT_ID, observation.get_request_kid());
+        assert_eq!(&REQ_PIV, observation.get_request_piv());
+
+        // Unprotected requests don't work
+        assert_eq!(
+            Error::NoOscoreOption,
+            Observation::new(&REQ_UNPROTECTED, Transport::Udp).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn ordering() {
+        let mut observation =
+            Observation::new(&REQ_PROTECTED, Transport::Udp).unwrap();
+
+        // The first one is allowed to reuse the request's piv
+        assert!(observation.check(None).is_ok());
+        observation.update(None);
+        assert_eq!(Err(Error::ReplayDetected), observation.check(None));
+
+        assert!(observation.check(Some(&[0x02])).is_ok());
+        observation.update(Some(&[0x02]));
+        // Duplicates and older ones are refused
+        assert_eq!(
+            Err(Error::ReplayDetected),
+            observation.check(Some(&[0x02]))
+        );
+        assert_eq!(
+            Err(Error::ReplayDetected),
+            observation.check(Some(&[0x01]))
+        );
+        // Newer ones are fine, even with gaps
+        assert!(observation.check(Some(&[0x05])).is_ok());
+    }
+}
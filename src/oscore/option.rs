@@ -0,0 +1,461 @@
+//! Encoding and decoding of the OSCORE option value (RFC 8613 Section 6.1).
+//!
+//! Unlike the processing in a security context, this strictly validates the
+//! value, so it can be used by proxies and tooling to inspect the option of
+//! a message without having a context for it.
+
+use alloc::vec::Vec;
+use coap_lite::{CoapOption, Packet};
+use core::fmt;
+
+use super::{error::Error, Result};
+
+/// The maximum length of the partial IV.
+pub const MAX_PIV_LEN: usize = 5;
+
+/// The flag announcing a second flag byte.
+const EXTENSION_FLAG: u8 = 0b1000_0000;
+/// The Group Flag of Group OSCORE.
+const GROUP_FLAG: u8 = 0b0010_0000;
+/// The flag announcing the kid context.
+const KID_CONTEXT_FLAG: u8 = 0b0001_0000;
+/// The flag announcing the kid.
+const KID_FLAG: u8 = 0b0000_1000;
+/// The bits with the length of the partial IV.
+const PIV_LEN_BITS: u8 = 0b0000_0111;
+/// The bits of the first flag byte without a meaning.
+const RESERVED_BITS: u8 = 0b0100_0000;
+/// The flag of the second flag byte announcing a key update (KUDOS).
+const KUDOS_FLAG: u8 = 0b0000_0001;
+
+/// The ways an OSCORE option value can be malformed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OptionError {
+    /// Reserved flag bits are set.
+    ReservedBits,
+    /// The value isn't empty, but has no flags set.
+    EmptyFlags,
+    /// The length of the partial IV is 6 or 7, or doesn't fit otherwise.
+    InvalidPivLength,
+    /// The value ends in the second flag byte or the partial IV.
+    TruncatedPiv,
+    /// The value ends in the kid context or its length.
+    TruncatedKidContext,
+    /// The value ends in the key update nonce or its length.
+    TruncatedNonce,
+    /// The key update nonce doesn't match its length in `x`.
+    InvalidNonceLength,
+    /// The kid context is longer than 255 bytes.
+    KidContextTooLong,
+    /// The value has bytes left over without the kid flag set.
+    TrailingBytes,
+}
+
+impl fmt::Display for OptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OptionError::ReservedBits => {
+                write!(f, "The OSCORE option has reserved bits set")
+            }
+            OptionError::EmptyFlags => {
+                write!(f, "The OSCORE option has a value without flags")
+            }
+            OptionError::InvalidPivLength => {
+                write!(f, "The OSCORE option has an invalid partial IV length")
+            }
+            OptionError::TruncatedPiv => {
+                write!(f, "The OSCORE option is truncated in the partial IV")
+            }
+            OptionError::TruncatedKidContext => {
+                write!(f, "The OSCORE option is truncated in the kid context")
+            }
+            OptionError::TruncatedNonce => {
+                write!(f, "The OSCORE option is truncated in the nonce")
+            }
+            OptionError::InvalidNonceLength => {
+                write!(f, "The OSCORE option's nonce doesn't match its length")
+            }
+            OptionError::KidContextTooLong => {
+                write!(f, "The OSCORE option's kid context is too long")
+            }
+            OptionError::TrailingBytes => {
+                write!(f, "The OSCORE option has bytes without a meaning")
+            }
+        }
+    }
+}
+
+/// The decoded value of an OSCORE option.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OscoreOption {
+    /// The partial IV, between 1 and `MAX_PIV_LEN` bytes.
+    pub piv: Option<Vec<u8>>,
+    /// The kid context.
+    pub kid_context: Option<Vec<u8>>,
+    /// The `x` byte and nonce of a key update.
+    pub kudos: Option<(u8, Vec<u8>)>,
+    /// The kid.
+    pub kid: Option<Vec<u8>>,
+    /// Whether the Group Flag is set, meaning the message is protected in
+    /// the group mode of Group OSCORE.
+    pub group: bool,
+}
+
+impl OscoreOption {
+    /// Decodes and validates the value of an OSCORE option.
+    ///
+    /// # Arguments
+    /// * `value` - The option value.
+    pub fn decode(value: &[u8]) -> Result<OscoreOption> {
+        Ok(OscoreOptionRef::decode(value)?.into_owned())
+    }
+
+    /// Decodes and validates the OSCORE option of a CoAP message.
+    ///
+    /// # Arguments
+    /// * `coap_msg` - The OSCORE message.
+    pub fn from_message(coap_msg: &[u8]) -> Result<OscoreOption> {
+        let packet = Packet::from_bytes(coap_msg)?;
+        let value = packet
+            .get_option(CoapOption::Oscore)
+            .and_then(|values| values.front())
+            .ok_or(Error::NoOscoreOption)?;
+
+        OscoreOption::decode(value)
+    }
+
+    /// Returns the encoded option value.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        self.borrowed().encode()
+    }
+
+    /// Returns the option borrowing the values of this one.
+    pub fn borrowed(&self) -> OscoreOptionRef<'_> {
+        OscoreOptionRef {
+            piv: self.piv.as_deref(),
+            kid_context: self.kid_context.as_deref(),
+            kudos: self.kudos.as_ref().map(|(x, nonce)| (*x, &nonce[..])),
+            kid: self.kid.as_deref(),
+            group: self.group,
+        }
+    }
+}
+
+/// The decoded value of an OSCORE option, borrowing from the encoded value.
+///
+/// This is what the processing without allocations works with.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OscoreOptionRef<'a> {
+    /// The partial IV, between 1 and `MAX_PIV_LEN` bytes.
+    pub piv: Option<&'a [u8]>,
+    /// The kid context.
+    pub kid_context: Option<&'a [u8]>,
+    /// The `x` byte and nonce of a key update.
+    pub kudos: Option<(u8, &'a [u8])>,
+    /// The kid.
+    pub kid: Option<&'a [u8]>,
+    /// Whether the Group Flag is set.
+    pub group: bool,
+}
+
+impl<'a> OscoreOptionRef<'a> {
+    /// Decodes and validates the value of an OSCORE option.
+    ///
+    /// # Arguments
+    /// * `value` - The option value.
+    pub fn decode(value: &'a [u8]) -> Result<OscoreOptionRef<'a>> {
+        let mut option = OscoreOptionRef::default();
+        // The empty value has all flags unset
+        let flags = match value.first() {
+            Some(&flags) => flags,
+            None => return Ok(option),
+        };
+        if flags & RESERVED_BITS != 0 {
+            return Err(OptionError::ReservedBits.into());
+        }
+        // Without any flags, the value has to be empty
+        if flags == 0 {
+            return Err(OptionError::EmptyFlags.into());
+        }
+        option.group = flags & GROUP_FLAG != 0;
+
+        let mut position = 1;
+        let kudos = if flags & EXTENSION_FLAG != 0 {
+            let extension =
+                *value.get(position).ok_or(OptionError::TruncatedPiv)?;
+            // The rest of the second flag byte isn't defined
+            if extension & !KUDOS_FLAG != 0 {
+                return Err(OptionError::ReservedBits.into());
+            }
+            position += 1;
+            extension & KUDOS_FLAG != 0
+        } else {
+            false
+        };
+
+        let piv_len = (flags & PIV_LEN_BITS) as usize;
+        if piv_len > MAX_PIV_LEN {
+            return Err(OptionError::InvalidPivLength.into());
+        }
+        if piv_len > 0 {
+            let piv = value
+                .get(position..position + piv_len)
+                .ok_or(OptionError::TruncatedPiv)?;
+            option.piv = Some(piv);
+            position += piv_len;
+        }
+
+        if flags & KID_CONTEXT_FLAG != 0 {
+            let s = *value
+                .get(position)
+                .ok_or(OptionError::TruncatedKidContext)?
+                as usize;
+            let kid_context = value
+                .get(position + 1..position + 1 + s)
+                .ok_or(OptionError::TruncatedKidContext)?;
+            option.kid_context = Some(kid_context);
+            position += 1 + s;
+        }
+
+        if kudos {
+            let x = *value.get(position).ok_or(OptionError::TruncatedNonce)?;
+            // The 4 least significant bits of x are the nonce length minus 1
+            let m = (x & 0x0F) as usize + 1;
+            let nonce = value
+                .get(position + 1..position + 1 + m)
+                .ok_or(OptionError::TruncatedNonce)?;
+            option.kudos = Some((x, nonce));
+            position += 1 + m;
+        }
+
+        if flags & KID_FLAG != 0 {
+            // The kid takes up the rest
+            option.kid = Some(&value[position..]);
+        } else if position != value.len() {
+            return Err(OptionError::TrailingBytes.into());
+        }
+
+        Ok(option)
+    }
+
+    /// Returns the length of the encoded option value, after validating the
+    /// values.
+    pub fn encoded_len(&self) -> Result<usize> {
+        let mut length = 1;
+        if self.kudos.is_some() {
+            length += 1;
+        }
+        if let Some(piv) = self.piv {
+            if piv.is_empty() || piv.len() > MAX_PIV_LEN {
+                return Err(OptionError::InvalidPivLength.into());
+            }
+            length += piv.len();
+        }
+        if let Some(kid_context) = self.kid_context {
+            if kid_context.len() > u8::MAX as usize {
+                return Err(OptionError::KidContextTooLong.into());
+            }
+            length += 1 + kid_context.len();
+        }
+        if let Some((x, nonce)) = self.kudos {
+            if nonce.len() != (x & 0x0F) as usize + 1 {
+                return Err(OptionError::InvalidNonceLength.into());
+            }
+            length += 1 + nonce.len();
+        }
+        if let Some(kid) = self.kid {
+            length += kid.len();
+        }
+
+        // Without any flags, the value is empty
+        if length == 1 && !self.group {
+            return Ok(0);
+        }
+
+        Ok(length)
+    }
+
+    /// Returns the encoded option value.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut value = vec![0; self.encoded_len()?];
+        self.encode_into(&mut value)?;
+
+        Ok(value)
+    }
+
+    /// Encodes the option value into the buffer, returning its length.
+    ///
+    /// # Arguments
+    /// * `buf` - The buffer, which has to hold `encoded_len` bytes.
+    pub fn encode_into(&self, buf: &mut [u8]) -> Result<usize> {
+        let length = self.encoded_len()?;
+        if buf.len() < length {
+            return Err(Error::BufferTooSmall);
+        }
+        if length == 0 {
+            return Ok(0);
+        }
+
+        let mut flags = 0;
+        if self.group {
+            flags |= GROUP_FLAG;
+        }
+        let mut position = 1;
+        if self.kudos.is_some() {
+            flags |= EXTENSION_FLAG;
+            buf[position] = KUDOS_FLAG;
+            position += 1;
+        }
+
+        let mut push = |bytes: &[u8], position: &mut usize| {
+            buf[*position..*position + bytes.len()].copy_from_slice(bytes);
+            *position += bytes.len();
+        };
+        if let Some(piv) = self.piv {
+            flags |= piv.len() as u8;
+            push(piv, &mut position);
+        }
+        if let Some(kid_context) = self.kid_context {
+            flags |= KID_CONTEXT_FLAG;
+            push(&[kid_context.len() as u8], &mut position);
+            push(kid_context, &mut position);
+        }
+        if let Some((x, nonce)) = self.kudos {
+            push(&[x], &mut position);
+            push(nonce, &mut position);
+        }
+        if let Some(kid) = self.kid {
+            flags |= KID_FLAG;
+            push(kid, &mut position);
+        }
+        buf[0] = flags;
+
+        Ok(length)
+    }
+
+    /// Returns the option owning copies of the values.
+    pub fn into_owned(self) -> OscoreOption {
+        OscoreOption {
+            piv: self.piv.map(Vec::from),
+            kid_context: self.kid_context.map(Vec::from),
+            kudos: self.kudos.map(|(x, nonce)| (x, nonce.to_vec())),
+            kid: self.kid.map(Vec::from),
+            group: self.group,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_vectors::*;
+    use super::*;
+
+    #[test]
+    fn test_vectors() {
+        for &(value, kid, piv, kid_context) in [
+            (&EX1_OPTION[..], EX1_KID, EX1_PIV, None),
+            (&EX2_OPTION, EX2_KID, EX2_PIV, None),
+            (&EX3_OPTION, EX3_KID, EX3_PIV, EX3_KID_CONTEXT),
+            (&EX4_OPTION, EX4_KID, EX4_PIV, None),
+            (&EX5_OPTION, EX5_KID, EX5_PIV, None),
+        ]
+        .iter()
+        {
+            let option = OscoreOption::decode(value).unwrap();
+            assert_eq!(kid, option.kid.as_deref());
+            assert_eq!(piv, option.piv.as_deref());
+            assert_eq!(kid_context, option.kid_context.as_deref());
+            assert_eq!(value, &option.encode().unwrap()[..]);
+        }
+
+        let option = OscoreOption::from_message(&REQ_PROTECTED).unwrap();
+        assert_eq!(Some(&CLIENT_ID[..]), option.kid.as_deref());
+        assert_eq!(Some(&REQ_PIV[..]), option.piv.as_deref());
+        assert_eq!(
+            Error::NoOscoreOption,
+            OscoreOption::from_message(&REQ_UNPROTECTED).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn extensions() {
+        let value = [
+            0xB9, 0x01, 0x05, 0x02, 0xAA, 0xBB, 0x02, 0x10, 0x11, 0x12, 0x01,
+        ];
+        let option = OscoreOption::decode(&value).unwrap();
+        assert_eq!(
+            OscoreOption {
+                piv: Some(vec![0x05]),
+                kid_context: Some(vec![0xAA, 0xBB]),
+                kudos: Some((0x02, vec![0x10, 0x11, 0x12])),
+                kid: Some(vec![0x01]),
+                group: true,
+            },
+            option
+        );
+        assert_eq!(&value[..], &option.encode().unwrap()[..]);
+    }
+
+    #[test]
+    fn invalid() {
+        for &(value, error) in [
+            (&[0x40][..], OptionError::ReservedBits),
+            (&[0x00], OptionError::EmptyFlags),
+            (&[0x00, 0x01], OptionError::EmptyFlags),
+            (&[0x80, 0x02], OptionError::ReservedBits),
+            (&[0x06, 1, 2, 3, 4, 5, 6], OptionError::InvalidPivLength),
+            (&[0x07, 1, 2, 3, 4, 5, 6, 7], OptionError::InvalidPivLength),
+            (&CRASH_OPTION, OptionError::TruncatedPiv),
+            (&[0x80], OptionError::TruncatedPiv),
+            (&CRASH_OPTION_CONTEXT, OptionError::TruncatedKidContext),
+            (&[0x10], OptionError::TruncatedKidContext),
+            (&[0x80, 0x01, 0x02, 0x10, 0x11], OptionError::TruncatedNonce),
+            (&[0x01, 0x05, 0xFF], OptionError::TrailingBytes),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                Error::InvalidOption(error),
+                OscoreOption::decode(value).unwrap_err()
+            );
+        }
+
+        for (option, error) in [
+            (
+                OscoreOption {
+                    piv: Some(vec![0; 6]),
+                    ..Default::default()
+                },
+                OptionError::InvalidPivLength,
+            ),
+            (
+                OscoreOption {
+                    piv: Some(vec![]),
+                    ..Default::default()
+                },
+                OptionError::InvalidPivLength,
+            ),
+            (
+                OscoreOption {
+                    kid_context: Some(vec![0; 256]),
+                    ..Default::default()
+                },
+                OptionError::KidContextTooLong,
+            ),
+            (
+                OscoreOption {
+                    kudos: Some((0x01, vec![0; 3])),
+                    ..Default::default()
+                },
+                OptionError::InvalidNonceLength,
+            ),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                Error::InvalidOption(*error),
+                option.encode().unwrap_err()
+            );
+        }
+    }
+}
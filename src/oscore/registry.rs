@@ -0,0 +1,453 @@
+use alloc::vec::Vec;
+
+use super::{
+    context, error::Error, transport::Transport, Result, SecurityContext,
+};
+
+/// The storage holding the security contexts of a `ContextRegistry`.
+///
+/// This makes it possible to keep the contexts wherever is most suitable,
+/// like a fixed-size array on an embedded device or a database. `VecBackend`
+/// is a simple implementation using a vector.
+pub trait ContextBackend {
+    /// Identifies a context in the backend, which has to remain valid until
+    /// the context is removed.
+    type Handle: Clone;
+
+    /// Returns the handle of a context with this recipient ID and ID
+    /// Context.
+    ///
+    /// Without an ID Context, any context with the recipient ID matches.
+    fn find(
+        &self,
+        recipient_id: &[u8],
+        id_context: Option<&[u8]>,
+    ) -> Option<Self::Handle>;
+
+    /// Returns the context with this handle.
+    fn get_mut(
+        &mut self,
+        handle: &Self::Handle,
+    ) -> Option<&mut SecurityContext>;
+
+    /// Adds a context, returning its handle.
+    fn insert(&mut self, context: SecurityContext) -> Result<Self::Handle>;
+
+    /// Removes the context with this handle and returns it.
+    fn remove(&mut self, handle: &Self::Handle) -> Option<SecurityContext>;
+
+    /// Persists the context with this handle after it's been used.
+    ///
+    /// Unprotecting a request updates the replay window and protecting a
+    /// response may use a sequence number, so the registry calls this after
+    /// each of them succeeded. Backends that keep their contexts in memory
+    /// don't need to do anything, which is the default.
+    fn commit(&mut self, handle: &Self::Handle) -> Result<()> {
+        let _ = handle;
+        Ok(())
+    }
+}
+
+/// A `ContextBackend` keeping the contexts in a vector.
+///
+/// The handles are indices, which are reused for new contexts once their
+/// context has been removed.
+#[derive(Default)]
+pub struct VecBackend {
+    contexts: Vec<Option<SecurityContext>>,
+}
+
+impl VecBackend {
+    /// Creates a new, empty `VecBackend`.
+    pub fn new() -> VecBackend {
+        VecBackend { contexts: vec![] }
+    }
+}
+
+impl ContextBackend for VecBackend {
+    type Handle = usize;
+
+    fn find(
+        &self,
+        recipient_id: &[u8],
+        id_context: Option<&[u8]>,
+    ) -> Option<usize> {
+        self.contexts.iter().position(|context| match context {
+            Some(context) => matches(context, recipient_id, id_context),
+            None => false,
+        })
+    }
+
+    fn get_mut(&mut self, handle: &usize) -> Option<&mut SecurityContext> {
+        self.contexts.get_mut(*handle).and_then(Option::as_mut)
+    }
+
+    fn insert(&mut self, context: SecurityContext) -> Result<usize> {
+        // Fill the first gap, if there is one
+        match self.contexts.iter().position(Option::is_none) {
+            Some(handle) => {
+                self.contexts[handle] = Some(context);
+                Ok(handle)
+            }
+            None => {
+                self.contexts.push(Some(context));
+                Ok(self.contexts.len() - 1)
+            }
+        }
+    }
+
+    fn remove(&mut self, handle: &usize) -> Option<SecurityContext> {
+        self.contexts.get_mut(*handle).and_then(Option::take)
+    }
+}
+
+/// Holds the security contexts of a server and picks the right one for
+/// incoming requests.
+///
+/// The context for a request is found by the `kid` and `kid context` in its
+/// OSCORE option, which are the recipient ID and ID Context of the context.
+/// Unprotecting a request returns the handle of the context that was used,
+/// with which the response can be protected.
+pub struct ContextRegistry<B: ContextBackend> {
+    backend: B,
+    /// The transport requests are framed for.
+    transport: Transport,
+}
+
+impl<B: ContextBackend> ContextRegistry<B> {
+    /// Creates a new `ContextRegistry` with the contexts in the backend.
+    pub fn new(backend: B) -> ContextRegistry<B> {
+        ContextRegistry {
+            backend,
+            transport: Transport::Udp,
+        }
+    }
+
+    /// Sets the transport the requests are framed for, to find their
+    /// context.
+    ///
+    /// It's UDP by default. The contexts have to be set to the same
+    /// transport with `SecurityContext::set_transport`.
+    pub fn set_transport(&mut self, transport: Transport) {
+        self.transport = transport;
+    }
+
+    /// Adds a context, returning its handle.
+    ///
+    /// Since the context is looked up by its recipient ID and ID Context,
+    /// their combination has to be unique.
+    pub fn add(&mut self, context: SecurityContext) -> Result<B::Handle> {
+        // Without an ID Context, the recipient ID alone has to be unique
+        if self
+            .backend
+            .find(context.get_recipient_id(), context.get_id_context())
+            .is_some()
+        {
+            return Err(Error::DuplicateContext);
+        }
+
+        self.backend.insert(context)
+    }
+
+    /// Removes the context with this handle and returns it.
+    pub fn remove(&mut self, handle: &B::Handle) -> Option<SecurityContext> {
+        self.backend.remove(handle)
+    }
+
+    /// Returns the context with this handle.
+    ///
+    /// Changes to it have to be persisted with `commit`.
+    pub fn get_mut(
+        &mut self,
+        handle: &B::Handle,
+    ) -> Option<&mut SecurityContext> {
+        self.backend.get_mut(handle)
+    }
+
+    /// Persists the context with this handle in the backend.
+    pub fn commit(&mut self, handle: &B::Handle) -> Result<()> {
+        self.backend.commit(handle)
+    }
+
+    /// Returns the handle of the context the OSCORE request is meant for.
+    ///
+    /// If the request has no `kid context`, the first context with the
+    /// recipient ID is used.
+    ///
+    /// # Arguments
+    /// * `oscore_msg` - The OSCORE message protecting the CoAP request.
+    pub fn find(&self, oscore_msg: &[u8]) -> Result<B::Handle> {
+        let (kid, kid_context) =
+            context::extract_request_ids(oscore_msg, self.transport)?;
+
+        self.backend
+            .find(&kid, kid_context.as_deref())
+            .ok_or(Error::UnknownContext)
+    }
+
+    /// Returns the original CoAP request protected in the OSCORE message,
+    /// together with the handle of the context that was used.
+    ///
+    /// # Arguments
+    /// * `oscore_msg` - The OSCORE message protecting the CoAP request.
+    pub fn unprotect_request(
+        &mut self,
+        oscore_msg: &[u8],
+    ) -> Result<(B::Handle, Vec<u8>)> {
+        let handle = self.find(oscore_msg)?;
+        let context =
+            self.backend.get_mut(&handle).ok_or(Error::UnknownContext)?;
+        let unprotected = context.unprotect_request(oscore_msg)?;
+        // Otherwise the request could be replayed after a restart
+        self.backend.commit(&handle)?;
+
+        Ok((handle, unprotected))
+    }
+
+    /// Returns an OSCORE message based on the original CoAP response, using
+    /// the context with this handle.
+    ///
+    /// # Arguments
+    /// * `handle` - The handle returned when unprotecting the request.
+    /// * `coap_msg` - The original CoAP response to protect.
+    /// * `request` - The OSCORE request to which to respond.
+    /// * `reuse_piv` - Whether the request's `piv` should be reused.
+    pub fn protect_response(
+        &mut self,
+        handle: &B::Handle,
+        coap_msg: &[u8],
+        request: &[u8],
+        reuse_piv: bool,
+    ) -> Result<Vec<u8>> {
+        let response = self
+            .backend
+            .get_mut(handle)
+            .ok_or(Error::UnknownContext)?
+            .protect_response(coap_msg, request, reuse_piv)?;
+        // Persist a sequence number we used before the response is sent
+        self.backend.commit(handle)?;
+
+        Ok(response)
+    }
+}
+
+/// Returns whether the context has this recipient ID and ID Context.
+///
+/// Without an ID Context, only the recipient ID is compared. This is meant
+/// for implementations of `ContextBackend`.
+pub fn matches(
+    context: &SecurityContext,
+    recipient_id: &[u8],
+    id_context: Option<&[u8]>,
+) -> bool {
+    context.get_recipient_id() == recipient_id
+        && (id_context.is_none() || context.get_id_context() == id_context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_vectors::*;
+    use super::*;
+
+    /// Returns the context of the server talking to a client.
+    fn server_context(
+        client_id: &[u8],
+        id_context: Option<&[u8]>,
+    ) -> SecurityContext {
+        SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            id_context.map(<[u8]>::to_vec),
+            SERVER_ID.to_vec(),
+            client_id.to_vec(),
+        )
+        .unwrap()
+    }
+
+    /// Returns the context of a client talking to the server.
+    fn client_context(
+        client_id: &[u8],
+        id_context: Option<&[u8]>,
+    ) -> SecurityContext {
+        SecurityContext::new(
+            MASTER_SECRET.to_vec(),
+            MASTER_SALT.to_vec(),
+            id_context.map(<[u8]>::to_vec),
+            client_id.to_vec(),
+            SERVER_ID.to_vec(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn lookup() {
+        let mut registry = ContextRegistry::new(VecBackend::new());
+        let a = registry.add(server_context(&[0xAA], None)).unwrap();
+        let b = registry
+            .add(server_context(&[0xBB], Some(&ID_CONTEXT)))
+            .unwrap();
+        let c = registry
+            .add(server_context(&[0xBB], Some(&[0x01])))
+            .unwrap();
+
+        let mut client_a = client_context(&[0xAA], None);
+        let mut client_b = client_context(&[0xBB], Some(&ID_CONTEXT));
+        let mut client_c = client_context(&[0xBB], Some(&[0x01]));
+        for (client, handle) in
+            &mut [(&mut client_a, a), (&mut client_b, b), (&mut client_c, c)]
+        {
+            let (req, mut binding) =
+                client.protect_request(&REQ_UNPROTECTED).unwrap();
+            let (found, unprotected) =
+                registry.unprotect_request(&req).unwrap();
+            assert_eq!(*handle, found);
+            assert_eq!(&REQ_UNPROTECTED[..], &unprotected[..]);
+
+            let res = registry
+                .protect_response(&found, &RES_UNPROTECTED, &req, false)
+                .unwrap();
+            assert_eq!(
+                &RES_UNPROTECTED[..],
+                &client.unprotect_response(&res, &mut binding).unwrap()[..]
+            );
+        }
+
+        // Unknown clients are refused
+        let mut client_d = client_context(&[0xDD], None);
+        let (req, _) = client_d.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert_eq!(
+            Error::UnknownContext,
+            registry.unprotect_request(&req).unwrap_err()
+        );
+        let mut client_e = client_context(&[0xBB], Some(&[0x02]));
+        let (req, _) = client_e.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert_eq!(Error::UnknownContext, registry.find(&req).unwrap_err());
+    }
+
+    #[test]
+    fn reliable_transports() {
+        let mut registry = ContextRegistry::new(VecBackend::new());
+        registry.set_transport(Transport::Tcp);
+        let mut server = server_context(&[0xAA], None);
+        server.set_transport(Transport::Tcp);
+        let handle = registry.add(server).unwrap();
+
+        let mut client = client_context(&[0xAA], None);
+        client.set_transport(Transport::Tcp);
+        let request = coap_lite::Packet::from_bytes(&REQ_UNPROTECTED).unwrap();
+        let request =
+            super::super::transport::encode(&request, Transport::Tcp).unwrap();
+        let (req, _) = client.protect_request(&request).unwrap();
+        assert_eq!(handle, registry.find(&req).unwrap());
+        let (found, unprotected) = registry.unprotect_request(&req).unwrap();
+        assert_eq!(handle, found);
+        assert_eq!(request, unprotected);
+    }
+
+    #[test]
+    fn management() {
+        let mut registry = ContextRegistry::new(VecBackend::new());
+        let a = registry.add(server_context(&[0xAA], None)).unwrap();
+        assert_eq!(
+            Error::DuplicateContext,
+            registry.add(server_context(&[0xAA], None)).err().unwrap()
+        );
+        let b = registry.add(server_context(&[0xBB], None)).unwrap();
+
+        // Removing one frees its handle
+        let removed = registry.remove(&a).unwrap();
+        assert_eq!(&[0xAA], removed.get_recipient_id());
+        assert!(registry.remove(&a).is_none());
+        assert!(registry.get_mut(&a).is_none());
+        assert_eq!(&[0xBB], registry.get_mut(&b).unwrap().get_recipient_id());
+
+        let mut client_a = client_context(&[0xAA], None);
+        let (req, _) = client_a.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert_eq!(Error::UnknownContext, registry.find(&req).unwrap_err());
+        assert_eq!(
+            Error::UnknownContext,
+            registry
+                .protect_response(&a, &RES_UNPROTECTED, &req, false)
+                .unwrap_err()
+        );
+
+        // And it's reused
+        let c = registry.add(server_context(&[0xCC], None)).unwrap();
+        assert_eq!(a, c);
+    }
+
+    /// A backend recording the contexts it has to persist.
+    struct CommittingBackend {
+        contexts: VecBackend,
+        committed: Vec<usize>,
+        fail: bool,
+    }
+
+    impl ContextBackend for CommittingBackend {
+        type Handle = usize;
+
+        fn find(
+            &self,
+            recipient_id: &[u8],
+            id_context: Option<&[u8]>,
+        ) -> Option<usize> {
+            self.contexts.find(recipient_id, id_context)
+        }
+
+        fn get_mut(&mut self, handle: &usize) -> Option<&mut SecurityContext> {
+            self.contexts.get_mut(handle)
+        }
+
+        fn insert(&mut self, context: SecurityContext) -> Result<usize> {
+            self.contexts.insert(context)
+        }
+
+        fn remove(&mut self, handle: &usize) -> Option<SecurityContext> {
+            self.contexts.remove(handle)
+        }
+
+        fn commit(&mut self, handle: &usize) -> Result<()> {
+            if self.fail {
+                return Err(Error::Storage);
+            }
+            self.committed.push(*handle);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn commit() {
+        let mut registry = ContextRegistry::new(CommittingBackend {
+            contexts: VecBackend::new(),
+            committed: vec![],
+            fail: false,
+        });
+        registry.add(server_context(&[0xAA], None)).unwrap();
+        let b = registry.add(server_context(&[0xBB], None)).unwrap();
+        let mut client = client_context(&[0xBB], None);
+
+        // Both the request and the response change the context
+        let (req, _) = client.protect_request(&REQ_UNPROTECTED).unwrap();
+        registry.unprotect_request(&req).unwrap();
+        assert_eq!(vec![b], registry.backend.committed);
+        registry
+            .protect_response(&b, &RES_UNPROTECTED, &req, false)
+            .unwrap();
+        assert_eq!(vec![b, b], registry.backend.committed);
+
+        // Failed operations don't
+        assert_eq!(
+            Error::ReplayDetected,
+            registry.unprotect_request(&req).unwrap_err()
+        );
+        assert_eq!(2, registry.backend.committed.len());
+
+        // And if the context can't be persisted, neither is the request
+        registry.backend.fail = true;
+        let (req, _) = client.protect_request(&REQ_UNPROTECTED).unwrap();
+        assert_eq!(
+            Error::Storage,
+            registry.unprotect_request(&req).unwrap_err()
+        );
+    }
+}
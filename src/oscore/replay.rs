@@ -0,0 +1,301 @@
+use super::{error::Error, Result};
+
+/// The default replay window size recommended by RFC 8613.
+pub const DEFAULT_WINDOW_SIZE: usize = 32;
+/// The largest replay window size we support.
+pub const MAX_WINDOW_SIZE: usize = 128;
+
+/// A sliding replay window as described in RFC 8613 Section 7.4.
+///
+/// It keeps track of the highest sequence number received so far and a
+/// bitmap of the sequence numbers below it that fall into the window. This
+/// allows accepting messages arriving out of order, while rejecting both
+/// duplicates and messages that are too old to be checked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayWindow {
+    /// The number of sequence numbers covered by the window.
+    size: usize,
+    /// The highest sequence number received, if any.
+    highest: Option<u64>,
+    /// Bit `i` is set if `highest - i` has been received.
+    bitmap: u128,
+}
+
+impl ReplayWindow {
+    /// Creates a new, empty `ReplayWindow` of the given size.
+    ///
+    /// The size has to be between 1 and `MAX_WINDOW_SIZE`.
+    pub fn new(size: usize) -> Result<ReplayWindow> {
+        if size == 0 || size > MAX_WINDOW_SIZE {
+            return Err(Error::InvalidReplayWindow);
+        }
+
+        Ok(ReplayWindow {
+            size,
+            highest: None,
+            bitmap: 0,
+        })
+    }
+
+    /// Throws an error if the sequence number has been received before or is
+    /// too old to tell.
+    ///
+    /// This doesn't change the window, so it can be used before verifying the
+    /// message.
+    pub fn check(&self, sequence_number: u64) -> Result<()> {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            // Nothing received yet, so everything is fresh
+            None => return Ok(()),
+        };
+        // Anything above the highest one is fresh
+        if sequence_number > highest {
+            return Ok(());
+        }
+
+        let offset = highest - sequence_number;
+        // Reject it if it's left of the window, since we can't tell whether
+        // it has been received before
+        if offset >= self.size as u64 {
+            return Err(Error::ReplayDetected);
+        }
+        // Reject it if it's been received already
+        if self.bitmap & (1 << offset) != 0 {
+            return Err(Error::ReplayDetected);
+        }
+
+        Ok(())
+    }
+
+    /// Adds the sequence number to the window.
+    ///
+    /// This should only be called once the message has been verified, and
+    /// after `check` has accepted it.
+    pub fn update(&mut self, sequence_number: u64) {
+        match self.highest {
+            Some(highest) if sequence_number <= highest => {
+                // It's inside the window, mark it as received
+                let offset = highest - sequence_number;
+                if offset < self.size as u64 {
+                    self.bitmap |= 1 << offset;
+                }
+            }
+            Some(highest) => {
+                // It's a new highest one, slide the window to the right
+                let shift = sequence_number - highest;
+                self.bitmap = if shift >= MAX_WINDOW_SIZE as u64 {
+                    0
+                } else {
+                    self.bitmap << shift
+                };
+                self.bitmap |= 1;
+                self.highest = Some(sequence_number);
+            }
+            None => {
+                // The first one we receive
+                self.bitmap = 1;
+                self.highest = Some(sequence_number);
+            }
+        }
+        // Forget everything that has left the window
+        self.bitmap &= self.mask();
+    }
+
+    /// Makes the sequence number the highest one received, with everything
+    /// below it counting as received.
+    ///
+    /// This is used to reinitialize a window whose state was lost, since any
+    /// of the older sequence numbers could have been received before.
+    pub fn initialize(&mut self, sequence_number: u64) {
+        self.highest = Some(sequence_number);
+        self.bitmap = self.mask();
+    }
+
+    /// Returns the size, highest sequence number and bitmap of the window.
+    pub fn state(&self) -> (usize, Option<u64>, u128) {
+        (self.size, self.highest, self.bitmap)
+    }
+
+    /// Recreates a window from the values returned by `state`, making sure
+    /// they're consistent.
+    pub fn from_state(
+        size: usize,
+        highest: Option<u64>,
+        bitmap: u128,
+    ) -> Result<ReplayWindow> {
+        let mut window = ReplayWindow::new(size)?;
+        // Without a highest one, nothing can have been received, and the
+        // bitmap can't extend beyond the window
+        if (highest.is_none() && bitmap != 0) || bitmap & !window.mask() != 0 {
+            return Err(Error::InvalidReplayWindow);
+        }
+        window.highest = highest;
+        window.bitmap = bitmap;
+
+        Ok(window)
+    }
+
+    /// Returns the bitmap with all bits inside the window set.
+    fn mask(&self) -> u128 {
+        if self.size < MAX_WINDOW_SIZE {
+            (1 << self.size) - 1
+        } else {
+            !0
+        }
+    }
+}
+
+impl Default for ReplayWindow {
+    fn default() -> ReplayWindow {
+        ReplayWindow {
+            size: DEFAULT_WINDOW_SIZE,
+            highest: None,
+            bitmap: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks and updates like a recipient would on successful verification.
+    fn receive(window: &mut ReplayWindow, sequence_number: u64) -> Result<()> {
+        window.check(sequence_number)?;
+        window.update(sequence_number);
+        Ok(())
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(
+            Error::InvalidReplayWindow,
+            ReplayWindow::new(0).unwrap_err()
+        );
+        assert_eq!(
+            Error::InvalidReplayWindow,
+            ReplayWindow::new(MAX_WINDOW_SIZE + 1).unwrap_err()
+        );
+        assert_eq!(1, ReplayWindow::new(1).unwrap().size);
+        assert_eq!(64, ReplayWindow::new(64).unwrap().size);
+        assert_eq!(128, ReplayWindow::new(128).unwrap().size);
+        assert_eq!(DEFAULT_WINDOW_SIZE, ReplayWindow::default().size);
+    }
+
+    #[test]
+    fn in_order() {
+        let mut window = ReplayWindow::default();
+        for n in 0..100 {
+            assert!(receive(&mut window, n).is_ok());
+        }
+        assert_eq!(Err(Error::ReplayDetected), receive(&mut window, 99));
+        assert_eq!(Err(Error::ReplayDetected), receive(&mut window, 80));
+    }
+
+    #[test]
+    fn duplicates() {
+        let mut window = ReplayWindow::default();
+        assert!(receive(&mut window, 0).is_ok());
+        assert_eq!(Err(Error::ReplayDetected), receive(&mut window, 0));
+        assert!(receive(&mut window, 5).is_ok());
+        assert_eq!(Err(Error::ReplayDetected), receive(&mut window, 5));
+        assert_eq!(Err(Error::ReplayDetected), receive(&mut window, 0));
+    }
+
+    #[test]
+    fn out_of_order() {
+        let mut window = ReplayWindow::default();
+        assert!(receive(&mut window, 10).is_ok());
+        assert!(receive(&mut window, 7).is_ok());
+        assert!(receive(&mut window, 9).is_ok());
+        assert!(receive(&mut window, 8).is_ok());
+        assert!(receive(&mut window, 12).is_ok());
+        assert!(receive(&mut window, 11).is_ok());
+        for n in 7..=12 {
+            assert_eq!(Err(Error::ReplayDetected), receive(&mut window, n));
+        }
+        // Older ones that are still inside the window work too
+        assert!(receive(&mut window, 0).is_ok());
+        assert!(receive(&mut window, 6).is_ok());
+    }
+
+    #[test]
+    fn state() {
+        let mut window = ReplayWindow::new(8).unwrap();
+        assert_eq!((8, None, 0), window.state());
+        assert!(receive(&mut window, 10).is_ok());
+        assert!(receive(&mut window, 8).is_ok());
+        assert_eq!((8, Some(10), 0b101), window.state());
+        assert_eq!(
+            window,
+            ReplayWindow::from_state(8, Some(10), 0b101).unwrap()
+        );
+
+        // Inconsistent states are refused
+        assert_eq!(
+            Error::InvalidReplayWindow,
+            ReplayWindow::from_state(0, None, 0).unwrap_err()
+        );
+        assert_eq!(
+            Error::InvalidReplayWindow,
+            ReplayWindow::from_state(8, None, 1).unwrap_err()
+        );
+        assert_eq!(
+            Error::InvalidReplayWindow,
+            ReplayWindow::from_state(8, Some(10), 0x100).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn too_old() {
+        let mut window = ReplayWindow::new(32).unwrap();
+        assert!(receive(&mut window, 40).is_ok());
+        // The window covers 9 to 40
+        assert_eq!(Err(Error::ReplayDetected), receive(&mut window, 8));
+        assert!(receive(&mut window, 9).is_ok());
+
+        let mut window = ReplayWindow::new(128).unwrap();
+        assert!(receive(&mut window, 200).is_ok());
+        assert_eq!(Err(Error::ReplayDetected), receive(&mut window, 72));
+        assert!(receive(&mut window, 73).is_ok());
+    }
+
+    #[test]
+    fn large_jump() {
+        let mut window = ReplayWindow::new(64).unwrap();
+        assert!(receive(&mut window, 1).is_ok());
+        assert!(receive(&mut window, 2).is_ok());
+        assert!(receive(&mut window, 1_000_000).is_ok());
+        assert_eq!(Err(Error::ReplayDetected), receive(&mut window, 2));
+        assert!(receive(&mut window, 999_999).is_ok());
+        assert_eq!(
+            Err(Error::ReplayDetected),
+            receive(&mut window, 1_000_000)
+        );
+    }
+
+    #[test]
+    fn initialization() {
+        let mut window = ReplayWindow::new(8).unwrap();
+        window.initialize(20);
+        for n in 0..=20 {
+            assert_eq!(Err(Error::ReplayDetected), window.check(n));
+        }
+        assert!(receive(&mut window, 21).is_ok());
+
+        let mut window = ReplayWindow::new(MAX_WINDOW_SIZE).unwrap();
+        window.initialize(200);
+        assert_eq!(Err(Error::ReplayDetected), window.check(100));
+        assert_eq!(Err(Error::ReplayDetected), window.check(199));
+        assert!(window.check(201).is_ok());
+    }
+
+    #[test]
+    fn check_does_not_update() {
+        let mut window = ReplayWindow::default();
+        assert!(window.check(3).is_ok());
+        assert!(window.check(3).is_ok());
+        window.update(3);
+        assert_eq!(Err(Error::ReplayDetected), window.check(3));
+    }
+}
@@ -0,0 +1,23 @@
+use super::Result;
+
+/// Persistent storage for the sender sequence number.
+///
+/// Reusing a sender sequence number means reusing an AEAD nonce, which has
+/// to be avoided even across reboots. Since writing to persistent memory for
+/// every message is usually too expensive, the security context only stores
+/// a value every K messages and skips ahead by K when it's restored, as
+/// described in RFC 8613 Appendix B.1.1.
+///
+/// The storage is part of the security context, so it has to be `Send` for
+/// the context to be moved between threads.
+pub trait SequenceNumberStorage: Send {
+    /// Returns the last stored sender sequence number, if any.
+    fn load(&mut self) -> Result<Option<u64>>;
+
+    /// Persists the sender sequence number.
+    ///
+    /// The value has to be durably stored when this returns, since the
+    /// context goes on to use it right away. If that's not possible, return
+    /// `Error::Storage`, which prevents the message from being protected.
+    fn store(&mut self, sequence_number: u64) -> Result<()>;
+}
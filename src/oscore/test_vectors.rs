@@ -19,6 +19,12 @@ pub const EX1_OPTION: [u8; 3] = [0x09, 0x05, 0x25];
 pub const EX2_KID: Option<&[u8]> = Some(&[]);
 pub const EX2_PIV: Option<&[u8]> = Some(&[0x00]);
 pub const EX2_OPTION: [u8; 2] = [0x09, 0x00];
+pub const EX3_KID: Option<&[u8]> = Some(&[]);
+pub const EX3_PIV: Option<&[u8]> = Some(&[0x05]);
+pub const EX3_KID_CONTEXT: Option<&[u8]> =
+    Some(&[0x44, 0x61, 0x6C, 0x65, 0x6B]);
+pub const EX3_OPTION: [u8; 8] =
+    [0x19, 0x05, 0x05, 0x44, 0x61, 0x6C, 0x65, 0x6B];
 pub const EX4_KID: Option<&[u8]> = None;
 pub const EX4_PIV: Option<&[u8]> = None;
 pub const EX4_OPTION: [u8; 0] = [];
@@ -63,6 +69,35 @@ pub const SERVER_NONCE: [u8; 13] = [
     0x7C,
 ];
 
+// Test vector 3 (like test vector 1, but with ID Context)
+
+pub const ID_CONTEXT: [u8; 8] =
+    [0x37, 0xCB, 0xF3, 0x21, 0x00, 0x17, 0xA2, 0xD3];
+pub const INFO_CLIENT_KEY_CTX: [u8; 17] = [
+    0x85, 0x40, 0x48, 0x37, 0xCB, 0xF3, 0x21, 0x00, 0x17, 0xA2, 0xD3, 0x0A,
+    0x63, 0x4B, 0x65, 0x79, 0x10,
+];
+pub const INFO_SERVER_KEY_CTX: [u8; 18] = [
+    0x85, 0x41, 0x01, 0x48, 0x37, 0xCB, 0xF3, 0x21, 0x00, 0x17, 0xA2, 0xD3,
+    0x0A, 0x63, 0x4B, 0x65, 0x79, 0x10,
+];
+pub const INFO_COMMON_IV_CTX: [u8; 16] = [
+    0x85, 0x40, 0x48, 0x37, 0xCB, 0xF3, 0x21, 0x00, 0x17, 0xA2, 0xD3, 0x0A,
+    0x62, 0x49, 0x56, 0x0D,
+];
+pub const CLIENT_KEY_CTX: [u8; 16] = [
+    0xAF, 0x2A, 0x13, 0x00, 0xA5, 0xE9, 0x57, 0x88, 0xB3, 0x56, 0x33, 0x6E,
+    0xEE, 0xCD, 0x2B, 0x92,
+];
+pub const SERVER_KEY_CTX: [u8; 16] = [
+    0xE3, 0x9A, 0x0C, 0x7C, 0x77, 0xB4, 0x3F, 0x03, 0xB4, 0xB3, 0x9A, 0xB9,
+    0xA2, 0x68, 0x69, 0x9F,
+];
+pub const COMMON_IV_CTX: [u8; 13] = [
+    0x2C, 0xA5, 0x8F, 0xB8, 0x5F, 0xF1, 0xB8, 0x1C, 0x0B, 0x71, 0x81, 0xB8,
+    0x5E,
+];
+
 // Test vector 4 (uses context from test vector 1)
 
 pub const REQ_UNPROTECTED: [u8; 22] = [
@@ -104,12 +139,34 @@ pub const RES_PIV_PROTECTED: [u8; 34] = [
     0x5F, 0xF4, 0xB8, 0x65, 0x8C, 0x66, 0x6A, 0x6C, 0xF8, 0x8E,
 ];
 
+// Test vector 6 (uses context from test vector 3 & parts from vector 4)
+
+pub const REQ_CTX_OPTION: [u8; 11] = [
+    0x19, 0x14, 0x08, 0x37, 0xCB, 0xF3, 0x21, 0x00, 0x17, 0xA2, 0xD3,
+];
+
 // Custom test vectors ----------------------------------------------------
 
 pub const CRASH_OPTION: [u8; 2] = [0b0000_1101, 0x01];
+pub const CRASH_OPTION_CONTEXT: [u8; 4] = [0b0001_1001, 0x01, 0x05, 0xAA];
 pub const SERVER_NONCE_LONG_PIV: [u8; 13] = [
     0x41, 0x22, 0xD4, 0xDD, 0x6D, 0x94, 0x41, 0x69, 0xEE, 0xFB, 0x54, 0x98,
     0x7C,
 ];
 pub const SERVER_ID_LONG: [u8; 10] =
     [0x01, 0x02, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01];
+
+// Like test vector 1, but with HKDF SHA-512
+
+pub const CLIENT_KEY_SHA512: [u8; 16] = [
+    0xCB, 0x7F, 0x4A, 0x1E, 0xCF, 0x94, 0x23, 0xBB, 0x47, 0x02, 0x62, 0xEC,
+    0x67, 0x03, 0x02, 0xDC,
+];
+pub const SERVER_KEY_SHA512: [u8; 16] = [
+    0xBB, 0xAF, 0x9D, 0xE7, 0xB4, 0x37, 0xDD, 0x18, 0x00, 0xD9, 0x23, 0xBB,
+    0x1C, 0x02, 0x62, 0xA7,
+];
+pub const COMMON_IV_SHA512: [u8; 13] = [
+    0x61, 0xBB, 0x6F, 0x71, 0x45, 0xBE, 0xE3, 0xEE, 0xE8, 0xCE, 0xC8, 0x1D,
+    0x12,
+];
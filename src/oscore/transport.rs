@@ -0,0 +1,293 @@
+//! The framing of CoAP messages on the different transports.
+//!
+//! OSCORE only protects the code, options and payload of a message, so the
+//! processing is the same for CoAP over UDP (RFC 7252) and over reliable
+//! transports (RFC 8323). These only differ in the header: over TCP and TLS
+//! it has the length and token length instead of the version, type and
+//! message ID, and over WebSockets the length is left out, since the frames
+//! carry it already.
+
+use alloc::vec::Vec;
+use coap_lite::{
+    error::MessageError, CoapOption, MessageClass, Packet, RequestType,
+};
+
+use super::{error::Error, Result};
+
+/// The transport a CoAP message is framed for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    /// CoAP over UDP or DTLS (RFC 7252).
+    Udp,
+    /// CoAP over TCP or TLS (RFC 8323 Section 3).
+    Tcp,
+    /// CoAP over WebSockets (RFC 8323 Section 4).
+    WebSocket,
+}
+
+/// The class of the signaling codes (RFC 8323 Section 5).
+const SIGNALING_CLASS: u8 = 7;
+/// The code of FETCH (RFC 8132), which coap_lite doesn't know.
+pub const FETCH: u8 = 0x05;
+
+/// Returns the CoAP message framed for the transport as a `Packet`.
+///
+/// Signaling messages are refused, since they only concern a single
+/// connection and are never protected with OSCORE.
+///
+/// # Arguments
+/// * `msg` - The CoAP message.
+/// * `transport` - The transport the message is framed for.
+pub fn decode(msg: &[u8], transport: Transport) -> Result<Packet> {
+    if transport == Transport::Udp {
+        check_code(*msg.get(1).ok_or(MessageError::InvalidPacketLength)?)?;
+        return Ok(Packet::from_bytes(msg)?);
+    }
+
+    let first = *msg.first().ok_or(MessageError::InvalidPacketLength)?;
+    let (length, token_length) = (first >> 4, (first & 0x0F) as usize);
+    let code_position = code_position(first, transport)?;
+    let code = *msg
+        .get(code_position)
+        .ok_or(MessageError::InvalidPacketLength)?;
+    check_code(code)?;
+    let token_start = code_position + 1;
+    if token_length > 8 || token_start + token_length > msg.len() {
+        return Err(MessageError::InvalidTokenLength.into());
+    }
+    if transport == Transport::Tcp {
+        let length = extended_length(length, &msg[1..code_position]);
+        if length != (msg.len() - token_start - token_length) as u64 {
+            return Err(MessageError::InvalidPacketLength.into());
+        }
+    }
+
+    // Let coap_lite parse it with the header it understands
+    // [ver_t_tkl, code, message_id, message_id]
+    let mut udp = vec![0x40 | token_length as u8, code, 0x00, 0x00];
+    udp.extend(&msg[token_start..]);
+
+    Ok(Packet::from_bytes(&udp)?)
+}
+
+/// Returns the `Packet` framed for the transport.
+///
+/// Over the reliable transports, the type and message ID are dropped.
+///
+/// # Arguments
+/// * `packet` - The CoAP message.
+/// * `transport` - The transport to frame the message for.
+pub fn encode(packet: &Packet, transport: Transport) -> Result<Vec<u8>> {
+    let udp = packet.to_bytes()?;
+    if transport == Transport::Udp {
+        return Ok(udp);
+    }
+
+    let token_length = udp[0] & 0x0F;
+    // The token, options and payload
+    let rest = &udp[4..];
+    let length = rest.len() - token_length as usize;
+    let mut msg = Vec::with_capacity(rest.len() + 6);
+    match transport {
+        Transport::Tcp if length < 13 => {
+            msg.push((length as u8) << 4 | token_length);
+        }
+        Transport::Tcp if length < 269 => {
+            msg.push(13 << 4 | token_length);
+            msg.push((length - 13) as u8);
+        }
+        Transport::Tcp if length < 65805 => {
+            msg.push(14 << 4 | token_length);
+            msg.extend(&((length - 269) as u16).to_be_bytes());
+        }
+        Transport::Tcp => {
+            msg.push(15 << 4 | token_length);
+            msg.extend(&((length - 65805) as u32).to_be_bytes());
+        }
+        _ => msg.push(token_length),
+    }
+    msg.push(udp[1]);
+    msg.extend(rest);
+
+    Ok(msg)
+}
+
+/// Returns the protected message framed for the transport.
+///
+/// Requests that keep an outer Observe have to be sent as FETCH (RFC 8613
+/// Section 4.2). Since coap_lite has no FETCH, their `Packet` has POST,
+/// which is replaced here.
+///
+/// # Arguments
+/// * `packet` - The OSCORE message.
+/// * `transport` - The transport to frame the message for.
+pub(crate) fn encode_oscore(
+    packet: &Packet,
+    transport: Transport,
+) -> Result<Vec<u8>> {
+    if packet.header.code == MessageClass::Request(RequestType::Post)
+        && packet.get_option(CoapOption::Observe).is_some()
+    {
+        return encode_with_code(packet, FETCH, transport);
+    }
+
+    encode(packet, transport)
+}
+
+/// Returns the code of the CoAP message framed for the transport.
+///
+/// # Arguments
+/// * `msg` - The CoAP message.
+/// * `transport` - The transport the message is framed for.
+pub(crate) fn code(msg: &[u8], transport: Transport) -> Result<u8> {
+    let first = *msg.first().ok_or(MessageError::InvalidPacketLength)?;
+    let position = code_position(first, transport)?;
+
+    Ok(*msg.get(position).ok_or(MessageError::InvalidPacketLength)?)
+}
+
+/// Returns the `Packet` framed for the transport, with the given code.
+///
+/// This keeps codes that coap_lite doesn't know, like FETCH, when a
+/// message is parsed and encoded again.
+///
+/// # Arguments
+/// * `packet` - The CoAP message.
+/// * `code` - The code to frame the message with.
+/// * `transport` - The transport to frame the message for.
+pub(crate) fn encode_with_code(
+    packet: &Packet,
+    code: u8,
+    transport: Transport,
+) -> Result<Vec<u8>> {
+    let mut msg = encode(packet, transport)?;
+    let position = code_position(msg[0], transport)?;
+    msg[position] = code;
+
+    Ok(msg)
+}
+
+/// Returns the position of the code, which follows the extended length
+/// over TCP.
+///
+/// # Arguments
+/// * `first` - The first byte of the message.
+/// * `transport` - The transport the message is framed for.
+fn code_position(first: u8, transport: Transport) -> Result<usize> {
+    // The number of bytes of the extended length
+    let extended = match (transport, first >> 4) {
+        (Transport::Udp, _) => 0,
+        // The frame holds exactly one message
+        (Transport::WebSocket, 0) => 0,
+        (Transport::WebSocket, _) => {
+            return Err(MessageError::InvalidHeader.into())
+        }
+        (_, 13) => 1,
+        (_, 14) => 2,
+        (_, 15) => 4,
+        _ => 0,
+    };
+
+    Ok(1 + extended)
+}
+
+/// Throws an error if the code is a signaling code.
+fn check_code(code: u8) -> Result<()> {
+    if code >> 5 == SIGNALING_CLASS {
+        Err(Error::Signaling)
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the length of the options and payload from the `Len` field and
+/// the extended length following it.
+fn extended_length(length: u8, extended: &[u8]) -> u64 {
+    let value = extended
+        .iter()
+        .fold(0, |value, &byte| value << 8 | u64::from(byte));
+    match length {
+        13 => value + 13,
+        14 => value + 269,
+        15 => value + 65805,
+        _ => u64::from(length),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_vectors::*;
+    use super::*;
+
+    #[test]
+    fn framing() {
+        let packet = Packet::from_bytes(&REQ_UNPROTECTED).unwrap();
+        // Len 13 and TKL 4, then the extended length for the 14 bytes of
+        // options, the code, token and options
+        let mut tcp = vec![0xD4, 0x01, 0x01];
+        tcp.extend(&REQ_UNPROTECTED[4..]);
+        assert_eq!(tcp, encode(&packet, Transport::Tcp).unwrap());
+        let decoded = decode(&tcp, Transport::Tcp).unwrap();
+        assert_eq!(tcp, encode(&decoded, Transport::Tcp).unwrap());
+        // Without the length on WebSockets
+        let mut ws = vec![0x04, 0x01];
+        ws.extend(&REQ_UNPROTECTED[4..]);
+        assert_eq!(ws, encode(&packet, Transport::WebSocket).unwrap());
+        let decoded = decode(&ws, Transport::WebSocket).unwrap();
+        assert_eq!(ws, encode(&decoded, Transport::WebSocket).unwrap());
+        assert_eq!(
+            &REQ_UNPROTECTED[..],
+            &encode(&packet, Transport::Udp).unwrap()[..]
+        );
+
+        // The extended lengths
+        for &(payload_len, header) in [
+            (11, &[0xC0][..]),
+            (12, &[0xD0, 0x00]),
+            (267, &[0xD0, 0xFF]),
+            (268, &[0xE0, 0x00, 0x00]),
+            (300, &[0xE0, 0x00, 0x20]),
+        ]
+        .iter()
+        {
+            let mut packet = Packet::new();
+            packet.payload = vec![0xAB; payload_len];
+            let tcp = encode(&packet, Transport::Tcp).unwrap();
+            // The payload marker counts as well
+            assert_eq!(header, &tcp[..header.len()]);
+            assert_eq!(
+                packet.payload,
+                decode(&tcp, Transport::Tcp).unwrap().payload
+            );
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        // The length has to match
+        let mut tcp = vec![0xD4, 0x02, 0x01];
+        tcp.extend(&REQ_UNPROTECTED[4..]);
+        assert!(decode(&tcp, Transport::Tcp).is_err());
+        assert!(decode(&tcp[..tcp.len() - 1], Transport::Tcp).is_err());
+        // WebSockets have no length
+        assert!(decode(&tcp, Transport::WebSocket).is_err());
+        // Truncated headers
+        assert!(decode(&[], Transport::Tcp).is_err());
+        assert!(decode(&[0x14, 0x01, 0x00], Transport::Tcp).is_err());
+        assert!(decode(&[0xD0], Transport::Tcp).is_err());
+
+        // Signaling messages, here a 7.01 CSM and a 7.02 Ping
+        assert_eq!(
+            Error::Signaling,
+            decode(&[0x00, 0xE1], Transport::Tcp).unwrap_err()
+        );
+        assert_eq!(
+            Error::Signaling,
+            decode(&[0x00, 0xE2], Transport::WebSocket).unwrap_err()
+        );
+        assert_eq!(
+            Error::Signaling,
+            decode(&[0x40, 0xE2, 0x00, 0x00], Transport::Udp).unwrap_err()
+        );
+    }
+}
@@ -3,62 +3,147 @@ use coap_lite::{CoapOption, Packet};
 use core::convert::TryFrom;
 use hkdf::Hkdf;
 use serde_bytes::Bytes;
-use sha2::Sha256;
+use sha2::{Sha256, Sha512};
 
 use crate::cbor;
 
-use super::{error::Error, Result};
-
-pub const KEY_LEN: usize = 16;
-pub const NONCE_LEN: usize = 13;
+use super::{
+    algorithm::{AeadAlgorithm, HkdfAlgorithm},
+    error::Error,
+    option::{OscoreOption, OscoreOptionRef},
+    Result,
+};
 
 /// Returns the CBOR encoded `info` structure.
 ///
 /// # Arguments
 /// * `id` - The sender ID or recipient ID (or empty for IV).
+/// * `id_context` - The ID Context, if any.
+/// * `alg_aead` - The COSE identifier of the AEAD algorithm.
 /// * `type` - Either "Key" or "IV".
 /// * `l` - The size of the key/nonce for the AEAD, in bytes.
-pub fn build_info(id: &[u8], r#type: &str, l: usize) -> Result<Vec<u8>> {
+pub fn build_info(
+    id: &[u8],
+    id_context: Option<&[u8]>,
+    alg_aead: i32,
+    r#type: &str,
+    l: usize,
+) -> Result<Vec<u8>> {
     // (id, id_context, alg_aead, type, L)
-    let info = (Bytes::new(id), (), 10, r#type, l);
+    let info = (
+        Bytes::new(id),
+        id_context.map(Bytes::new),
+        alg_aead,
+        r#type,
+        l,
+    );
     // Return the CBOR encoded version of that
     Ok(cbor::encode(info)?)
 }
 
+/// Returns the CBOR encoded `info` structure for the derivation of a new
+/// master secret in a key update.
+///
+/// # Arguments
+/// * `x` - The `x` values of the key update, concatenated.
+/// * `nonce` - The nonces of the key update, concatenated.
+/// * `l` - The key length of the AEAD algorithm, in bytes.
+pub fn build_update_info(x: &[u8], nonce: &[u8], l: usize) -> Result<Vec<u8>> {
+    // (L, label, X_N) with X_N being the CBOR sequence of x and nonce
+    let mut x_n = cbor::encode(Bytes::new(x))?;
+    x_n.extend(cbor::encode(Bytes::new(nonce))?);
+    let info = (l, "oscore key update", Bytes::new(&x_n));
+
+    Ok(cbor::encode(info)?)
+}
+
 /// Returns the derived key/IV for this `info` structure.
 ///
 /// # Arguments
+/// * `alg_hkdf` - The HKDF algorithm.
 /// * `master_secret` - The master secret.
 /// * `master_salt` - The master salt.
 /// * `info` - The `info` structure, different for key and IV derivation.
 /// * `l` - The size of the key/nonce for the AEAD used, in bytes.
 pub fn hkdf(
+    alg_hkdf: HkdfAlgorithm,
     master_secret: &[u8],
     master_salt: &[u8],
     info: &[u8],
     l: usize,
 ) -> Result<Vec<u8>> {
-    // This is the extract step, resulting in the pseudorandom key (PRK)
-    let h = Hkdf::<Sha256>::new(Some(master_salt), master_secret);
-    // Expand the PRK to the desired length output keying material (OKM)
     let mut okm = vec![0; l];
-    h.expand(info, &mut okm)?;
+    // This is the extract step, resulting in the pseudorandom key (PRK).
+    // Then we expand the PRK to the desired length output keying material
+    // (OKM).
+    match alg_hkdf {
+        HkdfAlgorithm::Sha256 => {
+            Hkdf::<Sha256>::new(Some(master_salt), master_secret)
+                .expand(info, &mut okm)?
+        }
+        HkdfAlgorithm::Sha512 => {
+            Hkdf::<Sha512>::new(Some(master_salt), master_secret)
+                .expand(info, &mut okm)?
+        }
+    }
+
+    Ok(okm)
+}
+
+/// Returns the output of HKDF-Expand for this `info` structure, without the
+/// extract step.
+///
+/// # Arguments
+/// * `alg_hkdf` - The HKDF algorithm.
+/// * `prk` - The pseudorandom key, here the current master secret.
+/// * `info` - The `info` structure.
+/// * `l` - The length of the output, in bytes.
+pub fn hkdf_expand(
+    alg_hkdf: HkdfAlgorithm,
+    prk: &[u8],
+    info: &[u8],
+    l: usize,
+) -> Result<Vec<u8>> {
+    let mut okm = vec![0; l];
+    match alg_hkdf {
+        HkdfAlgorithm::Sha256 => Hkdf::<Sha256>::from_prk(&pad_prk(prk, 32))
+            .map_err(|_| Error::Hkdf(hkdf::InvalidLength))?
+            .expand(info, &mut okm)?,
+        HkdfAlgorithm::Sha512 => Hkdf::<Sha512>::from_prk(&pad_prk(prk, 64))
+            .map_err(|_| Error::Hkdf(hkdf::InvalidLength))?
+            .expand(info, &mut okm)?,
+    }
 
     Ok(okm)
 }
 
+/// Returns the PRK padded with zeros to the hash length, which `hkdf`
+/// requires at least.
+///
+/// HMAC pads keys shorter than the block size with zeros anyway, so this
+/// doesn't change the output.
+fn pad_prk(prk: &[u8], hash_len: usize) -> Vec<u8> {
+    let mut padded = prk.to_vec();
+    if padded.len() < hash_len {
+        padded.resize(hash_len, 0);
+    }
+
+    padded
+}
+
 /// Returns the CBOR encoded AAD array.
 ///
 /// There's no argument for class I options, because the standard doesn't
 /// define any at this point.
 pub fn build_aad_array(
+    alg_aead: i32,
     request_kid: &[u8],
     request_piv: &[u8],
 ) -> Result<Vec<u8>> {
     // (oscore_version, algorithms, request_kid, request_piv, options)
     let arr = (
         1,
-        [10],
+        [alg_aead],
         Bytes::new(request_kid),
         Bytes::new(request_piv),
         Bytes::new(&[]),
@@ -68,9 +153,13 @@ pub fn build_aad_array(
 }
 
 /// Returns the AAD.
-pub fn build_aad(request_kid: &[u8], request_piv: &[u8]) -> Result<Vec<u8>> {
+pub fn build_aad(
+    alg_aead: i32,
+    request_kid: &[u8],
+    request_piv: &[u8],
+) -> Result<Vec<u8>> {
     // First we need to construct the AAD array containing our parameters
-    let aad_arr = build_aad_array(request_kid, request_piv)?;
+    let aad_arr = build_aad_array(alg_aead, request_kid, request_piv)?;
     // Then we pack it into an Encrypt0 structure
     let aad = ("Encrypt0", Bytes::new(&[]), Bytes::new(&aad_arr));
     // And return the encoding of that
@@ -78,105 +167,118 @@ pub fn build_aad(request_kid: &[u8], request_piv: &[u8]) -> Result<Vec<u8>> {
 }
 
 /// Returns the value of the OSCORE option.
-pub fn build_oscore_option(kid: Option<&[u8]>, piv: Option<&[u8]>) -> Vec<u8> {
-    // Allocate memory for the flag byte and piv and kid
-    let mut option =
-        vec![0; 1 + piv.map_or(0, |p| p.len()) + kid.map_or(0, |k| k.len())];
-    // If we have neither kid nor piv, our option has no value
-    if option.len() == 1 {
-        option.pop();
-        return option;
-    }
+pub fn build_oscore_option(
+    kid: Option<&[u8]>,
+    piv: Option<&[u8]>,
+    kid_context: Option<&[u8]>,
+) -> Result<Vec<u8>> {
+    build_oscore_option_kudos(kid, piv, kid_context, None)
+}
 
-    if let Some(piv) = piv {
-        // Set the partial IV length (3 least significant bits of flag byte)
-        option[0] |= piv.len() as u8 & 0b0000_0111;
-        // Copy in the partial IV
-        option[1..=piv.len()].copy_from_slice(piv);
+/// Returns the value of the OSCORE option, with the `x` and `nonce` values
+/// of a key update if present.
+pub fn build_oscore_option_kudos(
+    kid: Option<&[u8]>,
+    piv: Option<&[u8]>,
+    kid_context: Option<&[u8]>,
+    kudos: Option<(u8, &[u8])>,
+) -> Result<Vec<u8>> {
+    OscoreOptionRef {
+        piv,
+        kid_context,
+        kudos,
+        kid,
+        group: false,
     }
+    .encode()
+}
 
-    if let Some(kid) = kid {
-        // Set the kid flag
-        option[0] |= 0b0000_1000;
-        // Copy in the kid
-        option[1 + piv.map_or(0, |p| p.len())..].copy_from_slice(kid);
-    }
+/// The `kid`, `piv` and `kid context` values of an OSCORE option.
+pub type OptionValues = (Option<Vec<u8>>, Option<Vec<u8>>, Option<Vec<u8>>);
+
+/// The `x` and `nonce` values of a key update in an OSCORE option.
+pub type KudosValues = (u8, Vec<u8>);
+
+/// Returns the `kid`, `piv` and `kid context` values from the message, if
+/// present.
+pub fn extract_kid_piv_context(message: &Packet) -> Result<OptionValues> {
+    let option = decode_oscore_option(message)?;
+
+    Ok((option.kid, option.piv, option.kid_context))
+}
 
-    option
+/// Returns the `x` and `nonce` values of a key update from the message, if
+/// present.
+pub fn extract_kudos(message: &Packet) -> Result<Option<KudosValues>> {
+    Ok(decode_oscore_option(message)?.kudos)
 }
 
-/// Returns the `kid` and `piv` values from the message, if present.
-#[allow(clippy::type_complexity)]
-pub fn extract_kid_piv(
-    message: &Packet,
-) -> Result<(Option<Vec<u8>>, Option<Vec<u8>>)> {
+/// Returns the decoded OSCORE option of the message.
+fn decode_oscore_option(message: &Packet) -> Result<OscoreOption> {
     let option_value = message
         .get_option(CoapOption::Oscore)
         .ok_or(Error::NoOscoreOption)?
         .front()
         .ok_or(Error::NoOscoreOption)?;
 
-    Ok(extract_oscore_option(option_value))
+    OscoreOption::decode(option_value)
 }
 
-/// Returns the encoded `kid` and `piv` values from the option, if present.
-fn extract_oscore_option(value: &[u8]) -> (Option<Vec<u8>>, Option<Vec<u8>>) {
-    // Handle empty option
-    if value.is_empty() {
-        return (None, None);
-    }
+/// Returns the nonce for the AEAD.
+///
+/// The nonce has the same length as the Common IV, which depends on the AEAD
+/// algorithm.
+pub fn compute_nonce(piv: &[u8], id_piv: &[u8], common_iv: &[u8]) -> Vec<u8> {
+    let mut nonce = vec![0; common_iv.len()];
+    write_nonce(piv, id_piv, common_iv, &mut nonce);
 
-    // Unpack piv if present
-    let (piv, piv_len) = match value[0] & 0b0000_0111 {
-        0 => (None, 0),
-        n => {
-            // Check if we really received enough data
-            if value.len() > n as usize {
-                (Some(Vec::from(&value[1..=n as usize])), n)
-            } else {
-                // If not, abort
-                return (None, None);
-            }
-        }
-    };
-    // Unpack kid if present
-    let kid = match value[0] & 0b0000_1000 {
-        0 => None,
-        _ => Some(Vec::from(&value[1 + piv_len as usize..])),
-    };
+    nonce
+}
 
-    (kid, piv)
+/// Returns an error if the sender or recipient ID is too long for the
+/// nonce of the AEAD, which has room for `nonce_len - 6` bytes of it (RFC 8613
+/// Section 3.3).
+pub fn check_id(id: &[u8], alg_aead: AeadAlgorithm) -> Result<()> {
+    if id.len() > alg_aead.nonce_len() - 6 {
+        Err(Error::InvalidId)
+    } else {
+        Ok(())
+    }
 }
 
-/// Returns the nonce for the AEAD.
-pub fn compute_nonce(
+/// Writes the nonce for the AEAD into the buffer, which has to have the
+/// length of the Common IV.
+pub fn write_nonce(
     mut piv: &[u8],
     mut id_piv: &[u8],
-    common_iv: &[u8; NONCE_LEN],
-) -> [u8; NONCE_LEN] {
-    // Since id_piv could be longer than it should, trim it if necessary
-    if id_piv.len() > NONCE_LEN - 6 {
-        id_piv = &id_piv[id_piv.len() - (NONCE_LEN - 6)..]
+    common_iv: &[u8],
+    nonce: &mut [u8],
+) {
+    let nonce_len = common_iv.len();
+    // Contexts refuse longer IDs, but trim it in case it's longer anyway
+    if id_piv.len() > nonce_len - 6 {
+        id_piv = &id_piv[id_piv.len() - (nonce_len - 6)..]
     }
     // Same for the piv itself
     if piv.len() > 5 {
         piv = &piv[piv.len() - 5..];
     }
 
-    let mut nonce = [0; NONCE_LEN];
+    // Start from zeros, in case the buffer has been used before
+    for b in nonce.iter_mut() {
+        *b = 0;
+    }
     // Left-pad the Partial IV (PIV) with zeros to exactly 5 bytes
-    nonce[NONCE_LEN - piv.len()..].copy_from_slice(&piv);
+    nonce[nonce_len - piv.len()..].copy_from_slice(piv);
     // Left-pad ID_PIV with zeros to exactly nonce length minus 6 bytes
-    nonce[1 + NONCE_LEN - 6 - id_piv.len()..NONCE_LEN - 5]
-        .copy_from_slice(&id_piv);
+    nonce[1 + nonce_len - 6 - id_piv.len()..nonce_len - 5]
+        .copy_from_slice(id_piv);
     // Add the size of the ID_PIV (a single byte S)
     nonce[0] = id_piv.len() as u8;
     // XOR with common IV
     for (b1, b2) in nonce.iter_mut().zip(common_iv.iter()) {
         *b1 ^= b2;
     }
-
-    nonce
 }
 
 /// Returns the `piv` as a u64.
@@ -207,6 +309,10 @@ pub fn format_piv(piv: u64) -> Vec<u8> {
 }
 
 /// Represents a split-up Proxy-Uri.
+///
+/// The scheme and host are lowercase and the port is left out if it's the
+/// default one of the scheme, so equivalent URIs result in the same value.
+/// The path and query are still percent-encoded.
 #[derive(Debug, PartialEq)]
 pub struct ProxyUri {
     pub proxy_scheme: String,
@@ -227,80 +333,142 @@ impl TryFrom<&[u8]> for ProxyUri {
     /// better option, I have to write this abomination.
     fn try_from(bytes: &[u8]) -> Result<ProxyUri> {
         // Convert to a String we can work with
-        let mut proxy_uri = String::from_utf8(bytes.to_vec())?;
+        let proxy_uri = String::from_utf8(bytes.to_vec())?;
 
-        // Take the Uri-Scheme out
+        // Take the Uri-Scheme out, which has to be followed by '://'
         let scheme_end = proxy_uri.find(':').ok_or(Error::InvalidProxyUri)?;
-        let proxy_scheme: String = proxy_uri.drain(..scheme_end).collect();
-        // Drain the next three characters which should be '://'
-        proxy_uri.drain(..3);
-
-        // Take the Uri-Host out
-        let host_end = if let Some(port_separator) = proxy_uri.find(':') {
-            port_separator
-        } else if let Some(path_separator) = proxy_uri.find('/') {
-            path_separator
-        } else if let Some(query_separator) = proxy_uri.find('?') {
-            query_separator
+        let proxy_scheme = proxy_uri[..scheme_end].to_ascii_lowercase();
+        let rest = proxy_uri[scheme_end..]
+            .strip_prefix("://")
+            .ok_or(Error::InvalidProxyUri)?;
+
+        // The authority ends with the path or query
+        let authority_end = rest.find(['/', '?']).unwrap_or(rest.len());
+        let (authority, rest) = rest.split_at(authority_end);
+        // IPv6 literals are in brackets and contain colons themselves
+        let host_end = if authority.starts_with('[') {
+            authority.find(']').ok_or(Error::InvalidProxyUri)? + 1
         } else {
-            proxy_uri.len()
+            authority.find(':').unwrap_or(authority.len())
         };
-        let uri_host: String = proxy_uri.drain(..host_end).collect();
-
-        // Take the Uri-Port out
-        let port_end = if let Some(port_separator) = proxy_uri.find(':') {
-            proxy_uri.remove(port_separator);
-            if let Some(path_separator) = proxy_uri.find('/') {
-                path_separator
-            } else if let Some(query_separator) = proxy_uri.find('?') {
-                query_separator
-            } else {
-                proxy_uri.len()
-            }
-        } else {
-            0
-        };
-        let uri_port: String = proxy_uri.drain(..port_end).collect();
-        // Now we can remove the leading path separator, if any
-        if let Some(path_separator) = proxy_uri.find('/') {
-            proxy_uri.remove(path_separator);
+        let (uri_host, port) = authority.split_at(host_end);
+        if uri_host.is_empty() {
+            return Err(Error::InvalidProxyUri);
         }
 
-        // Take the path out
-        let path_end = if let Some(query_separator) = proxy_uri.find('?') {
-            proxy_uri.remove(query_separator);
-            query_separator
-        } else {
-            proxy_uri.len()
+        // Take the Uri-Port out, leaving out the default one
+        let uri_port = match port {
+            "" | ":" => None,
+            _ => {
+                let port = port
+                    .strip_prefix(':')
+                    .ok_or(Error::InvalidProxyUri)?
+                    .parse::<u16>()
+                    .map_err(|_| Error::InvalidProxyUri)?;
+                if Some(port) == default_port(&proxy_scheme) {
+                    None
+                } else {
+                    Some(format!("{}", port))
+                }
+            }
         };
-        let uri_path: String = proxy_uri.drain(..path_end).collect();
 
-        // Whatever remains is the query
-        let uri_query = proxy_uri;
+        // Take the path and whatever remains is the query
+        let (uri_path, uri_query) = match rest.find('?') {
+            Some(query_separator) => {
+                (&rest[..query_separator], &rest[query_separator + 1..])
+            }
+            None => (rest, ""),
+        };
+        // Now we can remove the leading path separator, if any
+        let uri_path = uri_path.strip_prefix('/').unwrap_or(uri_path);
+        // Make sure the lists can be decoded
+        percent_decode(uri_path)?;
+        percent_decode(uri_query)?;
 
         Ok(ProxyUri {
             proxy_scheme,
-            uri_host,
-            uri_port: if uri_port.is_empty() {
-                None
-            } else {
-                Some(uri_port)
-            },
+            uri_host: uri_host.to_ascii_lowercase(),
+            uri_port,
             uri_path: if uri_path.is_empty() {
                 None
             } else {
-                Some(uri_path)
+                Some(String::from(uri_path))
             },
             uri_query: if uri_query.is_empty() {
                 None
             } else {
-                Some(uri_query)
+                Some(String::from(uri_query))
             },
         })
     }
 }
 
 impl ProxyUri {
+    /// Returns the `ProxyUri` for the target of a request, from either its
+    /// Proxy-Uri or its Proxy-Scheme, Uri-Host and Uri-Port options,
+    /// together with the Uri-Path and Uri-Query options.
+    ///
+    /// Returns `None` if the request isn't meant for a forward proxy.
+    pub fn from_options(packet: &Packet) -> Result<Option<ProxyUri>> {
+        let first = |option| {
+            packet
+                .get_option(option)
+                .and_then(|value_list| value_list.front())
+        };
+        let mut proxy_uri =
+            if let Some(proxy_uri) = first(CoapOption::ProxyUri) {
+                ProxyUri::try_from(&proxy_uri[..])?
+            } else if let Some(proxy_scheme) = first(CoapOption::ProxyScheme) {
+                // Without a Uri-Host, we'd need the destination address
+                let host = first(CoapOption::UriHost)
+                    .ok_or(Error::InvalidProxyUri)?;
+                let host = String::from_utf8(host.clone())?;
+                let mut uri = String::from_utf8(proxy_scheme.clone())?;
+                uri += "://";
+                // Uri-Host may have an IPv6 literal without the brackets
+                if host.contains(':') && !host.starts_with('[') {
+                    uri += &format!("[{}]", host);
+                } else {
+                    uri += &host;
+                }
+                if let Some(port) = first(CoapOption::UriPort) {
+                    let port = port
+                        .iter()
+                        .fold(0u32, |port, &byte| port << 8 | u32::from(byte));
+                    uri += &format!(":{}", port);
+                }
+                ProxyUri::try_from(uri.as_bytes())?
+            } else {
+                return Ok(None);
+            };
+
+        // The path and query can only come from one place
+        let path = packet.get_option(CoapOption::UriPath);
+        let query = packet.get_option(CoapOption::UriQuery);
+        if (path.is_some() && proxy_uri.uri_path.is_some())
+            || (query.is_some() && proxy_uri.uri_query.is_some())
+        {
+            return Err(Error::InvalidProxyUri);
+        }
+        if let Some(path) = path {
+            let segments: Vec<String> = path
+                .iter()
+                .map(|segment| percent_encode(segment, b":@&"))
+                .collect();
+            proxy_uri.uri_path = Some(segments.join("/"));
+        }
+        if let Some(query) = query {
+            let arguments: Vec<String> = query
+                .iter()
+                .map(|argument| percent_encode(argument, b":@/?"))
+                .collect();
+            proxy_uri.uri_query = Some(arguments.join("&"));
+        }
+
+        Ok(Some(proxy_uri))
+    }
+
     /// Returns a `LinkedList` of the path components to be added as option
     /// values.
     pub fn get_path_list(&self) -> Option<LinkedList<Vec<u8>>> {
@@ -309,7 +477,7 @@ impl ProxyUri {
                 uri_path
                     .split('/')
                     .filter(|e| !e.is_empty())
-                    .map(|s| s.as_bytes().to_vec())
+                    .map(decode_component)
                     .collect(),
             ),
             None => None,
@@ -324,7 +492,7 @@ impl ProxyUri {
                 uri_query
                     .split('&')
                     .filter(|e| !e.is_empty())
-                    .map(|s| s.as_bytes().to_vec())
+                    .map(decode_component)
                     .collect(),
             ),
             None => None,
@@ -343,88 +511,323 @@ impl ProxyUri {
 
         proxy_uri_str.into_bytes()
     }
+
+    /// Returns the complete Proxy-Uri including the path and query, as
+    /// composed in RFC 7252 Section 6.5.
+    pub fn compose_full_uri(&self) -> Vec<u8> {
+        let mut proxy_uri = self.compose_proxy_uri();
+        // The path is at least the separator
+        proxy_uri.push(b'/');
+        if let Some(ref path) = self.uri_path {
+            proxy_uri.extend(path.as_bytes());
+        }
+        if let Some(ref query) = self.uri_query {
+            proxy_uri.push(b'?');
+            proxy_uri.extend(query.as_bytes());
+        }
+
+        proxy_uri
+    }
+}
+
+/// Replaces the options describing the target of a request for a forward
+/// proxy with a single Proxy-Uri containing the path and query.
+///
+/// This way the proxy gets the same request, no matter whether the client
+/// used Proxy-Uri or Proxy-Scheme.
+pub fn recompose_proxy_uri(packet: &mut Packet) -> Result<()> {
+    let proxy_uri = match ProxyUri::from_options(packet)? {
+        Some(proxy_uri) => proxy_uri,
+        None => return Ok(()),
+    };
+    for option in [
+        CoapOption::ProxyScheme,
+        CoapOption::UriHost,
+        CoapOption::UriPort,
+        CoapOption::UriPath,
+        CoapOption::UriQuery,
+    ]
+    .iter()
+    {
+        packet.clear_option(*option);
+    }
+    let mut uri_list = LinkedList::new();
+    uri_list.push_back(proxy_uri.compose_full_uri());
+    packet.set_option(CoapOption::ProxyUri, uri_list);
+
+    Ok(())
+}
+
+/// Returns the default port of a scheme, if it's known.
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "coap" | "coap+tcp" => Some(5683),
+        "coaps" | "coaps+tcp" => Some(5684),
+        "coap+ws" | "http" => Some(80),
+        "coaps+ws" | "https" => Some(443),
+        _ => None,
+    }
+}
+
+/// Returns the percent-decoded bytes of a URI component.
+fn percent_decode(component: &str) -> Result<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(component.len());
+    let mut bytes = component.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+        let mut hex_digit = || {
+            bytes
+                .next()
+                .and_then(|digit| char::from(digit).to_digit(16))
+                .ok_or(Error::InvalidProxyUri)
+        };
+        decoded.push((hex_digit()? << 4 | hex_digit()?) as u8);
+    }
+
+    Ok(decoded)
+}
+
+/// Returns the percent-decoded bytes of a component that's been validated.
+fn decode_component(component: &str) -> Vec<u8> {
+    percent_decode(component).unwrap_or_else(|_| component.as_bytes().to_vec())
+}
+
+/// Returns the percent-encoded URI component, only leaving the unreserved
+/// characters, sub-delimiters except for '&' and the given ones as they are.
+fn percent_encode(component: &[u8], allowed: &[u8]) -> String {
+    let mut encoded = String::with_capacity(component.len());
+    for &byte in component {
+        if byte.is_ascii_alphanumeric()
+            || b"-._~!$'()*+,;=".contains(&byte)
+            || allowed.contains(&byte)
+        {
+            encoded.push(char::from(byte));
+        } else {
+            encoded += &format!("%{:02X}", byte);
+        }
+    }
+
+    encoded
 }
 
 #[cfg(test)]
 mod tests {
-    use super::super::test_vectors::*;
+    use super::super::{option::OptionError, test_vectors::*};
     use super::*;
 
     #[test]
     fn info() {
-        let i_sender = build_info(&CLIENT_ID, "Key", 16).unwrap();
+        let i_sender = build_info(&CLIENT_ID, None, 10, "Key", 16).unwrap();
         assert_eq!(&INFO_CLIENT_KEY, &i_sender[..]);
 
-        let i_recipient = build_info(&SERVER_ID, "Key", 16).unwrap();
+        let i_recipient = build_info(&SERVER_ID, None, 10, "Key", 16).unwrap();
         assert_eq!(&INFO_SERVER_KEY, &i_recipient[..]);
 
-        let i_iv = build_info(&[], "IV", 13).unwrap();
+        let i_iv = build_info(&[], None, 10, "IV", 13).unwrap();
         assert_eq!(&INFO_COMMON_IV, &i_iv[..]);
     }
 
+    #[test]
+    fn info_id_context() {
+        let i_sender =
+            build_info(&CLIENT_ID, Some(&ID_CONTEXT), 10, "Key", 16).unwrap();
+        assert_eq!(&INFO_CLIENT_KEY_CTX, &i_sender[..]);
+
+        let i_recipient =
+            build_info(&SERVER_ID, Some(&ID_CONTEXT), 10, "Key", 16).unwrap();
+        assert_eq!(&INFO_SERVER_KEY_CTX, &i_recipient[..]);
+
+        let i_iv = build_info(&[], Some(&ID_CONTEXT), 10, "IV", 13).unwrap();
+        assert_eq!(&INFO_COMMON_IV_CTX, &i_iv[..]);
+    }
+
+    #[test]
+    fn derivation() {
+        let alg = HkdfAlgorithm::Sha256;
+        let key =
+            hkdf(alg, &MASTER_SECRET, &MASTER_SALT, &INFO_CLIENT_KEY, 16);
+        assert_eq!(&CLIENT_KEY, &key.unwrap()[..]);
+        let iv = hkdf(alg, &MASTER_SECRET, &MASTER_SALT, &INFO_COMMON_IV, 13);
+        assert_eq!(&COMMON_IV, &iv.unwrap()[..]);
+
+        let alg = HkdfAlgorithm::Sha512;
+        let key =
+            hkdf(alg, &MASTER_SECRET, &MASTER_SALT, &INFO_SERVER_KEY, 16);
+        assert_eq!(&SERVER_KEY_SHA512, &key.unwrap()[..]);
+        let iv = hkdf(alg, &MASTER_SECRET, &MASTER_SALT, &INFO_COMMON_IV, 13);
+        assert_eq!(&COMMON_IV_SHA512, &iv.unwrap()[..]);
+    }
+
+    #[test]
+    fn update_derivation() {
+        // (16, "oscore key update", h'4107 41AA')
+        let info = build_update_info(&[0x07], &[0xAA], 16).unwrap();
+        let mut expected = vec![0x83, 0x10, 0x71];
+        expected.extend(b"oscore key update");
+        expected.extend(&[0x44, 0x41, 0x07, 0x41, 0xAA]);
+        assert_eq!(expected, info);
+
+        // Only HKDF-Expand, with the master secret as the PRK
+        let secret =
+            hkdf_expand(HkdfAlgorithm::Sha256, &MASTER_SECRET, &info, 16);
+        assert_eq!(
+            &[
+                0xAD, 0x9D, 0xE0, 0x48, 0x52, 0x4C, 0x75, 0x7E, 0xD9, 0xA8,
+                0x43, 0xA9, 0x09, 0x56, 0x24, 0x30
+            ],
+            &secret.unwrap()[..]
+        );
+        let secret =
+            hkdf_expand(HkdfAlgorithm::Sha512, &MASTER_SECRET, &info, 32);
+        assert_eq!(
+            &[
+                0xD9, 0x12, 0x9A, 0x10, 0xD9, 0x51, 0xAB, 0x6F, 0x3F, 0xC4,
+                0x80, 0xC3, 0xB8, 0x1B, 0xDC, 0xE3, 0xC3, 0x4B, 0x6E, 0x71,
+                0x53, 0x4A, 0x85, 0xA9, 0xF2, 0x9A, 0xC3, 0x0F, 0x8A, 0xDA,
+                0x67, 0xD2
+            ],
+            &secret.unwrap()[..]
+        );
+    }
+
     #[test]
     fn aad_array() {
         let example_aad_arr =
-            build_aad_array(&EXAMPLE_KID, &EXAMPLE_PIV).unwrap();
+            build_aad_array(10, &EXAMPLE_KID, &EXAMPLE_PIV).unwrap();
         assert_eq!(&EXAMPLE_AAD_ARR, &example_aad_arr[..]);
 
-        let v4_aad_arr = build_aad_array(&CLIENT_ID, &REQ_PIV).unwrap();
+        let v4_aad_arr = build_aad_array(10, &CLIENT_ID, &REQ_PIV).unwrap();
         assert_eq!(&REQ_AAD_ARR, &v4_aad_arr[..]);
     }
 
     #[test]
     fn aad() {
-        let example_aad = build_aad(&EXAMPLE_KID, &EXAMPLE_PIV).unwrap();
+        let example_aad = build_aad(10, &EXAMPLE_KID, &EXAMPLE_PIV).unwrap();
         assert_eq!(&EXAMPLE_AAD, &example_aad[..]);
 
-        let v4_aad = build_aad(&CLIENT_ID, &REQ_PIV).unwrap();
+        let v4_aad = build_aad(10, &CLIENT_ID, &REQ_PIV).unwrap();
         assert_eq!(&REQ_AAD, &v4_aad[..]);
     }
 
     #[test]
     fn option_encoding() {
-        assert_eq!(&EX1_OPTION, &build_oscore_option(EX1_KID, EX1_PIV)[..]);
-        assert_eq!(&EX2_OPTION, &build_oscore_option(EX2_KID, EX2_PIV)[..]);
-        assert_eq!(&EX4_OPTION, &build_oscore_option(EX4_KID, EX4_PIV)[..]);
-        assert_eq!(&EX5_OPTION, &build_oscore_option(EX5_KID, EX5_PIV)[..]);
+        assert_eq!(
+            &EX1_OPTION,
+            &build_oscore_option(EX1_KID, EX1_PIV, None).unwrap()[..]
+        );
+        assert_eq!(
+            &EX2_OPTION,
+            &build_oscore_option(EX2_KID, EX2_PIV, None).unwrap()[..]
+        );
+        assert_eq!(
+            &EX3_OPTION,
+            &build_oscore_option(EX3_KID, EX3_PIV, EX3_KID_CONTEXT).unwrap()[..]
+        );
+        assert_eq!(
+            &EX4_OPTION,
+            &build_oscore_option(EX4_KID, EX4_PIV, None).unwrap()[..]
+        );
+        assert_eq!(
+            &EX5_OPTION,
+            &build_oscore_option(EX5_KID, EX5_PIV, None).unwrap()[..]
+        );
     }
 
     #[test]
     fn option_decoding() {
-        let (kid, piv) = extract_oscore_option(&EX1_OPTION);
-        assert_eq!(EX1_KID, kid.as_deref());
-        assert_eq!(EX1_PIV, piv.as_deref());
+        for &(value, kid, piv, kid_context) in [
+            (&EX1_OPTION[..], EX1_KID, EX1_PIV, None),
+            (&EX2_OPTION, EX2_KID, EX2_PIV, None),
+            (&EX3_OPTION, EX3_KID, EX3_PIV, EX3_KID_CONTEXT),
+            (&EX4_OPTION, EX4_KID, EX4_PIV, None),
+            (&EX5_OPTION, EX5_KID, EX5_PIV, None),
+        ]
+        .iter()
+        {
+            let mut packet = Packet::new();
+            packet.add_option(CoapOption::Oscore, value.to_vec());
+            let (k, p, c) = extract_kid_piv_context(&packet).unwrap();
+            assert_eq!(kid, k.as_deref());
+            assert_eq!(piv, p.as_deref());
+            assert_eq!(kid_context, c.as_deref());
+        }
 
-        let (kid, piv) = extract_oscore_option(&EX2_OPTION);
-        assert_eq!(EX2_KID, kid.as_deref());
-        assert_eq!(EX2_PIV, piv.as_deref());
+        // Malformed options are refused instead of read as empty
+        for &(value, error) in [
+            (&CRASH_OPTION[..], OptionError::TruncatedPiv),
+            (&CRASH_OPTION_CONTEXT, OptionError::TruncatedKidContext),
+            (&[0x00], OptionError::EmptyFlags),
+        ]
+        .iter()
+        {
+            let mut packet = Packet::new();
+            packet.add_option(CoapOption::Oscore, value.to_vec());
+            assert_eq!(
+                Error::InvalidOption(error),
+                extract_kid_piv_context(&packet).unwrap_err()
+            );
+        }
+    }
 
-        let (kid, piv) = extract_oscore_option(&EX4_OPTION);
-        assert_eq!(EX4_KID, kid.as_deref());
-        assert_eq!(EX4_PIV, piv.as_deref());
+    #[test]
+    fn option_kudos() {
+        let option = build_oscore_option_kudos(
+            Some(&[0x01]),
+            Some(&[0x05]),
+            Some(&[0xAA, 0xBB]),
+            Some((0x02, &[0x10, 0x11, 0x12])),
+        )
+        .unwrap();
+        assert_eq!(
+            &[
+                0x99, 0x01, 0x05, 0x02, 0xAA, 0xBB, 0x02, 0x10, 0x11, 0x12,
+                0x01
+            ][..],
+            &option[..]
+        );
+        let mut packet = Packet::new();
+        packet.add_option(CoapOption::Oscore, option);
+        assert_eq!(
+            Some((0x02, vec![0x10, 0x11, 0x12])),
+            extract_kudos(&packet).unwrap()
+        );
 
-        let (kid, piv) = extract_oscore_option(&EX5_OPTION);
-        assert_eq!(EX5_KID, kid.as_deref());
-        assert_eq!(EX5_PIV, piv.as_deref());
+        // Only the nonce
+        let option =
+            build_oscore_option_kudos(None, None, None, Some((0, &[7])))
+                .unwrap();
+        assert_eq!(&[0x80, 0x01, 0x00, 0x07][..], &option[..]);
 
-        let (kid, piv) = extract_oscore_option(&CRASH_OPTION);
-        assert_eq!(None, kid);
-        assert_eq!(None, piv);
+        // A partial IV too long for its 3 bits isn't truncated
+        assert_eq!(
+            Error::InvalidOption(OptionError::InvalidPivLength),
+            build_oscore_option(None, Some(&[0; 8]), None).unwrap_err()
+        );
+        // Truncated ones are refused
+        let mut packet = Packet::new();
+        packet.add_option(CoapOption::Oscore, vec![0x80, 0x01, 0x02, 0x10]);
+        assert_eq!(
+            Error::InvalidOption(OptionError::TruncatedNonce),
+            extract_kudos(&packet).unwrap_err()
+        );
     }
 
     #[test]
     fn nonce() {
         assert_eq!(
-            CLIENT_NONCE,
-            compute_nonce(&REQ_PIV, &CLIENT_ID, &COMMON_IV)
+            &CLIENT_NONCE,
+            &compute_nonce(&REQ_PIV, &CLIENT_ID, &COMMON_IV)[..]
         );
         assert_eq!(
-            SERVER_NONCE,
-            compute_nonce(&RES_PIV, &SERVER_ID, &COMMON_IV)
+            &SERVER_NONCE,
+            &compute_nonce(&RES_PIV, &SERVER_ID, &COMMON_IV)[..]
         );
         assert_eq!(
-            SERVER_NONCE_LONG_PIV,
-            compute_nonce(&RES_PIV, &SERVER_ID_LONG, &COMMON_IV)
+            &SERVER_NONCE_LONG_PIV,
+            &compute_nonce(&RES_PIV, &SERVER_ID_LONG, &COMMON_IV)[..]
         );
     }
 
@@ -563,6 +966,114 @@ mod tests {
         );
     }
 
+    #[test]
+    fn proxy_uri_normalization() {
+        // IPv6 literals, the default port and uppercase letters
+        let split = ProxyUri::try_from(
+            "COAP://[2001:DB8::1]:5683/a%20b/c:d?x=%26y".as_bytes(),
+        )
+        .unwrap();
+        assert_eq!("coap", split.proxy_scheme);
+        assert_eq!("[2001:db8::1]", split.uri_host);
+        assert_eq!(None, split.uri_port);
+        assert_eq!(b"coap://[2001:db8::1]"[..], split.compose_proxy_uri()[..]);
+        let mut path_list = LinkedList::new();
+        path_list.push_back(b"a b".to_vec());
+        path_list.push_back(b"c:d".to_vec());
+        assert_eq!(path_list, split.get_path_list().unwrap());
+        let mut query_list = LinkedList::new();
+        query_list.push_back(b"x=&y".to_vec());
+        assert_eq!(query_list, split.get_query_list().unwrap());
+        assert_eq!(
+            b"coap://[2001:db8::1]/a%20b/c:d?x=%26y"[..],
+            split.compose_full_uri()[..]
+        );
+
+        // Other ports are kept
+        let split =
+            ProxyUri::try_from("coaps://[::1]:5683".as_bytes()).unwrap();
+        assert_eq!(Some(String::from("5683")), split.uri_port);
+        assert_eq!(b"coaps://[::1]:5683/"[..], split.compose_full_uri()[..]);
+
+        for invalid in [
+            "coap:/example.com",
+            "coap://",
+            "coap://[::1/a",
+            "coap://example.com:port/a",
+            "coap://example.com:70000",
+            "coap://example.com/a%2",
+            "coap://example.com/?q=%ZZ",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                Error::InvalidProxyUri,
+                ProxyUri::try_from(invalid.as_bytes()).unwrap_err()
+            );
+        }
+    }
+
+    #[test]
+    fn proxy_uri_options() {
+        let mut proxy_uri = Packet::new();
+        proxy_uri.add_option(
+            CoapOption::ProxyUri,
+            b"coap://Example.com:5683/a%2Fb/c?q=1&r=%C3%A4".to_vec(),
+        );
+        let mut proxy_scheme = Packet::new();
+        proxy_scheme.add_option(CoapOption::ProxyScheme, b"coap".to_vec());
+        proxy_scheme.add_option(CoapOption::UriHost, b"example.com".to_vec());
+        proxy_scheme.add_option(CoapOption::UriPort, vec![0x16, 0x33]);
+        proxy_scheme.add_option(CoapOption::UriPath, b"a/b".to_vec());
+        proxy_scheme.add_option(CoapOption::UriPath, b"c".to_vec());
+        proxy_scheme.add_option(CoapOption::UriQuery, b"q=1".to_vec());
+        proxy_scheme.add_option(CoapOption::UriQuery, "r=\u{e4}".into());
+
+        // Both forms end up the same
+        for packet in [&mut proxy_uri, &mut proxy_scheme].iter_mut() {
+            recompose_proxy_uri(packet).unwrap();
+            assert_eq!(
+                1,
+                packet.options().filter(|(_, v)| !v.is_empty()).count()
+            );
+            assert_eq!(
+                b"coap://example.com/a%2Fb/c?q=1&r=%C3%A4"[..],
+                packet
+                    .get_option(CoapOption::ProxyUri)
+                    .unwrap()
+                    .front()
+                    .unwrap()[..]
+            );
+        }
+
+        // An IPv6 literal in Uri-Host without the brackets
+        let mut packet = Packet::new();
+        packet.add_option(CoapOption::ProxyScheme, b"coap".to_vec());
+        packet.add_option(CoapOption::UriHost, b"2001:db8::1".to_vec());
+        packet.add_option(CoapOption::UriPort, vec![0x16, 0x34]);
+        assert_eq!(
+            b"coap://[2001:db8::1]:5684/"[..],
+            ProxyUri::from_options(&packet)
+                .unwrap()
+                .unwrap()
+                .compose_full_uri()[..]
+        );
+
+        // Requests without a proxy are left alone
+        let mut packet = Packet::from_bytes(&REQ_UNPROTECTED).unwrap();
+        assert_eq!(None, ProxyUri::from_options(&packet).unwrap());
+        recompose_proxy_uri(&mut packet).unwrap();
+        assert_eq!(&REQ_UNPROTECTED[..], &packet.to_bytes().unwrap()[..]);
+        // The path can't come from both
+        let mut packet = Packet::new();
+        packet.add_option(CoapOption::ProxyUri, b"coap://a/b".to_vec());
+        packet.add_option(CoapOption::UriPath, b"c".to_vec());
+        assert_eq!(
+            Error::InvalidProxyUri,
+            ProxyUri::from_options(&packet).unwrap_err()
+        );
+    }
+
     #[test]
     fn lists() {
         let ex1 = "coap://example.com:1234/path/to/resource?q=1&b=2&c=3";